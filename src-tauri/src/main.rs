@@ -4,11 +4,17 @@
 mod player;
 
 use log::warn;
-use player::{Player, PlayerEvent, RepeatMode, ShuffleMode};
+use player::{
+    DspNodeId, DspNodeInfo, EqBand, EqualizerPreset, EqualizerSettings, OutputDeviceSelection,
+    OutputHostInfo, OutputRateMode, PlaybackRate, Player, PlayerEvent, PlayerSettings,
+    PlayerSnapshot, RepeatMode, ReplayGainMode, ResamplerKind, ResumePosition, ResumeSettings,
+    ShuffleMode, SleepTimerMode, StreamErrorPolicy, StreamMetadata, SupportedFormat, WaveformPeak,
+};
 use serde::Serialize;
 use std::{process::Command, sync::Mutex};
 use tauri::{
-    async_runtime, AboutMetadata, AppHandle, CustomMenuItem, Manager, Menu, MenuItem, Submenu,
+    async_runtime, AboutMetadata, AppHandle, CustomMenuItem, Manager, Menu, MenuItem, RunEvent,
+    Submenu,
 };
 
 struct PlayerState(Mutex<Player>);
@@ -75,6 +81,293 @@ fn player_set_repeat_mode(player_state: tauri::State<PlayerState>, repeat_mode:
     player_state.0.lock().unwrap().set_repeat_mode(repeat_mode);
 }
 
+#[tauri::command]
+fn player_set_crossfade(player_state: tauri::State<PlayerState>, crossfade_seconds: f64) {
+    player_state
+        .0
+        .lock()
+        .unwrap()
+        .set_crossfade(crossfade_seconds);
+}
+
+#[tauri::command]
+fn player_set_replay_gain_mode(
+    player_state: tauri::State<PlayerState>,
+    replay_gain_mode: ReplayGainMode,
+) {
+    player_state
+        .0
+        .lock()
+        .unwrap()
+        .set_replay_gain_mode(replay_gain_mode);
+}
+
+#[tauri::command]
+fn player_set_replay_gain_preamp(player_state: tauri::State<PlayerState>, preamp_db: f32) {
+    player_state
+        .0
+        .lock()
+        .unwrap()
+        .set_replay_gain_preamp(preamp_db);
+}
+
+#[tauri::command]
+async fn player_get_waveform(
+    path: String,
+    resolution: usize,
+    player_state: tauri::State<'_, PlayerState>,
+) -> Result<Vec<WaveformPeak>, String> {
+    let waveforms = player_state.0.lock().unwrap().waveforms();
+    async_runtime::spawn_blocking(move || waveforms.peaks(&path, resolution))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| format!("{e:?}"))
+}
+
+#[tauri::command]
+async fn player_read_metadata(path: String) -> Result<StreamMetadata, String> {
+    async_runtime::spawn_blocking(move || player::read_metadata(&path))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn player_set_fade_duration(player_state: tauri::State<PlayerState>, fade_duration_ms: u32) {
+    player_state
+        .0
+        .lock()
+        .unwrap()
+        .set_fade_duration(fade_duration_ms);
+}
+
+#[tauri::command]
+fn player_list_output_devices() -> Vec<OutputHostInfo> {
+    player::list_output_hosts()
+}
+
+#[tauri::command]
+fn player_get_output_device(player_state: tauri::State<PlayerState>) -> OutputDeviceSelection {
+    player_state.0.lock().unwrap().output_device()
+}
+
+#[tauri::command]
+fn player_set_output_device(
+    player_state: tauri::State<PlayerState>,
+    host: Option<String>,
+    device: Option<String>,
+) {
+    player_state
+        .0
+        .lock()
+        .unwrap()
+        .set_output_device(OutputDeviceSelection { host, device });
+}
+
+#[tauri::command]
+fn player_get_output_rate_mode(player_state: tauri::State<PlayerState>) -> OutputRateMode {
+    player_state.0.lock().unwrap().output_rate_mode()
+}
+
+#[tauri::command]
+fn player_set_output_rate_mode(
+    player_state: tauri::State<PlayerState>,
+    output_rate_mode: OutputRateMode,
+) {
+    player_state
+        .0
+        .lock()
+        .unwrap()
+        .set_output_rate_mode(output_rate_mode);
+}
+
+#[tauri::command]
+fn player_get_state(player_state: tauri::State<PlayerState>) -> Option<PlayerSnapshot> {
+    player_state.0.lock().unwrap().snapshot()
+}
+
+#[tauri::command]
+fn player_get_settings(player_state: tauri::State<PlayerState>) -> PlayerSettings {
+    player_state.0.lock().unwrap().settings()
+}
+
+#[tauri::command]
+fn player_set_multichannel_output(player_state: tauri::State<PlayerState>, enabled: bool) {
+    player_state
+        .0
+        .lock()
+        .unwrap()
+        .set_multichannel_output(enabled);
+}
+
+#[tauri::command]
+fn player_set_downmix_lfe(player_state: tauri::State<PlayerState>, enabled: bool) {
+    player_state.0.lock().unwrap().set_downmix_lfe(enabled);
+}
+
+#[tauri::command]
+fn player_set_playback_rate(
+    player_state: tauri::State<PlayerState>,
+    rate: f32,
+    preserve_pitch: bool,
+) {
+    player_state
+        .0
+        .lock()
+        .unwrap()
+        .set_playback_rate(PlaybackRate {
+            rate,
+            preserve_pitch,
+        });
+}
+
+#[tauri::command]
+fn player_set_loop_start(player_state: tauri::State<PlayerState>, pos: Option<usize>) {
+    player_state.0.lock().unwrap().set_loop_start(pos);
+}
+
+#[tauri::command]
+fn player_set_loop_end(player_state: tauri::State<PlayerState>, pos: Option<usize>) {
+    player_state.0.lock().unwrap().set_loop_end(pos);
+}
+
+#[tauri::command]
+fn player_clear_loop(player_state: tauri::State<PlayerState>) {
+    player_state.0.lock().unwrap().clear_loop();
+}
+
+#[tauri::command]
+fn player_queue_play_next(player_state: tauri::State<PlayerState>, file_paths: Vec<String>) {
+    player_state.0.lock().unwrap().queue_play_next(file_paths);
+}
+
+#[tauri::command]
+fn player_queue_append(player_state: tauri::State<PlayerState>, file_paths: Vec<String>) {
+    player_state.0.lock().unwrap().queue_append(file_paths);
+}
+
+#[tauri::command]
+fn player_queue_remove(player_state: tauri::State<PlayerState>, position: usize) {
+    player_state.0.lock().unwrap().queue_remove(position);
+}
+
+#[tauri::command]
+fn player_queue_move(player_state: tauri::State<PlayerState>, from: usize, to: usize) {
+    player_state.0.lock().unwrap().queue_move(from, to);
+}
+
+#[tauri::command]
+fn player_queue_clear_upcoming(player_state: tauri::State<PlayerState>) {
+    player_state.0.lock().unwrap().queue_clear_upcoming();
+}
+
+#[tauri::command]
+fn player_queue_jump_to(player_state: tauri::State<PlayerState>, position: usize) {
+    player_state.0.lock().unwrap().queue_jump_to(position);
+}
+
+#[tauri::command]
+fn player_set_resume_settings(
+    player_state: tauri::State<PlayerState>,
+    resume_settings: ResumeSettings,
+) {
+    player_state
+        .0
+        .lock()
+        .unwrap()
+        .set_resume_settings(resume_settings);
+}
+
+#[tauri::command]
+fn player_list_resume_positions(player_state: tauri::State<PlayerState>) -> Vec<ResumePosition> {
+    player_state.0.lock().unwrap().resume_positions()
+}
+
+#[tauri::command]
+fn player_clear_resume_positions(player_state: tauri::State<PlayerState>, path: Option<String>) {
+    player_state.0.lock().unwrap().clear_resume_positions(path);
+}
+
+#[tauri::command]
+fn player_set_sleep_timer(player_state: tauri::State<PlayerState>, mode: SleepTimerMode) {
+    player_state.0.lock().unwrap().set_sleep_timer(Some(mode));
+}
+
+#[tauri::command]
+fn player_cancel_sleep_timer(player_state: tauri::State<PlayerState>) {
+    player_state.0.lock().unwrap().set_sleep_timer(None);
+}
+
+#[tauri::command]
+fn player_set_stop_after_current(player_state: tauri::State<PlayerState>, enabled: bool) {
+    player_state
+        .0
+        .lock()
+        .unwrap()
+        .set_stop_after_current(enabled);
+}
+
+#[tauri::command]
+fn player_set_stream_error_policy(
+    player_state: tauri::State<PlayerState>,
+    policy: StreamErrorPolicy,
+) {
+    player_state
+        .0
+        .lock()
+        .unwrap()
+        .set_stream_error_policy(policy);
+}
+
+#[tauri::command]
+fn player_supported_formats() -> Vec<SupportedFormat> {
+    player::supported_formats()
+}
+
+#[tauri::command]
+fn player_set_resampler(player_state: tauri::State<PlayerState>, resampler: ResamplerKind) {
+    player_state.0.lock().unwrap().set_resampler(resampler);
+}
+
+#[tauri::command]
+fn player_get_equalizer(player_state: tauri::State<PlayerState>) -> EqualizerSettings {
+    player_state.0.lock().unwrap().equalizer()
+}
+
+#[tauri::command]
+fn player_set_equalizer_enabled(player_state: tauri::State<PlayerState>, enabled: bool) {
+    player_state
+        .0
+        .lock()
+        .unwrap()
+        .set_equalizer_enabled(enabled);
+}
+
+#[tauri::command]
+fn player_set_equalizer_bands(player_state: tauri::State<PlayerState>, bands: Vec<EqBand>) {
+    player_state.0.lock().unwrap().set_equalizer_bands(bands);
+}
+
+#[tauri::command]
+fn player_set_equalizer_preset(player_state: tauri::State<PlayerState>, preset: EqualizerPreset) {
+    player_state.0.lock().unwrap().set_equalizer_preset(preset);
+}
+
+#[tauri::command]
+fn player_get_dsp_nodes(player_state: tauri::State<PlayerState>) -> Vec<DspNodeInfo> {
+    player_state.0.lock().unwrap().dsp_nodes()
+}
+
+#[tauri::command]
+fn player_move_dsp_node(player_state: tauri::State<PlayerState>, id: DspNodeId, index: usize) {
+    player_state.0.lock().unwrap().move_dsp_node(id, index);
+}
+
+#[tauri::command]
+fn player_scan_loudness(file_paths: Vec<String>, player_state: tauri::State<PlayerState>) {
+    player_state.0.lock().unwrap().scan_loudness(file_paths);
+}
+
 #[tauri::command]
 async fn show_main_window(window: tauri::Window) {
     window.get_window("main").unwrap().show().unwrap();
@@ -122,6 +415,39 @@ async fn poll_player_events(
             PlayerEvent::StreamMetadataChange(metadata) => {
                 try_emit_all(&app_handle, "player://stream-metadata-change", metadata);
             }
+            PlayerEvent::LoudnessScanProgress(progress) => {
+                try_emit_all(&app_handle, "player://loudness-scan-progress", progress);
+            }
+            PlayerEvent::OutputStatusChange(status) => {
+                try_emit_all(&app_handle, "player://output-status-change", status);
+            }
+            PlayerEvent::StreamFormatChange(format) => {
+                try_emit_all(&app_handle, "player://stream-format-change", format);
+            }
+            PlayerEvent::PlaybackRateChange(playback_rate) => {
+                try_emit_all(&app_handle, "player://playback-rate-change", playback_rate);
+            }
+            PlayerEvent::LoopRegionChange(loop_region) => {
+                try_emit_all(&app_handle, "player://loop-region-change", loop_region);
+            }
+            PlayerEvent::Levels(levels) => {
+                try_emit_all(&app_handle, "player://levels", levels);
+            }
+            PlayerEvent::Spectrum(spectrum) => {
+                try_emit_all(&app_handle, "player://spectrum", spectrum);
+            }
+            PlayerEvent::SleepTimerChange(status) => {
+                try_emit_all(&app_handle, "player://sleep-timer-change", status);
+            }
+            PlayerEvent::StopAfterCurrentChange(enabled) => {
+                try_emit_all(&app_handle, "player://stop-after-current-change", enabled);
+            }
+            PlayerEvent::QueueChange(queue) => {
+                try_emit_all(&app_handle, "player://queue-change", queue);
+            }
+            PlayerEvent::Error(error) => {
+                try_emit_all(&app_handle, "player://error", error);
+            }
         }
     }
 }
@@ -160,7 +486,6 @@ fn build_menu(app_name: &str) -> Menu {
 
 fn main() {
     let (player_event_tx, player_event_rx) = async_runtime::channel(1024);
-    let player = Player::new(player_event_tx);
     let menu = build_menu("directory-player");
 
     tauri::Builder::default()
@@ -168,7 +493,6 @@ fn main() {
         .plugin(tauri_plugin_log::Builder::default().build())
         .plugin(tauri_plugin_fs_watch::init())
         .plugin(tauri_plugin_context_menu::init())
-        .manage(PlayerState(Mutex::new(player)))
         .invoke_handler(tauri::generate_handler![
             show_main_window,
             player_play,
@@ -181,9 +505,56 @@ fn main() {
             player_skip_back,
             player_set_shuffle_mode,
             player_set_repeat_mode,
+            player_set_crossfade,
+            player_set_replay_gain_mode,
+            player_set_replay_gain_preamp,
+            player_scan_loudness,
+            player_get_waveform,
+            player_read_metadata,
+            player_set_fade_duration,
+            player_list_output_devices,
+            player_get_output_device,
+            player_set_output_device,
+            player_get_output_rate_mode,
+            player_set_output_rate_mode,
+            player_get_state,
+            player_get_settings,
+            player_set_multichannel_output,
+            player_set_downmix_lfe,
+            player_set_resampler,
+            player_set_stream_error_policy,
+            player_supported_formats,
+            player_set_playback_rate,
+            player_set_loop_start,
+            player_set_loop_end,
+            player_clear_loop,
+            player_queue_play_next,
+            player_queue_append,
+            player_queue_remove,
+            player_queue_move,
+            player_queue_clear_upcoming,
+            player_queue_jump_to,
+            player_set_resume_settings,
+            player_list_resume_positions,
+            player_clear_resume_positions,
+            player_set_sleep_timer,
+            player_cancel_sleep_timer,
+            player_set_stop_after_current,
+            player_get_equalizer,
+            player_set_equalizer_enabled,
+            player_set_equalizer_bands,
+            player_set_equalizer_preset,
+            player_get_dsp_nodes,
+            player_move_dsp_node,
             show_in_folder
         ])
         .setup(|app| {
+            let data_dir = app
+                .path_resolver()
+                .app_data_dir()
+                .expect("failed to resolve app data directory");
+            let player = Player::new(player_event_tx, data_dir);
+            app.manage(PlayerState(Mutex::new(player)));
             async_runtime::spawn(poll_player_events(app.handle(), player_event_rx));
             Ok(())
         })
@@ -195,6 +566,14 @@ fn main() {
                     warn!("Failed to emit menu-event");
                 });
         })
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app_handle, event| {
+            if let RunEvent::Exit = event {
+                // The session is otherwise only saved every few seconds
+                if let Some(player_state) = app_handle.try_state::<PlayerState>() {
+                    player_state.0.lock().unwrap().save_session();
+                }
+            }
+        });
 }
@@ -0,0 +1,236 @@
+use std::{sync::Arc, thread, time::Duration};
+
+use log::warn;
+use realfft::{num_complex::Complex, RealFftPlanner, RealToComplex};
+use rtrb::{Consumer, Producer};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::error::TrySendError;
+
+use super::{channel_mix::MAX_OUTPUT_CHANNELS, PlayerEvent};
+
+/// Number of logarithmically spaced bands in the spectrum.
+pub const SPECTRUM_BANDS: usize = 64;
+/// Capacity of the ring buffer between the audio thread and the analysis thread.
+pub const ANALYSIS_RING_CAPACITY: usize = 8;
+const FFT_SIZE: usize = 2048;
+/// How often the levels and spectrum are sent to the UI.
+const UPDATE_RATE_HZ: usize = 30;
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+const MIN_FREQUENCY: f32 = 20.0;
+const MAX_FREQUENCY: f32 = 20000.0;
+/// The spectrum is clamped to this level in dBFS, so that silence doesn't show as -inf.
+const SPECTRUM_FLOOR_DB: f32 = -100.0;
+
+/// Peak and RMS amplitude of an output channel over an update interval, where full scale is one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChannelLevel {
+    pub peak: f32,
+    pub rms: f32,
+}
+
+/// The analysis of one update interval, sent from the audio thread. It has a fixed size so that it
+/// can be sent without allocating.
+pub struct AnalysisFrame {
+    num_channels: usize,
+    levels: [ChannelLevel; MAX_OUTPUT_CHANNELS],
+    /// Level of each spectrum band in dBFS.
+    spectrum: [f32; SPECTRUM_BANDS],
+}
+
+/// Measures the output of the audio thread. Everything it needs is allocated up front, since
+/// `process` runs in the audio callback.
+pub struct AnalysisTap {
+    num_channels: usize,
+    interval_frames: usize,
+    /// Frames since the last analysis frame was sent.
+    frames: usize,
+    peak: [f32; MAX_OUTPUT_CHANNELS],
+    sum_squares: [f32; MAX_OUTPUT_CHANNELS],
+    /// The most recent `FFT_SIZE` frames mixed to mono, as a circular buffer.
+    history: Vec<f32>,
+    history_pos: usize,
+    window: Vec<f32>,
+    /// Scales FFT magnitudes so that a full scale sine wave is at 0 dBFS.
+    magnitude_scale: f32,
+    fft: Arc<dyn RealToComplex<f32>>,
+    fft_input: Vec<f32>,
+    fft_output: Vec<Complex<f32>>,
+    fft_scratch: Vec<Complex<f32>>,
+    /// Range of FFT bins in each spectrum band.
+    bands: [(usize, usize); SPECTRUM_BANDS],
+    /// Whether the last frame sent was silent, in which case further silent frames are not sent.
+    is_silent: bool,
+    to_analysis_tx: Producer<AnalysisFrame>,
+}
+
+impl AnalysisTap {
+    pub fn new(
+        num_channels: usize,
+        sample_rate: u32,
+        to_analysis_tx: Producer<AnalysisFrame>,
+    ) -> AnalysisTap {
+        let fft = RealFftPlanner::<f32>::new().plan_fft_forward(FFT_SIZE);
+        let window: Vec<f32> = (0..FFT_SIZE)
+            .map(|i| {
+                let phase = i as f32 / FFT_SIZE as f32;
+                0.5 - 0.5 * (2.0 * std::f32::consts::PI * phase).cos()
+            })
+            .collect();
+        let magnitude_scale = 2.0 / window.iter().sum::<f32>();
+
+        let num_bins = FFT_SIZE / 2 + 1;
+        let bin_of = |frequency: f32| {
+            ((frequency * FFT_SIZE as f32 / sample_rate as f32) as usize).min(num_bins - 1)
+        };
+        let max_frequency = MAX_FREQUENCY.min(sample_rate as f32 / 2.0);
+        let band_edge = |band: usize| {
+            let t = band as f32 / SPECTRUM_BANDS as f32;
+            MIN_FREQUENCY * (max_frequency / MIN_FREQUENCY).powf(t)
+        };
+        let mut bands = [(0, 0); SPECTRUM_BANDS];
+        for (band, bins) in bands.iter_mut().enumerate() {
+            let start = bin_of(band_edge(band));
+            // Low bands can be narrower than a bin, in which case they share one
+            let end = bin_of(band_edge(band + 1)).max(start + 1);
+            *bins = (start, end.min(num_bins));
+        }
+
+        AnalysisTap {
+            num_channels: num_channels.min(MAX_OUTPUT_CHANNELS),
+            interval_frames: (sample_rate as usize / UPDATE_RATE_HZ).max(1),
+            frames: 0,
+            peak: [0.0; MAX_OUTPUT_CHANNELS],
+            sum_squares: [0.0; MAX_OUTPUT_CHANNELS],
+            history: vec![0.0; FFT_SIZE],
+            history_pos: 0,
+            window,
+            magnitude_scale,
+            fft_input: fft.make_input_vec(),
+            fft_output: fft.make_output_vec(),
+            fft_scratch: fft.make_scratch_vec(),
+            fft,
+            bands,
+            is_silent: false,
+            to_analysis_tx,
+        }
+    }
+
+    /// Measures the interleaved output buffer, sending an analysis frame whenever an update
+    /// interval has passed.
+    pub fn process(&mut self, data: &[f32]) {
+        let num_channels = self.num_channels;
+        for frame in data.chunks_exact(num_channels) {
+            let mut mono = 0.0;
+            for (channel, &sample) in frame.iter().enumerate() {
+                self.peak[channel] = self.peak[channel].max(sample.abs());
+                self.sum_squares[channel] += sample * sample;
+                mono += sample;
+            }
+            self.history[self.history_pos] = mono / num_channels as f32;
+            self.history_pos = (self.history_pos + 1) % FFT_SIZE;
+
+            self.frames += 1;
+            if self.frames >= self.interval_frames {
+                self.send_frame();
+            }
+        }
+    }
+
+    fn send_frame(&mut self) {
+        let mut levels = [ChannelLevel::default(); MAX_OUTPUT_CHANNELS];
+        for (channel, level) in levels[0..self.num_channels].iter_mut().enumerate() {
+            *level = ChannelLevel {
+                peak: self.peak[channel],
+                rms: (self.sum_squares[channel] / self.frames as f32).sqrt(),
+            };
+        }
+        self.peak = [0.0; MAX_OUTPUT_CHANNELS];
+        self.sum_squares = [0.0; MAX_OUTPUT_CHANNELS];
+        self.frames = 0;
+
+        // Unroll the history so that the oldest frame comes first
+        let (newer, older) = self.history.split_at(self.history_pos);
+        for ((input, &sample), &w) in self
+            .fft_input
+            .iter_mut()
+            .zip(older.iter().chain(newer))
+            .zip(&self.window)
+        {
+            *input = sample * w;
+        }
+        let mut spectrum = [SPECTRUM_FLOOR_DB; SPECTRUM_BANDS];
+        if self
+            .fft
+            .process_with_scratch(
+                &mut self.fft_input,
+                &mut self.fft_output,
+                &mut self.fft_scratch,
+            )
+            .is_ok()
+        {
+            for (level, &(start, end)) in spectrum.iter_mut().zip(&self.bands) {
+                let magnitude = self.fft_output[start..end]
+                    .iter()
+                    .map(|bin| bin.norm())
+                    .fold(0.0, f32::max)
+                    * self.magnitude_scale;
+                *level = (20.0 * magnitude.log10()).max(SPECTRUM_FLOOR_DB);
+            }
+        }
+
+        // Once the meters have fallen to the floor the UI already shows silence, so sending more of
+        // it while paused or stopped would only keep the event channel busy
+        let is_silent = levels.iter().all(|level| level.peak == 0.0)
+            && spectrum.iter().all(|&level| level <= SPECTRUM_FLOOR_DB);
+        if is_silent && self.is_silent {
+            return;
+        }
+
+        // The UI only needs the latest frame, so it's fine to drop one if the ring is full
+        if self
+            .to_analysis_tx
+            .push(AnalysisFrame {
+                num_channels: self.num_channels,
+                levels,
+                spectrum,
+            })
+            .is_ok()
+        {
+            self.is_silent = is_silent;
+        }
+    }
+}
+
+/// Forwards the latest analysis frame from the audio thread to the UI until the output is closed.
+pub fn forward_analysis(
+    mut from_process_rx: Consumer<AnalysisFrame>,
+    event_tx: tokio::sync::mpsc::Sender<PlayerEvent>,
+) {
+    loop {
+        let mut latest = None;
+        while let Ok(frame) = from_process_rx.pop() {
+            latest = Some(frame);
+        }
+        if let Some(frame) = latest {
+            let events = [
+                PlayerEvent::Levels(frame.levels[0..frame.num_channels].to_vec()),
+                PlayerEvent::Spectrum(frame.spectrum.to_vec()),
+            ];
+            for event in events {
+                match event_tx.try_send(event) {
+                    // Skip this update rather than hold up the audio analysis
+                    Ok(()) | Err(TrySendError::Full(_)) => {}
+                    Err(TrySendError::Closed(_)) => {
+                        warn!("Stopped forwarding analysis because the event channel was closed");
+                        return;
+                    }
+                }
+            }
+        }
+        if from_process_rx.is_abandoned() && from_process_rx.is_empty() {
+            // The output was closed
+            break;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
@@ -0,0 +1,120 @@
+use std::f64::consts::PI;
+
+/// Normalized coefficients of a second order IIR filter, with `a0` equal to one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BiquadCoefficients {
+    pub b0: f64,
+    pub b1: f64,
+    pub b2: f64,
+    pub a1: f64,
+    pub a2: f64,
+}
+
+impl BiquadCoefficients {
+    pub fn new(b: [f64; 3], a: [f64; 3]) -> BiquadCoefficients {
+        BiquadCoefficients {
+            b0: b[0] / a[0],
+            b1: b[1] / a[0],
+            b2: b[2] / a[0],
+            a1: a[1] / a[0],
+            a2: a[2] / a[0],
+        }
+    }
+
+    /// Peaking EQ filter, following the Audio EQ Cookbook by Robert Bristow-Johnson.
+    pub fn peaking(sample_rate: u32, frequency: f64, q: f64, gain_db: f64) -> BiquadCoefficients {
+        let (cos_w0, alpha) = cookbook_params(sample_rate, frequency, q);
+        let a = 10_f64.powf(gain_db / 40.0);
+        BiquadCoefficients::new(
+            [1.0 + alpha * a, -2.0 * cos_w0, 1.0 - alpha * a],
+            [1.0 + alpha / a, -2.0 * cos_w0, 1.0 - alpha / a],
+        )
+    }
+
+    pub fn low_shelf(sample_rate: u32, frequency: f64, q: f64, gain_db: f64) -> BiquadCoefficients {
+        let (cos_w0, alpha) = cookbook_params(sample_rate, frequency, q);
+        let a = 10_f64.powf(gain_db / 40.0);
+        let k = 2.0 * a.sqrt() * alpha;
+        BiquadCoefficients::new(
+            [
+                a * ((a + 1.0) - (a - 1.0) * cos_w0 + k),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos_w0),
+                a * ((a + 1.0) - (a - 1.0) * cos_w0 - k),
+            ],
+            [
+                (a + 1.0) + (a - 1.0) * cos_w0 + k,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos_w0),
+                (a + 1.0) + (a - 1.0) * cos_w0 - k,
+            ],
+        )
+    }
+
+    pub fn high_shelf(
+        sample_rate: u32,
+        frequency: f64,
+        q: f64,
+        gain_db: f64,
+    ) -> BiquadCoefficients {
+        let (cos_w0, alpha) = cookbook_params(sample_rate, frequency, q);
+        let a = 10_f64.powf(gain_db / 40.0);
+        let k = 2.0 * a.sqrt() * alpha;
+        BiquadCoefficients::new(
+            [
+                a * ((a + 1.0) + (a - 1.0) * cos_w0 + k),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos_w0),
+                a * ((a + 1.0) + (a - 1.0) * cos_w0 - k),
+            ],
+            [
+                (a + 1.0) - (a - 1.0) * cos_w0 + k,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos_w0),
+                (a + 1.0) - (a - 1.0) * cos_w0 - k,
+            ],
+        )
+    }
+
+    pub fn low_pass(sample_rate: u32, frequency: f64, q: f64) -> BiquadCoefficients {
+        let (cos_w0, alpha) = cookbook_params(sample_rate, frequency, q);
+        BiquadCoefficients::new(
+            [(1.0 - cos_w0) / 2.0, 1.0 - cos_w0, (1.0 - cos_w0) / 2.0],
+            [1.0 + alpha, -2.0 * cos_w0, 1.0 - alpha],
+        )
+    }
+
+    pub fn high_pass(sample_rate: u32, frequency: f64, q: f64) -> BiquadCoefficients {
+        let (cos_w0, alpha) = cookbook_params(sample_rate, frequency, q);
+        BiquadCoefficients::new(
+            [(1.0 + cos_w0) / 2.0, -(1.0 + cos_w0), (1.0 + cos_w0) / 2.0],
+            [1.0 + alpha, -2.0 * cos_w0, 1.0 - alpha],
+        )
+    }
+}
+
+/// Returns `cos(w0)` and `alpha` as defined by the Audio EQ Cookbook, keeping the frequency below
+/// Nyquist.
+fn cookbook_params(sample_rate: u32, frequency: f64, q: f64) -> (f64, f64) {
+    let nyquist = sample_rate as f64 / 2.0;
+    let w0 = 2.0 * PI * frequency.max(1.0).min(nyquist * 0.99) / sample_rate as f64;
+    let alpha = w0.sin() / (2.0 * q.max(0.01));
+    (w0.cos(), alpha)
+}
+
+/// Filter state for a single channel, using the transposed direct form II.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BiquadState {
+    z1: f64,
+    z2: f64,
+}
+
+impl BiquadState {
+    pub fn process(&mut self, coefficients: &BiquadCoefficients, x: f64) -> f64 {
+        let y = coefficients.b0 * x + self.z1;
+        self.z1 = coefficients.b1 * x - coefficients.a1 * y + self.z2;
+        self.z2 = coefficients.b2 * x - coefficients.a2 * y;
+        y
+    }
+
+    pub fn reset(&mut self) {
+        self.z1 = 0.0;
+        self.z2 = 0.0;
+    }
+}
@@ -0,0 +1,201 @@
+use std::f32::consts::FRAC_1_SQRT_2;
+
+use symphonia::core::audio::Channels;
+
+use super::file_stream::ReadData;
+
+/// Maximum number of output channels, which covers 7.1.
+pub const MAX_OUTPUT_CHANNELS: usize = 8;
+/// Symphonia doesn't define more channel positions than this.
+const MAX_INPUT_CHANNELS: usize = 32;
+
+/// Speaker positions of the output channels for the channel counts we know the layout of, in the
+/// order that the platform audio APIs expect them.
+fn output_layout(num_channels: usize) -> Option<&'static [Channels]> {
+    const STEREO: &[Channels] = &[Channels::FRONT_LEFT, Channels::FRONT_RIGHT];
+    const QUAD: &[Channels] = &[
+        Channels::FRONT_LEFT,
+        Channels::FRONT_RIGHT,
+        Channels::REAR_LEFT,
+        Channels::REAR_RIGHT,
+    ];
+    // ALSA puts the rear channels before the centre and LFE, whereas WASAPI and Core Audio use the
+    // WAVE order
+    #[cfg(target_os = "linux")]
+    const SURROUND_5_1: &[Channels] = &[
+        Channels::FRONT_LEFT,
+        Channels::FRONT_RIGHT,
+        Channels::REAR_LEFT,
+        Channels::REAR_RIGHT,
+        Channels::FRONT_CENTRE,
+        Channels::LFE1,
+    ];
+    #[cfg(not(target_os = "linux"))]
+    const SURROUND_5_1: &[Channels] = &[
+        Channels::FRONT_LEFT,
+        Channels::FRONT_RIGHT,
+        Channels::FRONT_CENTRE,
+        Channels::LFE1,
+        Channels::REAR_LEFT,
+        Channels::REAR_RIGHT,
+    ];
+    #[cfg(target_os = "linux")]
+    const SURROUND_7_1: &[Channels] = &[
+        Channels::FRONT_LEFT,
+        Channels::FRONT_RIGHT,
+        Channels::REAR_LEFT,
+        Channels::REAR_RIGHT,
+        Channels::FRONT_CENTRE,
+        Channels::LFE1,
+        Channels::SIDE_LEFT,
+        Channels::SIDE_RIGHT,
+    ];
+    #[cfg(not(target_os = "linux"))]
+    const SURROUND_7_1: &[Channels] = &[
+        Channels::FRONT_LEFT,
+        Channels::FRONT_RIGHT,
+        Channels::FRONT_CENTRE,
+        Channels::LFE1,
+        Channels::REAR_LEFT,
+        Channels::REAR_RIGHT,
+        Channels::SIDE_LEFT,
+        Channels::SIDE_RIGHT,
+    ];
+    match num_channels {
+        2 => Some(STEREO),
+        4 => Some(QUAD),
+        6 => Some(SURROUND_5_1),
+        8 => Some(SURROUND_7_1),
+        _ => None,
+    }
+}
+
+/// Gains of a channel in a stereo downmix, following ITU-R BS.775 with surround and height
+/// channels mixed into their side at -3 dB.
+fn stereo_downmix_gains(channel: Channels, downmix_lfe: bool) -> (f32, f32) {
+    const LEFT: Channels = Channels::REAR_LEFT
+        .union(Channels::SIDE_LEFT)
+        .union(Channels::FRONT_LEFT_CENTRE)
+        .union(Channels::REAR_LEFT_CENTRE)
+        .union(Channels::TOP_FRONT_LEFT)
+        .union(Channels::TOP_REAR_LEFT)
+        .union(Channels::FRONT_LEFT_WIDE)
+        .union(Channels::FRONT_LEFT_HIGH);
+    const RIGHT: Channels = Channels::REAR_RIGHT
+        .union(Channels::SIDE_RIGHT)
+        .union(Channels::FRONT_RIGHT_CENTRE)
+        .union(Channels::REAR_RIGHT_CENTRE)
+        .union(Channels::TOP_FRONT_RIGHT)
+        .union(Channels::TOP_REAR_RIGHT)
+        .union(Channels::FRONT_RIGHT_WIDE)
+        .union(Channels::FRONT_RIGHT_HIGH);
+    const CENTRE: Channels = Channels::FRONT_CENTRE
+        .union(Channels::REAR_CENTRE)
+        .union(Channels::TOP_CENTRE)
+        .union(Channels::TOP_FRONT_CENTRE)
+        .union(Channels::TOP_REAR_CENTRE)
+        .union(Channels::FRONT_CENTRE_HIGH);
+    const LFE: Channels = Channels::LFE1.union(Channels::LFE2);
+
+    if channel == Channels::FRONT_LEFT {
+        (1.0, 0.0)
+    } else if channel == Channels::FRONT_RIGHT {
+        (0.0, 1.0)
+    } else if LEFT.contains(channel) {
+        (FRAC_1_SQRT_2, 0.0)
+    } else if RIGHT.contains(channel) {
+        (0.0, FRAC_1_SQRT_2)
+    } else if CENTRE.contains(channel) || (downmix_lfe && LFE.contains(channel)) {
+        (FRAC_1_SQRT_2, FRAC_1_SQRT_2)
+    } else {
+        (0.0, 0.0)
+    }
+}
+
+/// Maps the channels of a stream to the output channels. Channels are passed through if the
+/// output has all of them, and are otherwise downmixed to the front left and right outputs.
+#[derive(Clone, Copy)]
+pub struct ChannelMix {
+    num_output_channels: usize,
+    /// Gain from each input channel to each output channel.
+    matrix: [[f32; MAX_OUTPUT_CHANNELS]; MAX_INPUT_CHANNELS],
+}
+
+impl ChannelMix {
+    /// Creates the mix for a stream with the given channel layout. This doesn't allocate, so it
+    /// can be done on the audio thread.
+    pub fn new(layout: Channels, num_output_channels: usize, downmix_lfe: bool) -> ChannelMix {
+        let num_output_channels = num_output_channels.clamp(2, MAX_OUTPUT_CHANNELS);
+        let mut matrix = [[0.0; MAX_OUTPUT_CHANNELS]; MAX_INPUT_CHANNELS];
+        let num_input_channels = layout.count().min(MAX_INPUT_CHANNELS);
+        let outputs = output_layout(num_output_channels);
+
+        if num_input_channels <= 1 {
+            // Mono, or a stream without a known layout, goes to both front speakers
+            matrix[0][0] = 1.0;
+            matrix[0][1] = 1.0;
+        } else if let Some(outputs) =
+            outputs.filter(|outputs| layout.iter().all(|channel| outputs.contains(&channel)))
+        {
+            for (input, channel) in layout.iter().enumerate() {
+                if let Some(output) = outputs.iter().position(|&position| position == channel) {
+                    matrix[input][output] = 1.0;
+                }
+            }
+        } else {
+            let mut left_sum = 0.0;
+            let mut right_sum = 0.0;
+            for (input, channel) in layout.iter().take(MAX_INPUT_CHANNELS).enumerate() {
+                let (left, right) = stereo_downmix_gains(channel, downmix_lfe);
+                matrix[input][0] = left;
+                matrix[input][1] = right;
+                left_sum += left;
+                right_sum += right;
+            }
+            // Scale the mix down so that it can't clip
+            let scale = 1.0 / f32::max(1.0, f32::max(left_sum, right_sum));
+            for gains in matrix.iter_mut() {
+                gains[0] *= scale;
+                gains[1] *= scale;
+            }
+        }
+
+        ChannelMix {
+            num_output_channels,
+            matrix,
+        }
+    }
+
+    pub fn num_output_channels(&self) -> usize {
+        self.num_output_channels
+    }
+
+    /// Mixes the frames in `read_data` into the interleaved `data` buffer, returning the number of
+    /// frames written.
+    pub fn apply(&self, read_data: &ReadData, data: &mut [f32]) -> usize {
+        let num_frames = read_data.num_frames();
+        let num_output_channels = self.num_output_channels;
+        let out = &mut data[0..num_frames * num_output_channels];
+        for sample in out.iter_mut() {
+            *sample = 0.0;
+        }
+
+        let num_input_channels = read_data.num_channels().min(MAX_INPUT_CHANNELS);
+        for (input, gains) in self.matrix[0..num_input_channels].iter().enumerate() {
+            let samples = read_data.read_channel(input);
+            for (output, &gain) in gains[0..num_output_channels].iter().enumerate() {
+                if gain == 0.0 {
+                    continue;
+                }
+                for (frame, &sample) in out
+                    .chunks_exact_mut(num_output_channels)
+                    .zip(samples.iter())
+                {
+                    frame[output] += sample * gain;
+                }
+            }
+        }
+
+        num_frames
+    }
+}
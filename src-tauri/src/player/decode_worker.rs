@@ -1,13 +1,22 @@
+use std::mem;
 use std::ops::Range;
+use std::sync::Arc;
 
 use log::{error, trace, warn};
-use rubato::{FftFixedIn, Resampler};
+use rubato::ResamplerConstructionError;
 use symphonia::core::audio::{AudioBuffer, AudioBufferRef, Signal};
-use symphonia::core::codecs::Decoder;
+use symphonia::core::codecs::{Decoder, CODEC_TYPE_OPUS};
 use symphonia::core::conv::IntoSample;
 use symphonia::core::formats::{FormatReader, SeekMode, SeekTo};
 use symphonia::core::sample::Sample;
 
+use super::errors::PlayerErrorKind;
+use super::resampler::{BlockResampler, ResamplerKind};
+use super::time_stretch::{PlaybackRate, SharedPlaybackRate, TimeStretcher};
+
+/// Opus needs this many frames of decoding before a seek position to converge, per RFC 7845.
+const OPUS_SEEK_PREROLL: usize = 3840;
+
 fn convert_samples_any(
     input: &AudioBufferRef<'_>,
     output: &mut [Vec<f32>],
@@ -37,6 +46,15 @@ where
     }
 }
 
+/// The point in a decoded block where playback jumps back to the start of the loop region.
+#[derive(Clone, Copy, Debug)]
+pub struct LoopWrap {
+    /// Number of frames of the block that come before the end of the region.
+    pub at: usize,
+    /// Position in frames of the file that the frames after `at` continue from.
+    pub to: usize,
+}
+
 #[derive(Debug, Clone)]
 pub struct DecodedBlock {
     pub samples: Vec<Vec<f32>>,
@@ -44,6 +62,13 @@ pub struct DecodedBlock {
     pub playhead: usize,
     pub is_eof: bool,
     pub resample_ratio: f64,
+    /// Position of the first frame of the block in frames of the file.
+    pub source_pos: usize,
+    /// Set if the block reaches the end of a loop region, in which case the frames after the wrap
+    /// are from the start of the region.
+    pub wrap: Option<LoopWrap>,
+    /// Set on the last block when decoding stopped because of an error.
+    pub error: Option<PlayerErrorKind>,
     pub stream_id: u32,
     pub next: Option<Box<DecodedBlock>>,
     /// Number of blocks in the linked list
@@ -67,6 +92,9 @@ pub enum DecodeWorkerToFileStreamMessage {
 pub enum FileStreamToDecodeWorkerMessage {
     DisposeBlock(Box<DecodedBlock>),
     Seek(usize, u32),
+    /// Loops decoding between two positions in frames of the file. Takes effect for frames that
+    /// are decoded after the message, so it is followed by a seek.
+    SetLoop(Option<(usize, usize)>),
     Done(Vec<Vec<f32>>),
 }
 
@@ -77,11 +105,32 @@ pub struct DecodeWorker {
     output_buffer: Vec<Vec<f32>>,
     reader: Box<dyn FormatReader>,
     decoder: Box<dyn Decoder>,
-    resampler: Option<FftFixedIn<f32>>,
+    resampler: Option<Box<dyn BlockResampler>>,
+    resampler_kind: ResamplerKind,
+    stretcher: Option<TimeStretcher>,
+    shared_playback_rate: Option<Arc<SharedPlaybackRate>>,
+    playback_rate: PlaybackRate,
     block_size: usize,
     track_id: u32,
+    sample_rate: u32,
+    target_sample_rate: u32,
+    /// Rate that the resampler converts to, which differs from the target rate when the playback
+    /// rate is changed without preserving the pitch.
+    resampled_sample_rate: u32,
+    /// Output frames per source frame, including the effect of the playback rate.
     resample_ratio: f64,
     stream_id: u32,
+    is_eof: bool,
+    /// Frames to skip at the start of the next packet to land exactly on the seek position.
+    seek_delta: usize,
+    /// Position in frames of the file of the next frame that goes into the input buffer.
+    source_pos: usize,
+    /// Position in frames of the file of the first frame in the input buffer.
+    block_source_pos: usize,
+    /// Number of frames in the input buffer from before the end of the loop region, and the start
+    /// of the region that the frames after them continue from.
+    input_wrap: Option<(usize, usize)>,
+    loop_region: Option<(usize, usize)>,
 }
 
 impl DecodeWorker {
@@ -92,36 +141,16 @@ impl DecodeWorker {
         num_channels: usize,
         block_size: usize,
         sample_rate: u32,
+        resampler_kind: ResamplerKind,
+        shared_playback_rate: Option<Arc<SharedPlaybackRate>>,
         track_id: u32,
         reader: Box<dyn FormatReader>,
         decoder: Box<dyn Decoder>,
         message_producer: rtrb::Producer<DecodeWorkerToFileStreamMessage>,
         message_consumer: rtrb::Consumer<FileStreamToDecodeWorkerMessage>,
-    ) -> Self {
-        let resample_ratio = target_sample_rate as f64 / sample_rate as f64;
-        let mut maybe_resampler = if sample_rate != target_sample_rate {
-            trace!("Will resample from {sample_rate} to {target_sample_rate}");
-            Some(
-                FftFixedIn::new(
-                    sample_rate as usize,
-                    target_sample_rate as usize,
-                    block_size,
-                    2,
-                    num_channels,
-                )
-                .expect("Failed to create resampler"),
-            )
-        } else {
-            trace!("Will not resample");
-            None
-        };
+    ) -> Result<Self, ResamplerConstructionError> {
         let mut input_buffer =
             Vec::from_iter((0..num_channels).map(|_| Vec::with_capacity(block_size)));
-        let output_buffer = if let Some(resampler) = maybe_resampler.as_mut() {
-            resampler.output_buffer_allocate(true)
-        } else {
-            input_buffer.clone()
-        };
         if decoder.last_decoded().frames() > 0 {
             convert_samples_any(
                 &decoder.last_decoded(),
@@ -129,24 +158,280 @@ impl DecodeWorker {
                 0..decoder.last_decoded().frames(),
             );
         }
-        DecodeWorker {
-            resample_ratio,
+        let source_pos = input_buffer[0].len();
+        let mut worker = DecodeWorker {
+            resample_ratio: 1.0,
             message_producer,
             message_consumer,
-            output_buffer,
+            output_buffer: input_buffer.clone(),
             input_buffer,
             reader,
             decoder,
-            resampler: maybe_resampler,
+            resampler: None,
+            resampler_kind,
+            stretcher: None,
+            shared_playback_rate,
+            playback_rate: PlaybackRate::default(),
             block_size,
             track_id,
+            sample_rate,
+            target_sample_rate,
+            resampled_sample_rate: sample_rate,
             stream_id: 0,
+            is_eof: false,
+            seek_delta: 0,
+            source_pos,
+            block_source_pos: 0,
+            input_wrap: None,
+            loop_region: None,
+        };
+        worker.configure()?;
+        Ok(worker)
+    }
+
+    /// Sets up the resampler and time stretcher for the current playback rate.
+    fn configure(&mut self) -> Result<(), ResamplerConstructionError> {
+        let playback_rate = self
+            .shared_playback_rate
+            .as_ref()
+            .map_or_else(PlaybackRate::default, |shared| shared.load());
+        let num_channels = self.input_buffer.len();
+        let sample_rate = self.sample_rate;
+        let is_varispeed = !playback_rate.preserve_pitch && !playback_rate.is_normal();
+        let resampled_sample_rate = if is_varispeed {
+            // Playing the audio at a lower rate speeds it up
+            (self.target_sample_rate as f32 / playback_rate.rate).round() as u32
+        } else {
+            self.target_sample_rate
+        };
+
+        self.resampler = if sample_rate != resampled_sample_rate {
+            trace!(
+                "Will resample from {sample_rate} to {resampled_sample_rate} with {:?}",
+                self.resampler_kind
+            );
+            let resampler = self.resampler_kind.build(
+                sample_rate,
+                resampled_sample_rate,
+                self.block_size,
+                num_channels,
+            )?;
+            self.output_buffer = resampler.output_buffer_allocate(true);
+            Some(resampler)
+        } else {
+            trace!("Will not resample");
+            None
+        };
+
+        let stretch_rate = if playback_rate.preserve_pitch && !playback_rate.is_normal() {
+            trace!("Will time-stretch at {}x", playback_rate.rate);
+            self.stretcher = Some(TimeStretcher::new(
+                playback_rate.rate,
+                self.target_sample_rate,
+                num_channels,
+            ));
+            playback_rate.rate as f64
+        } else {
+            self.stretcher = None;
+            1.0
+        };
+
+        self.playback_rate = playback_rate;
+        self.resampled_sample_rate = resampled_sample_rate;
+        self.resample_ratio = resampled_sample_rate as f64 / sample_rate as f64 / stretch_rate;
+        Ok(())
+    }
+
+    /// Resamples and time-stretches the input buffer and sends the result to the file stream.
+    /// `input_num_frames` is the number of frames in the buffer, which is padded with silence if it
+    /// isn't full.
+    fn push_block(&mut self, input_num_frames: usize, is_eof: bool) {
+        let is_partial = input_num_frames < self.block_size;
+        if is_partial {
+            trace!(
+                "Resizing buffer from {} to {}",
+                input_num_frames,
+                self.block_size
+            );
+            for channel in self.input_buffer.iter_mut() {
+                channel.resize(self.block_size, 0.0);
+            }
         }
+        let (mut samples, mut num_frames) = if let Some(resampler) = self.resampler.as_mut() {
+            // The sinc and linear resamplers don't produce the same number of frames for every
+            // block
+            let (_, output_num_frames) = resampler
+                .process_into_buffer(&self.input_buffer, &mut self.output_buffer)
+                .expect("Failed to resample");
+            (self.output_buffer.clone(), output_num_frames)
+        } else {
+            (self.input_buffer.clone(), self.block_size)
+        };
+        if is_partial {
+            num_frames = (input_num_frames as f64 * self.resampled_sample_rate as f64
+                / self.sample_rate as f64) as usize;
+        }
+        if let Some(stretcher) = self.stretcher.as_mut() {
+            samples = stretcher.process(&samples, num_frames);
+            if is_eof {
+                for (channel, rest) in samples.iter_mut().zip(stretcher.flush()) {
+                    channel.extend(rest);
+                }
+            }
+            num_frames = samples[0].len();
+        }
+        // The resampler and time stretcher don't map frames exactly, so the wrap is placed where
+        // the ratio puts it
+        let wrap = self.input_wrap.take().map(|(at, to)| LoopWrap {
+            at: if at == input_num_frames {
+                num_frames
+            } else {
+                ((at as f64 * self.resample_ratio).round() as usize).min(num_frames)
+            },
+            to,
+        });
+        for channel in self.input_buffer.iter_mut() {
+            channel.clear();
+        }
+        let source_pos = mem::replace(&mut self.block_source_pos, self.source_pos);
+        self.message_producer
+            .push(DecodeWorkerToFileStreamMessage::Block(Box::new(
+                DecodedBlock {
+                    samples,
+                    stream_id: self.stream_id,
+                    num_frames,
+                    playhead: 0,
+                    is_eof,
+                    next: None,
+                    resample_ratio: self.resample_ratio,
+                    source_pos,
+                    wrap,
+                    error: None,
+                    len: 1,
+                },
+            )))
+            .unwrap();
+    }
+
+    /// Ends the stream with an empty block that carries the error, so that playback moves on and
+    /// the error can be reported. Waits for room in the ring unless the stream was dropped.
+    fn push_error_block(&mut self, kind: PlayerErrorKind) {
+        let mut message = DecodeWorkerToFileStreamMessage::Block(Box::new(DecodedBlock {
+            samples: vec![Vec::new(); self.input_buffer.len()],
+            stream_id: self.stream_id,
+            num_frames: 0,
+            playhead: 0,
+            is_eof: true,
+            next: None,
+            resample_ratio: self.resample_ratio,
+            source_pos: self.source_pos,
+            wrap: None,
+            error: Some(kind),
+            len: 1,
+        }));
+        while let Err(rtrb::PushError::Full(rejected)) = self.message_producer.push(message) {
+            if self.message_producer.is_abandoned() {
+                return;
+            }
+            message = rejected;
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+    }
+
+    /// Moves the reader so that decoding continues from `seek_to`, in frames of the file, and
+    /// drops what was buffered from before.
+    fn seek(&mut self, seek_to: usize) -> symphonia::core::errors::Result<()> {
+        self.seek_reader(seek_to)?;
+        self.block_source_pos = seek_to;
+        self.input_wrap = None;
+        for channel in self.input_buffer.iter_mut() {
+            channel.clear();
+        }
+        Ok(())
+    }
+
+    /// Moves the reader so that decoding continues from `seek_to`, in frames of the file.
+    fn seek_reader(&mut self, seek_to: usize) -> symphonia::core::errors::Result<()> {
+        // Decoding starts early enough for the decoder to settle, and the extra frames are
+        // skipped along with the rest of the seek delta
+        let preroll = if self.decoder.codec_params().codec == CODEC_TYPE_OPUS {
+            OPUS_SEEK_PREROLL
+        } else {
+            0
+        };
+        let seeked_to = self.reader.seek(
+            SeekMode::Accurate,
+            SeekTo::TimeStamp {
+                ts: seek_to.saturating_sub(preroll) as u64,
+                track_id: self.track_id,
+            },
+        )?;
+        self.is_eof = false;
+        self.seek_delta = (seek_to as u64).saturating_sub(seeked_to.actual_ts) as usize;
+        trace!("Found seek delta of {}", self.seek_delta);
+        self.source_pos = seek_to;
+
+        self.decoder.reset();
+        Ok(())
+    }
+
+    /// Continues decoding from the start of the loop region once its end is in the input buffer.
+    /// The frames from the start go into the same block, so that the resampler and time stretcher
+    /// run across the wrap as if the audio was continuous. A region shorter than a block could
+    /// wrap more than once per block, so there the block is cut at the wrap instead.
+    fn loop_back(&mut self, start: usize) -> symphonia::core::errors::Result<()> {
+        let num_frames = self.input_buffer[0].len();
+        self.input_wrap = Some((num_frames, start));
+        let is_short_region = self.source_pos - start < self.block_size;
+        if is_short_region || num_frames == self.block_size {
+            self.push_block(num_frames, false);
+        }
+        if is_short_region {
+            // The block was padded with silence, which the resampler shouldn't carry over
+            if let Some(resampler) = self.resampler.as_mut() {
+                resampler.reset();
+            }
+        }
+        self.seek_reader(start)?;
+        if self.input_buffer[0].is_empty() {
+            self.block_source_pos = start;
+        }
+        Ok(())
+    }
+
+    /// Copies frames of the last decoded packet to the input buffer, sending a block whenever it
+    /// fills up and looping back at the end of the loop region.
+    fn buffer_decoded(&mut self, mut frames: Range<usize>) -> symphonia::core::errors::Result<()> {
+        while !frames.is_empty() {
+            // The region only applies when playback reaches its end from before it
+            let loop_region = self.loop_region.filter(|&(_, end)| end > self.source_pos);
+            let mut num_frames = frames
+                .len()
+                .min(self.block_size - self.input_buffer[0].len());
+            if let Some((_, end)) = loop_region {
+                num_frames = num_frames.min(end - self.source_pos);
+            }
+            convert_samples_any(
+                &self.decoder.last_decoded(),
+                self.input_buffer.as_mut_slice(),
+                frames.start..frames.start + num_frames,
+            );
+            frames.start += num_frames;
+            self.source_pos += num_frames;
+
+            if let Some((start, end)) = loop_region {
+                if self.source_pos == end {
+                    return self.loop_back(start);
+                }
+            }
+            if self.input_buffer[0].len() == self.block_size {
+                self.push_block(self.block_size, false);
+            }
+        }
+        Ok(())
     }
 
     pub fn run(mut self) {
-        let mut is_eof = false;
-        let mut seek_delta: usize = 0;
         let result: symphonia::core::errors::Result<()> = loop {
             let mut is_done = false;
             let poll_result = loop {
@@ -162,31 +447,30 @@ impl DecodeWorker {
                                 trace!("Disposing block with len {}", block.len);
                             }
                         }
+                        FileStreamToDecodeWorkerMessage::SetLoop(loop_region) => {
+                            self.loop_region = loop_region;
+                        }
                         FileStreamToDecodeWorkerMessage::Seek(seek_to, stream_id) => {
-                            match self.reader.seek(
-                                SeekMode::Accurate,
-                                SeekTo::TimeStamp {
-                                    ts: seek_to as u64,
-                                    track_id: self.track_id,
-                                },
-                            ) {
-                                Err(e) => {
-                                    break Err(e);
+                            if let Err(e) = self.seek(seek_to) {
+                                break Err(e);
+                            }
+                            self.stream_id = stream_id;
+                            let playback_rate = self
+                                .shared_playback_rate
+                                .as_ref()
+                                .map(|shared| shared.load());
+                            if playback_rate.is_some_and(|rate| rate != self.playback_rate) {
+                                // The playback rate is picked up on seek, since the blocks that
+                                // were already sent can't be changed
+                                if let Err(e) = self.configure() {
+                                    error!("Failed to apply playback rate: {e}");
                                 }
-                                Ok(seeked_to) => {
-                                    self.stream_id = stream_id;
-                                    is_eof = false;
-                                    seek_delta =
-                                        (seeked_to.required_ts - seeked_to.actual_ts) as usize;
-                                    trace!("Found seek delta of {}", seek_delta);
-
-                                    self.decoder.reset();
-                                    for channel in self.input_buffer.iter_mut() {
-                                        channel.clear();
-                                    }
-                                    if let Some(resampler) = self.resampler.as_mut() {
-                                        resampler.reset();
-                                    }
+                            } else {
+                                if let Some(resampler) = self.resampler.as_mut() {
+                                    resampler.reset();
+                                }
+                                if let Some(stretcher) = self.stretcher.as_mut() {
+                                    stretcher.reset();
                                 }
                             }
                         }
@@ -204,7 +488,7 @@ impl DecodeWorker {
                 break Ok(());
             }
 
-            if is_eof || self.message_producer.is_full() {
+            if self.is_eof || self.message_producer.is_full() {
                 std::thread::sleep(std::time::Duration::from_millis(1));
                 continue;
             }
@@ -216,36 +500,19 @@ impl DecodeWorker {
                         && err.to_string() == "end of stream" =>
                 {
                     let num_frames = self.input_buffer[0].len();
-                    trace!("Resizing buffer from {} to {}", num_frames, self.block_size);
-                    for channel in self.input_buffer.iter_mut() {
-                        channel.resize(self.block_size, 0.0);
-                    }
-                    let samples = if let Some(resampler) = self.resampler.as_mut() {
-                        resampler
-                            .process_into_buffer(&self.input_buffer, &mut self.output_buffer, None)
-                            .expect("Failed to resample");
-                        self.output_buffer.clone()
+                    let loop_start = self
+                        .loop_region
+                        .filter(|&(_, end)| end >= self.source_pos)
+                        .map(|(start, _)| start);
+                    if let Some(start) = loop_start {
+                        // The loop region extends to the end of the file
+                        if let Err(e) = self.loop_back(start) {
+                            break Err(e);
+                        }
                     } else {
-                        self.input_buffer.clone()
-                    };
-                    for channel in self.input_buffer.iter_mut() {
-                        channel.clear();
+                        self.push_block(num_frames, true);
+                        self.is_eof = true;
                     }
-                    self.message_producer
-                        .push(DecodeWorkerToFileStreamMessage::Block(Box::new(
-                            DecodedBlock {
-                                num_frames: (num_frames as f64 * self.resample_ratio) as usize,
-                                samples,
-                                resample_ratio: self.resample_ratio,
-                                stream_id: self.stream_id,
-                                playhead: 0,
-                                is_eof: true,
-                                next: None,
-                                len: 1,
-                            },
-                        )))
-                        .unwrap();
-                    is_eof = true;
                     continue;
                 }
                 Err(err) => {
@@ -266,66 +533,24 @@ impl DecodeWorker {
                     if input_num_frames == 0 {
                         continue;
                     }
-                    if input_num_frames < seek_delta {
+                    if input_num_frames < self.seek_delta {
                         trace!(
                             "Block of {} frames skipped for seek delta",
                             input_num_frames
                         );
-                        seek_delta -= input_num_frames;
+                        self.seek_delta -= input_num_frames;
                         continue;
                     }
-                    let input_frames_consumed = input_num_frames - seek_delta;
-                    let mut input_offset = seek_delta;
-                    if seek_delta > 0 {
-                        trace!("Recuperated remaining {} frames of seek delta", seek_delta);
-                    }
-
-                    seek_delta = 0;
-                    if input_frames_consumed + self.input_buffer[0].len() >= self.block_size {
-                        let consume_samples = self.block_size - self.input_buffer[0].len();
-                        convert_samples_any(
-                            &decoded,
-                            self.input_buffer.as_mut_slice(),
-                            input_offset..consume_samples,
+                    if self.seek_delta > 0 {
+                        trace!(
+                            "Recuperated remaining {} frames of seek delta",
+                            self.seek_delta
                         );
-                        input_offset = consume_samples;
-                        let mut output_num_frames = self.block_size;
-                        let samples = if let Some(resampler) = self.resampler.as_mut() {
-                            output_num_frames = resampler.output_frames_next();
-                            resampler
-                                .process_into_buffer(
-                                    &self.input_buffer,
-                                    &mut self.output_buffer,
-                                    None,
-                                )
-                                .expect("Failed to resample");
-                            self.output_buffer.clone()
-                        } else {
-                            self.input_buffer.clone()
-                        };
-                        for channel in self.input_buffer.iter_mut() {
-                            channel.clear();
-                        }
-                        self.message_producer
-                            .push(DecodeWorkerToFileStreamMessage::Block(Box::new(
-                                DecodedBlock {
-                                    samples,
-                                    stream_id: self.stream_id,
-                                    num_frames: output_num_frames,
-                                    playhead: 0,
-                                    is_eof: false,
-                                    next: None,
-                                    resample_ratio: self.resample_ratio,
-                                    len: 1,
-                                },
-                            )))
-                            .unwrap();
                     }
-                    convert_samples_any(
-                        &decoded,
-                        self.input_buffer.as_mut_slice(),
-                        input_offset..input_num_frames,
-                    );
+                    let input_offset = mem::take(&mut self.seek_delta);
+                    if let Err(e) = self.buffer_decoded(input_offset..input_num_frames) {
+                        break Err(e);
+                    }
                 }
                 Err(symphonia::core::errors::Error::DecodeError(err)) => {
                     warn!("decode error: {}", err)
@@ -335,6 +560,12 @@ impl DecodeWorker {
         };
         if let Err(e) = result {
             error!("DecodeWorker error: {}", e);
+            // What was decoded before the error is still played
+            let num_frames = self.input_buffer[0].len();
+            if num_frames > 0 {
+                self.push_block(num_frames, false);
+            }
+            self.push_error_block((&e).into());
         }
     }
 }
@@ -0,0 +1,346 @@
+use serde::{Deserialize, Serialize};
+
+use super::equalizer::EqualizerParams;
+
+/// Capacity of a `DspChain`, reserved up front so that nodes can be inserted on the audio thread
+/// without allocating.
+pub const MAX_DSP_NODES: usize = 16;
+
+pub type DspNodeId = u64;
+
+/// A node in the DSP chain as the frontend sees it.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct DspNodeInfo {
+    pub id: DspNodeId,
+    pub name: String,
+}
+
+/// Parameter updates for nodes in a `DspChain`. Each node handles the variant it understands and
+/// ignores the others. Values must be sendable to the audio thread without allocating.
+pub enum DspNodeParams {
+    Equalizer(EqualizerParams),
+}
+
+/// An effect applied to the output on the audio thread.
+pub trait DspNode: Send {
+    /// Identifies the kind of node to the frontend.
+    fn name(&self) -> &'static str;
+
+    /// Called off the audio thread before the node is inserted into a chain, so it is free to
+    /// allocate any state it needs for the given format.
+    fn prepare(&mut self, sample_rate: u32, num_channels: usize);
+
+    /// Processes interleaved samples in place.
+    fn process(&mut self, data: &mut [f32]);
+
+    /// Clears any internal state, e.g. filter history, when the audio becomes discontinuous
+    /// because of a seek or a track change.
+    fn reset(&mut self);
+
+    fn set_params(&mut self, _params: &DspNodeParams) {}
+}
+
+/// How long a node takes to fade in when it's inserted and to fade out when it's removed, so that
+/// the sound doesn't change abruptly.
+const NODE_FADE_SECONDS: f32 = 0.02;
+/// Frames that a fading node processes at a time, which is what the dry buffer holds.
+const NODE_FADE_CHUNK_FRAMES: usize = 256;
+
+struct ChainNode {
+    id: DspNodeId,
+    node: Box<dyn DspNode>,
+    /// How much of the output of the node is used instead of its input, from zero to one.
+    wet: f32,
+    /// Change of `wet` per frame, which is negative while the node is removed or moved.
+    wet_step: f32,
+    /// Index that the node is moved to once it has faded out, after which it fades back in.
+    move_to: Option<usize>,
+}
+
+impl ChainNode {
+    /// Whether the node is fading out to be removed, so that it no longer counts towards the
+    /// indexes that the manager sees.
+    fn is_removed(&self) -> bool {
+        self.wet_step < 0.0 && self.move_to.is_none()
+    }
+}
+
+/// An ordered list of nodes that the output is passed through.
+pub struct DspChain {
+    nodes: Vec<ChainNode>,
+    /// Nodes that have faded out or couldn't be inserted, waiting to be handed back for dropping.
+    removed: Vec<Box<dyn DspNode>>,
+    num_channels: usize,
+    fade_step: f32,
+    /// Input of a fading node, to mix its output with.
+    dry: Vec<f32>,
+}
+
+impl DspChain {
+    pub fn new(sample_rate: u32, num_channels: usize) -> DspChain {
+        DspChain {
+            nodes: Vec::with_capacity(MAX_DSP_NODES),
+            removed: Vec::with_capacity(MAX_DSP_NODES * 2),
+            num_channels,
+            fade_step: 1.0 / (NODE_FADE_SECONDS * sample_rate as f32).max(1.0),
+            dry: vec![0.0; NODE_FADE_CHUNK_FRAMES * num_channels],
+        }
+    }
+
+    /// Inserts a prepared node at `index`, or at the end if `index` is out of bounds, and fades it
+    /// in. The node is handed back if the chain is full, since growing it would allocate.
+    pub fn insert(
+        &mut self,
+        index: usize,
+        id: DspNodeId,
+        node: Box<dyn DspNode>,
+    ) -> Result<(), Box<dyn DspNode>> {
+        if self.nodes.len() == self.nodes.capacity() {
+            return Err(node);
+        }
+        let index = self.chain_index(index);
+        self.nodes.insert(
+            index,
+            ChainNode {
+                id,
+                node,
+                wet: 0.0,
+                wet_step: self.fade_step,
+                move_to: None,
+            },
+        );
+        Ok(())
+    }
+
+    /// Fades a node out, after which it's removed and can be taken with `take_removed`.
+    pub fn remove(&mut self, id: DspNodeId) {
+        if let Some(index) = self.position(id) {
+            self.nodes[index].wet_step = -self.fade_step;
+            self.nodes[index].move_to = None;
+        }
+    }
+
+    /// Fades a node out, moves it to `index`, or to the end if `index` is out of bounds, and fades
+    /// it back in. Nodes that are being removed don't count towards the index.
+    pub fn move_node(&mut self, id: DspNodeId, index: usize) {
+        if let Some(from) = self.position(id) {
+            if !self.nodes[from].is_removed() {
+                self.nodes[from].wet_step = -self.fade_step;
+                self.nodes[from].move_to = Some(index);
+            }
+        }
+    }
+
+    /// Takes a node that has been removed, so that it can be dropped off the audio thread.
+    pub fn take_removed(&mut self) -> Option<Box<dyn DspNode>> {
+        self.removed.pop()
+    }
+
+    /// Keeps a node until it can be handed back with `take_removed`, e.g. because the chain was
+    /// full or there was no room to send it back. If even that is full the node is leaked, which
+    /// is better than deallocating it on the audio thread.
+    pub fn push_removed(&mut self, node: Box<dyn DspNode>) {
+        if self.removed.len() < self.removed.capacity() {
+            self.removed.push(node);
+        } else {
+            std::mem::forget(node);
+        }
+    }
+
+    pub fn set_params(&mut self, id: DspNodeId, params: &DspNodeParams) {
+        if let Some(index) = self.position(id) {
+            self.nodes[index].node.set_params(params);
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for chain_node in self.nodes.iter_mut() {
+            chain_node.node.reset();
+        }
+    }
+
+    pub fn process(&mut self, data: &mut [f32]) {
+        let num_channels = self.num_channels;
+        for chain_node in self.nodes.iter_mut() {
+            if chain_node.wet_step == 0.0 {
+                chain_node.node.process(data);
+                continue;
+            }
+            for chunk in data.chunks_mut(self.dry.len()) {
+                let dry = &mut self.dry[0..chunk.len()];
+                dry.copy_from_slice(chunk);
+                chain_node.node.process(chunk);
+                for (frame, dry_frame) in chunk
+                    .chunks_exact_mut(num_channels)
+                    .zip(dry.chunks_exact(num_channels))
+                {
+                    chain_node.wet = (chain_node.wet + chain_node.wet_step).clamp(0.0, 1.0);
+                    for (sample, &dry_sample) in frame.iter_mut().zip(dry_frame) {
+                        *sample = dry_sample + (*sample - dry_sample) * chain_node.wet;
+                    }
+                }
+            }
+            if chain_node.wet == 1.0 && chain_node.wet_step > 0.0 {
+                chain_node.wet_step = 0.0;
+            }
+        }
+
+        let mut index = 0;
+        while index < self.nodes.len() {
+            if self.nodes[index].wet > 0.0 || self.nodes[index].wet_step >= 0.0 {
+                index += 1;
+                continue;
+            }
+            let mut chain_node = self.nodes.remove(index);
+            match chain_node.move_to.take() {
+                Some(to) => {
+                    // Its state belongs to where it was, and it's silent while it fades back in
+                    chain_node.node.reset();
+                    chain_node.wet_step = self.fade_step;
+                    let to = self.chain_index(to);
+                    self.nodes.insert(to, chain_node);
+                    // The nodes after either index have shifted, so look through them again
+                    index = 0;
+                }
+                None => self.push_removed(chain_node.node),
+            }
+        }
+    }
+
+    fn position(&self, id: DspNodeId) -> Option<usize> {
+        self.nodes.iter().position(|chain_node| chain_node.id == id)
+    }
+
+    /// Index in `nodes` of the node that is at `index` once the ones being removed are left out,
+    /// which is how the manager counts, or the end if there is no such node.
+    fn chain_index(&self, index: usize) -> usize {
+        self.nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, chain_node)| !chain_node.is_removed())
+            .nth(index)
+            .map_or(self.nodes.len(), |(chain_index, _)| chain_index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 1000;
+    /// Frames that a fade takes at `SAMPLE_RATE`.
+    const FADE_FRAMES: usize = 20;
+
+    struct Add(f32);
+
+    impl DspNode for Add {
+        fn name(&self) -> &'static str {
+            "add"
+        }
+
+        fn prepare(&mut self, _sample_rate: u32, _num_channels: usize) {}
+
+        fn process(&mut self, data: &mut [f32]) {
+            for sample in data.iter_mut() {
+                *sample += self.0;
+            }
+        }
+
+        fn reset(&mut self) {}
+    }
+
+    struct Double;
+
+    impl DspNode for Double {
+        fn name(&self) -> &'static str {
+            "double"
+        }
+
+        fn prepare(&mut self, _sample_rate: u32, _num_channels: usize) {}
+
+        fn process(&mut self, data: &mut [f32]) {
+            for sample in data.iter_mut() {
+                *sample *= 2.0;
+            }
+        }
+
+        fn reset(&mut self) {}
+    }
+
+    /// Passes silence through the chain in mono and returns the output.
+    fn process(chain: &mut DspChain, num_frames: usize) -> Vec<f32> {
+        let mut data = vec![0.0; num_frames];
+        chain.process(&mut data);
+        data
+    }
+
+    fn assert_smooth(output: &[f32]) {
+        for pair in output.windows(2) {
+            assert!(
+                (pair[1] - pair[0]).abs() <= 0.25,
+                "jumped from {} to {}",
+                pair[0],
+                pair[1]
+            );
+        }
+    }
+
+    fn chain() -> DspChain {
+        let mut chain = DspChain::new(SAMPLE_RATE, 1);
+        assert!(chain.insert(0, 0, Box::new(Add(1.0))).is_ok());
+        assert!(chain.insert(1, 1, Box::new(Double)).is_ok());
+        chain
+    }
+
+    #[test]
+    fn inserted_nodes_fade_in() {
+        let mut chain = chain();
+        let output = process(&mut chain, FADE_FRAMES * 2);
+        assert_smooth(&output);
+        assert_eq!(output.last(), Some(&2.0));
+    }
+
+    #[test]
+    fn moved_node_fades_out_and_back_in() {
+        let mut chain = chain();
+        process(&mut chain, FADE_FRAMES * 2);
+        chain.move_node(1, 0);
+        let output = process(&mut chain, FADE_FRAMES * 3);
+        assert_smooth(&output);
+        assert_eq!(output.last(), Some(&1.0));
+        assert!(chain.take_removed().is_none());
+    }
+
+    #[test]
+    fn removed_node_fades_out_and_is_handed_back() {
+        let mut chain = chain();
+        process(&mut chain, FADE_FRAMES * 2);
+        chain.remove(0);
+        let output = process(&mut chain, FADE_FRAMES * 2);
+        assert_smooth(&output);
+        assert_eq!(output.last(), Some(&0.0));
+        assert_eq!(chain.take_removed().map(|node| node.name()), Some("add"));
+        assert!(chain.take_removed().is_none());
+    }
+
+    #[test]
+    fn indexes_leave_out_removed_nodes() {
+        let mut chain = chain();
+        process(&mut chain, FADE_FRAMES * 2);
+        chain.remove(0);
+        // Goes after the doubling node, which is the first one the manager still knows about
+        assert!(chain.insert(1, 2, Box::new(Add(3.0))).is_ok());
+        let output = process(&mut chain, FADE_FRAMES * 2);
+        assert_smooth(&output);
+        assert_eq!(output.last(), Some(&3.0));
+    }
+
+    #[test]
+    fn node_that_cannot_be_handed_back_is_kept() {
+        let mut chain = DspChain::new(SAMPLE_RATE, 1);
+        chain.push_removed(Box::new(Double));
+        let node = chain.take_removed().unwrap();
+        chain.push_removed(node);
+        assert_eq!(chain.take_removed().map(|node| node.name()), Some("double"));
+    }
+}
@@ -0,0 +1,207 @@
+use arrayvec::ArrayVec;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    biquad::{BiquadCoefficients, BiquadState},
+    dsp::{DspNode, DspNodeParams},
+};
+
+pub const MAX_EQ_BANDS: usize = 10;
+const MAX_EQ_GAIN_DB: f32 = 24.0;
+const MIN_EQ_FREQUENCY: f32 = 10.0;
+const MAX_EQ_FREQUENCY: f32 = 22000.0;
+const MIN_EQ_Q: f32 = 0.1;
+const MAX_EQ_Q: f32 = 18.0;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum EqBandKind {
+    Peaking,
+    LowShelf,
+    HighShelf,
+    HighPass,
+    LowPass,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EqBand {
+    pub kind: EqBandKind,
+    /// Center or corner frequency in Hz.
+    pub frequency: f32,
+    /// Ignored by the pass filters.
+    pub gain_db: f32,
+    pub q: f32,
+}
+
+impl EqBand {
+    const fn new(kind: EqBandKind, frequency: f32, gain_db: f32, q: f32) -> EqBand {
+        EqBand {
+            kind,
+            frequency,
+            gain_db,
+            q,
+        }
+    }
+
+    fn clamped(&self) -> EqBand {
+        EqBand {
+            kind: self.kind,
+            frequency: self.frequency.max(MIN_EQ_FREQUENCY).min(MAX_EQ_FREQUENCY),
+            gain_db: self.gain_db.max(-MAX_EQ_GAIN_DB).min(MAX_EQ_GAIN_DB),
+            q: self.q.max(MIN_EQ_Q).min(MAX_EQ_Q),
+        }
+    }
+
+    fn coefficients(&self, sample_rate: u32) -> BiquadCoefficients {
+        let frequency = self.frequency as f64;
+        let gain_db = self.gain_db as f64;
+        let q = self.q as f64;
+        match self.kind {
+            EqBandKind::Peaking => BiquadCoefficients::peaking(sample_rate, frequency, q, gain_db),
+            EqBandKind::LowShelf => {
+                BiquadCoefficients::low_shelf(sample_rate, frequency, q, gain_db)
+            }
+            EqBandKind::HighShelf => {
+                BiquadCoefficients::high_shelf(sample_rate, frequency, q, gain_db)
+            }
+            EqBandKind::HighPass => BiquadCoefficients::high_pass(sample_rate, frequency, q),
+            EqBandKind::LowPass => BiquadCoefficients::low_pass(sample_rate, frequency, q),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum EqualizerPreset {
+    Flat,
+    BassBoost,
+    TrebleBoost,
+    Vocal,
+    Loudness,
+}
+
+impl EqualizerPreset {
+    pub fn bands(&self) -> Vec<EqBand> {
+        use EqBandKind::*;
+        match self {
+            EqualizerPreset::Flat => vec![],
+            EqualizerPreset::BassBoost => vec![EqBand::new(LowShelf, 120.0, 6.0, 0.707)],
+            EqualizerPreset::TrebleBoost => vec![EqBand::new(HighShelf, 6000.0, 6.0, 0.707)],
+            EqualizerPreset::Vocal => vec![
+                EqBand::new(LowShelf, 150.0, -3.0, 0.707),
+                EqBand::new(Peaking, 2500.0, 3.0, 1.0),
+                EqBand::new(HighShelf, 9000.0, -1.5, 0.707),
+            ],
+            EqualizerPreset::Loudness => vec![
+                EqBand::new(LowShelf, 100.0, 6.0, 0.707),
+                EqBand::new(Peaking, 1000.0, -2.0, 0.5),
+                EqBand::new(HighShelf, 10000.0, 4.0, 0.707),
+            ],
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EqualizerSettings {
+    pub enabled: bool,
+    pub bands: Vec<EqBand>,
+    /// The preset the bands were last set from, if they haven't been edited since.
+    pub preset: Option<EqualizerPreset>,
+}
+
+impl Default for EqualizerSettings {
+    fn default() -> Self {
+        EqualizerSettings {
+            enabled: false,
+            bands: vec![],
+            preset: Some(EqualizerPreset::Flat),
+        }
+    }
+}
+
+impl EqualizerSettings {
+    /// Parameters for the audio thread, which can be sent without any allocation.
+    pub fn params(&self) -> EqualizerParams {
+        self.bands
+            .iter()
+            .take(MAX_EQ_BANDS)
+            .map(|band| band.clamped())
+            .collect()
+    }
+}
+
+pub type EqualizerParams = ArrayVec<EqBand, MAX_EQ_BANDS>;
+
+/// Equalizer node made up of a series of biquad filters.
+pub struct Equalizer {
+    params: EqualizerParams,
+    sample_rate: u32,
+    coefficients: ArrayVec<BiquadCoefficients, MAX_EQ_BANDS>,
+    states: Vec<[BiquadState; MAX_EQ_BANDS]>,
+}
+
+impl Equalizer {
+    pub fn new(params: EqualizerParams) -> Equalizer {
+        Equalizer {
+            params,
+            sample_rate: 0,
+            coefficients: ArrayVec::new(),
+            states: Vec::new(),
+        }
+    }
+
+    fn update_coefficients(&mut self) {
+        self.coefficients.clear();
+        for band in self.params.iter() {
+            self.coefficients.push(band.coefficients(self.sample_rate));
+        }
+    }
+}
+
+impl DspNode for Equalizer {
+    fn name(&self) -> &'static str {
+        "equalizer"
+    }
+
+    fn prepare(&mut self, sample_rate: u32, num_channels: usize) {
+        self.sample_rate = sample_rate;
+        self.states = vec![[BiquadState::default(); MAX_EQ_BANDS]; num_channels];
+        self.update_coefficients();
+    }
+
+    fn process(&mut self, data: &mut [f32]) {
+        let num_channels = self.states.len();
+        if self.coefficients.is_empty() || num_channels == 0 {
+            return;
+        }
+        for frame in data.chunks_exact_mut(num_channels) {
+            for (sample, channel_states) in frame.iter_mut().zip(self.states.iter_mut()) {
+                let mut x = *sample as f64;
+                for (coefficients, state) in self.coefficients.iter().zip(channel_states.iter_mut())
+                {
+                    x = state.process(coefficients, x);
+                }
+                *sample = x as f32;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        for channel_states in self.states.iter_mut() {
+            for state in channel_states.iter_mut() {
+                state.reset();
+            }
+        }
+    }
+
+    fn set_params(&mut self, params: &DspNodeParams) {
+        match params {
+            DspNodeParams::Equalizer(params) => {
+                let num_bands_changed = params.len() != self.params.len();
+                self.params = params.clone();
+                self.update_coefficients();
+                if num_bands_changed {
+                    self.reset();
+                }
+            }
+        }
+    }
+}
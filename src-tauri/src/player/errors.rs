@@ -1,17 +1,133 @@
 use std::{error::Error, fmt::Display, io};
 
+use serde::{Deserialize, Serialize};
+
+/// How many times a file that fails partway through is reopened before it's skipped.
+pub const MAX_STREAM_RETRIES: u32 = 3;
+
+/// What went wrong, for the UI to decide how to present an error.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum PlayerErrorKind {
+    NotFound,
+    Io,
+    UnsupportedCodec,
+    NoTrack,
+    /// The file could be opened but broke off while decoding.
+    CorruptData,
+    Device,
+    /// The audio of the file couldn't be converted to the output rate, which is a fault of the
+    /// player rather than of the file.
+    Resampler,
+}
+
+impl Display for PlayerErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PlayerErrorKind::NotFound => write!(f, "The file could not be found"),
+            PlayerErrorKind::Io => write!(f, "The file could not be read"),
+            PlayerErrorKind::UnsupportedCodec => write!(f, "The file format is not supported"),
+            PlayerErrorKind::NoTrack => write!(f, "The file has no audio track"),
+            PlayerErrorKind::CorruptData => write!(f, "The file is damaged"),
+            PlayerErrorKind::Device => write!(f, "The output device failed"),
+            PlayerErrorKind::Resampler => {
+                write!(
+                    f,
+                    "The audio could not be converted to the output sample rate"
+                )
+            }
+        }
+    }
+}
+
+impl From<&io::Error> for PlayerErrorKind {
+    fn from(value: &io::Error) -> Self {
+        match value.kind() {
+            io::ErrorKind::NotFound => PlayerErrorKind::NotFound,
+            _ => PlayerErrorKind::Io,
+        }
+    }
+}
+
+impl From<&symphonia::core::errors::Error> for PlayerErrorKind {
+    fn from(value: &symphonia::core::errors::Error) -> Self {
+        match value {
+            symphonia::core::errors::Error::IoError(e) => e.into(),
+            symphonia::core::errors::Error::Unsupported(_) => PlayerErrorKind::UnsupportedCodec,
+            _ => PlayerErrorKind::CorruptData,
+        }
+    }
+}
+
+/// What the player does when a stream fails partway through.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub enum StreamErrorPolicy {
+    /// Reopens the file at the position where it failed, and skips it if that keeps failing.
+    #[default]
+    Retry,
+    Skip,
+    Stop,
+}
+
+/// What the player does next about a stream that failed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StreamErrorAction {
+    /// Reopens the file at the position where it failed.
+    Retry,
+    /// Reports the error and moves on to the next item of the queue.
+    Skip,
+    /// Reports the error and stops playback.
+    Stop,
+}
+
+impl StreamErrorPolicy {
+    /// What to do about a failed stream that was already reopened `retries` times.
+    pub fn action(self, retries: u32) -> StreamErrorAction {
+        match self {
+            StreamErrorPolicy::Retry if retries < MAX_STREAM_RETRIES => StreamErrorAction::Retry,
+            StreamErrorPolicy::Retry | StreamErrorPolicy::Skip => StreamErrorAction::Skip,
+            StreamErrorPolicy::Stop => StreamErrorAction::Stop,
+        }
+    }
+}
+
+/// An error that interrupted playback, as reported to the UI.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PlayerError {
+    pub kind: PlayerErrorKind,
+    /// The file that failed, unless it's an output error.
+    pub path: Option<String>,
+    pub message: String,
+}
+
 #[derive(Debug)]
 pub enum FileStreamOpenError {
     IoError(io::Error),
     SymphoniaError(symphonia::core::errors::Error),
     NoTrackFound,
+    ResamplerError(rubato::ResamplerConstructionError),
+}
+
+impl FileStreamOpenError {
+    pub fn kind(&self) -> PlayerErrorKind {
+        match self {
+            FileStreamOpenError::IoError(e) => e.into(),
+            FileStreamOpenError::SymphoniaError(e) => e.into(),
+            FileStreamOpenError::NoTrackFound => PlayerErrorKind::NoTrack,
+            FileStreamOpenError::ResamplerError(_) => PlayerErrorKind::Resampler,
+        }
+    }
 }
 
 impl Error for FileStreamOpenError {}
 
 impl Display for FileStreamOpenError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        todo!()
+        match self {
+            FileStreamOpenError::IoError(e) => write!(f, "{e}"),
+            FileStreamOpenError::SymphoniaError(e) => write!(f, "{e}"),
+            FileStreamOpenError::NoTrackFound => write!(f, "The file has no audio track"),
+            FileStreamOpenError::ResamplerError(e) => write!(f, "{e}"),
+        }
     }
 }
 
@@ -21,8 +137,107 @@ impl From<symphonia::core::errors::Error> for FileStreamOpenError {
     }
 }
 
+impl From<rubato::ResamplerConstructionError> for FileStreamOpenError {
+    fn from(value: rubato::ResamplerConstructionError) -> Self {
+        FileStreamOpenError::ResamplerError(value)
+    }
+}
+
 impl From<io::Error> for FileStreamOpenError {
     fn from(value: io::Error) -> Self {
         FileStreamOpenError::IoError(value)
     }
 }
+
+#[derive(Debug)]
+pub enum OutputError {
+    NoDevice,
+    DefaultConfigError(cpal::DefaultStreamConfigError),
+    SupportedConfigsError(cpal::SupportedStreamConfigsError),
+    BuildStreamError(cpal::BuildStreamError),
+    PlayStreamError(cpal::PlayStreamError),
+}
+
+impl Error for OutputError {}
+
+impl Display for OutputError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputError::NoDevice => write!(f, "No output device is available"),
+            OutputError::DefaultConfigError(e) => write!(f, "{e}"),
+            OutputError::SupportedConfigsError(e) => write!(f, "{e}"),
+            OutputError::BuildStreamError(e) => write!(f, "{e}"),
+            OutputError::PlayStreamError(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<cpal::DefaultStreamConfigError> for OutputError {
+    fn from(value: cpal::DefaultStreamConfigError) -> Self {
+        OutputError::DefaultConfigError(value)
+    }
+}
+
+impl From<cpal::SupportedStreamConfigsError> for OutputError {
+    fn from(value: cpal::SupportedStreamConfigsError) -> Self {
+        OutputError::SupportedConfigsError(value)
+    }
+}
+
+impl From<cpal::BuildStreamError> for OutputError {
+    fn from(value: cpal::BuildStreamError) -> Self {
+        OutputError::BuildStreamError(value)
+    }
+}
+
+impl From<cpal::PlayStreamError> for OutputError {
+    fn from(value: cpal::PlayStreamError) -> Self {
+        OutputError::PlayStreamError(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_policy_skips_after_retries() {
+        for retries in 0..MAX_STREAM_RETRIES {
+            assert_eq!(
+                StreamErrorPolicy::Retry.action(retries),
+                StreamErrorAction::Retry
+            );
+        }
+        assert_eq!(
+            StreamErrorPolicy::Retry.action(MAX_STREAM_RETRIES),
+            StreamErrorAction::Skip
+        );
+    }
+
+    #[test]
+    fn skip_policy_skips_to_next() {
+        assert_eq!(StreamErrorPolicy::Skip.action(0), StreamErrorAction::Skip);
+    }
+
+    #[test]
+    fn stop_policy_stops() {
+        assert_eq!(StreamErrorPolicy::Stop.action(0), StreamErrorAction::Stop);
+    }
+
+    #[test]
+    fn decode_errors_are_corrupt_data() {
+        let error = symphonia::core::errors::Error::DecodeError("invalid frame");
+        assert_eq!(PlayerErrorKind::from(&error), PlayerErrorKind::CorruptData);
+    }
+
+    #[test]
+    fn resampler_errors_are_not_blamed_on_the_file() {
+        let error = FileStreamOpenError::ResamplerError(
+            rubato::ResamplerConstructionError::InvalidSampleRate {
+                input: 0,
+                output: 48000,
+            },
+        );
+        assert_eq!(error.kind(), PlayerErrorKind::Resampler);
+    }
+}
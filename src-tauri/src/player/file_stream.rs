@@ -1,21 +1,44 @@
 use std::fs::File;
 use std::mem;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
 
 use log::{trace, warn};
-use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::audio::{AudioBufferRef, Channels};
+use symphonia::core::codecs::{CodecParameters, CodecRegistry, Decoder, DecoderOptions};
+use symphonia::core::formats::FormatReader;
 use symphonia::core::meta::MetadataRevision;
 use symphonia::core::units::TimeBase;
-use symphonia::core::{io::MediaSourceStream, probe::Hint};
+use symphonia::core::{
+    io::MediaSourceStream,
+    probe::{Hint, ProbedMetadata},
+};
 
 use super::decode_worker::{
     DecodeWorker, DecodeWorkerToFileStreamMessage, DecodedBlock, FileStreamToDecodeWorkerMessage,
+    LoopWrap,
 };
-use super::errors::FileStreamOpenError;
+use super::errors::{FileStreamOpenError, PlayerErrorKind};
+use super::metadata::StreamCodecInfo;
+use super::resampler::ResamplerKind;
+use super::time_stretch::SharedPlaybackRate;
 
 const MESSAGE_BUFFER_SIZE: usize = 16384;
 const MIN_BLOCK_SIZE: usize = 1024;
 
+/// Symphonia's codecs that are enabled by the cargo features, along with our own decoders for
+/// formats that symphonia can demux but not decode.
+pub fn codec_registry() -> &'static CodecRegistry {
+    static CODECS: OnceLock<CodecRegistry> = OnceLock::new();
+    CODECS.get_or_init(|| {
+        let mut registry = CodecRegistry::new();
+        symphonia::default::register_enabled_codecs(&mut registry);
+        #[cfg(feature = "opus")]
+        registry.register_all::<super::opus_decoder::OpusDecoder>();
+        registry
+    })
+}
+
 pub struct ReadData<'a> {
     data: &'a Vec<Vec<f32>>,
     len: usize,
@@ -60,82 +83,170 @@ pub struct FileStream {
     n_frames: Option<u64>,
     time_base: Option<TimeBase>,
     metadata: Option<MetadataRevision>,
+    codec_info: StreamCodecInfo,
+    resample_ratio: f64,
+    sample_rate: u32,
+    source_sample_rate: u32,
+    channel_layout: Channels,
+    loop_region: Option<(usize, usize)>,
+    /// Why decoding stopped early, once the stream has been read up to that point.
+    error: Option<PlayerErrorKind>,
 }
 
-pub struct FileStreamMetadata {
-    time_base: Option<TimeBase>,
-    preferred_metadata: Option<MetadataRevision>,
+/// A file opened with symphonia, along with a decoder for its default track.
+pub struct TrackDecoder {
+    pub reader: Box<dyn FormatReader>,
+    pub decoder: Box<dyn Decoder>,
+    pub track_id: u32,
+    pub n_frames: Option<u64>,
+    pub time_base: Option<TimeBase>,
+    pub codec_params: CodecParameters,
+    pub file_size: Option<u64>,
+    /// Metadata that was found outside of the container while probing the file.
+    pub probe_metadata: ProbedMetadata,
 }
 
-impl FileStream {
-    pub fn open<P>(file_path: P, target_sample_rate: u32) -> Result<Self, FileStreamOpenError>
-    where
-        P: Into<PathBuf>,
-    {
-        let file: PathBuf = file_path.into();
+impl TrackDecoder {
+    pub fn open(file: &Path) -> Result<TrackDecoder, FileStreamOpenError> {
         let mut hint = Hint::new();
         if let Some(extension) = file.extension() {
             hint.with_extension(extension.to_str().unwrap());
         }
 
-        let source = Box::new(File::open(file.clone())?);
-        let mss = MediaSourceStream::new(source, Default::default());
+        let source = File::open(file)?;
+        let file_size = source.metadata().ok().map(|metadata| metadata.len());
+        let mss = MediaSourceStream::new(Box::new(source), Default::default());
 
-        let mut probed = symphonia::default::get_probe().format(
+        let probed = symphonia::default::get_probe().format(
             &hint,
             mss,
             &Default::default(),
             &Default::default(),
         )?;
 
-        let mut reader = probed.format;
+        let reader = probed.format;
         let track = reader
             .default_track()
             .ok_or(FileStreamOpenError::NoTrackFound)?;
         let track_id = track.id;
         let n_frames = track.codec_params.n_frames;
         let time_base = track.codec_params.time_base;
+        let codec_params = track.codec_params.clone();
+
+        let decoder =
+            codec_registry().make(&track.codec_params, &DecoderOptions { verify: false })?;
 
-        let mut decoder = symphonia::default::get_codecs()
-            .make(&track.codec_params, &DecoderOptions { verify: false })?;
+        Ok(TrackDecoder {
+            reader,
+            decoder,
+            track_id,
+            n_frames,
+            time_base,
+            codec_params,
+            file_size,
+            probe_metadata: probed.metadata,
+        })
+    }
+
+    /// The tags of the file, preferring those of the container format over other tags found
+    /// while probing.
+    pub fn metadata(&mut self) -> Option<MetadataRevision> {
+        self.reader.metadata().current().cloned().or_else(|| {
+            self.probe_metadata
+                .get()
+                .as_ref()
+                .and_then(|metadata| metadata.current().cloned())
+        })
+    }
 
-        let decoded = loop {
-            let packet = match reader.next_packet() {
-                Ok(packet) => packet,
-                Err(err) => break Err(err),
-            };
+    /// Decodes the next packet of the track, skipping packets that fail to decode.
+    pub fn next_buffer(&mut self) -> symphonia::core::errors::Result<AudioBufferRef<'_>> {
+        loop {
+            let packet = self.reader.next_packet()?;
 
-            if packet.track_id() != track_id {
+            if packet.track_id() != self.track_id {
                 continue;
             }
 
-            match decoder.decode(&packet) {
+            match self.decoder.decode(&packet) {
                 Err(symphonia::core::errors::Error::DecodeError(err)) => {
                     // Decode errors are not fatal. Print the error message and try to decode the next
                     // packet as usual.
                     warn!("decode error: {}", err);
                 }
-                Err(err) => break Err(err),
-                Ok(decoded) => break Ok(decoded),
+                Err(err) => return Err(err),
+                Ok(_) => return Ok(self.decoder.last_decoded()),
             }
-        }?;
+        }
+    }
+}
+
+pub struct FileStreamMetadata {
+    time_base: Option<TimeBase>,
+    preferred_metadata: Option<MetadataRevision>,
+}
+
+impl FileStream {
+    /// Opens a file and starts decoding it on a worker thread. `select_sample_rate` is called with
+    /// the sample rate of the file and returns the rate to resample to, or `None` to keep it.
+    /// `resampler_kind` is the algorithm used if the file is resampled, and `playback_rate` is
+    /// followed as it changes if given.
+    pub fn open<P, F>(
+        file_path: P,
+        select_sample_rate: F,
+        resampler_kind: ResamplerKind,
+        playback_rate: Option<Arc<SharedPlaybackRate>>,
+    ) -> Result<Self, FileStreamOpenError>
+    where
+        P: Into<PathBuf>,
+        F: FnOnce(u32) -> Option<u32>,
+    {
+        let file: PathBuf = file_path.into();
+        let track = TrackDecoder::open(&file)?;
+        Self::start(
+            file,
+            track,
+            select_sample_rate,
+            resampler_kind,
+            playback_rate,
+        )
+    }
+
+    /// Starts decoding a track that was opened from `file` on a worker thread.
+    fn start<F>(
+        file: PathBuf,
+        mut track: TrackDecoder,
+        select_sample_rate: F,
+        resampler_kind: ResamplerKind,
+        playback_rate: Option<Arc<SharedPlaybackRate>>,
+    ) -> Result<Self, FileStreamOpenError>
+    where
+        F: FnOnce(u32) -> Option<u32>,
+    {
+        let decoded = track.next_buffer()?;
 
         trace!("First packet decoded frames: {}", decoded.frames());
 
         let spec = decoded.spec();
         let sample_rate = spec.rate;
         let block_size = decoded.capacity().max(MIN_BLOCK_SIZE);
-        let num_channels = spec.channels.count();
-
-        // Prefer metadata that's provided in the container format over other tags found during the
-        // probe operation.
-        let metadata = reader.metadata().current().cloned().or_else(|| {
-            probed
-                .metadata
-                .get()
-                .as_ref()
-                .and_then(|metadata| metadata.current().cloned())
-        });
+        let channel_layout = spec.channels;
+        let num_channels = channel_layout.count();
+        let target_sample_rate = select_sample_rate(sample_rate).unwrap_or(sample_rate);
+
+        let mut codec_info = StreamCodecInfo::new(&track.codec_params, track.file_size);
+        // Some formats only have these once the first packet is decoded
+        codec_info.sample_rate.get_or_insert(sample_rate);
+        codec_info.channels.get_or_insert(num_channels);
+        let metadata = track.metadata();
+        let TrackDecoder {
+            reader,
+            decoder,
+            track_id,
+            n_frames,
+            time_base,
+            ..
+        } = track;
 
         let (from_worker_producer, from_worker_consumer) =
             rtrb::RingBuffer::new(MESSAGE_BUFFER_SIZE);
@@ -145,12 +256,14 @@ impl FileStream {
             num_channels,
             block_size,
             sample_rate,
+            resampler_kind,
+            playback_rate,
             track_id,
             reader,
             decoder,
             from_worker_producer,
             to_worker_consumer,
-        );
+        )?;
 
         std::thread::spawn(move || {
             trace!("Starting decode worker for {file:?}");
@@ -167,6 +280,13 @@ impl FileStream {
             n_frames,
             time_base,
             metadata,
+            codec_info,
+            resample_ratio: target_sample_rate as f64 / sample_rate as f64,
+            sample_rate: target_sample_rate,
+            source_sample_rate: sample_rate,
+            channel_layout,
+            loop_region: None,
+            error: None,
         })
     }
 
@@ -178,10 +298,45 @@ impl FileStream {
         self.time_base.as_ref()
     }
 
+    pub fn num_channels(&self) -> usize {
+        self.read_buffer.len()
+    }
+
+    /// Sample rate of the decoded audio, after resampling.
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Sample rate of the file itself.
+    pub fn source_sample_rate(&self) -> u32 {
+        self.source_sample_rate
+    }
+
+    /// Speaker positions of the decoded channels, in channel order.
+    pub fn channel_layout(&self) -> Channels {
+        self.channel_layout
+    }
+
     pub fn playhead(&self) -> usize {
         self.playhead
     }
 
+    /// Estimated number of frames at the target sample rate left until the end of the file, at
+    /// the playback rate of the decoded blocks. There is no end while a region is looped.
+    pub fn remaining_frames(&self) -> Option<usize> {
+        if self.loop_region.is_some() {
+            return None;
+        }
+        let resample_ratio = self
+            .blocks
+            .as_ref()
+            .map_or(self.resample_ratio, |block| block.resample_ratio);
+        self.n_frames.map(|n_frames| {
+            let remaining = n_frames.saturating_sub(self.playhead as u64);
+            (remaining as f64 * resample_ratio) as usize
+        })
+    }
+
     pub fn is_ready(&mut self) -> bool {
         self.poll();
         self.blocks.is_some()
@@ -191,6 +346,16 @@ impl FileStream {
         self.metadata.as_ref()
     }
 
+    pub fn codec_info(&self) -> &StreamCodecInfo {
+        &self.codec_info
+    }
+
+    /// Why the stream ended early, either with an error from the decode worker or because the
+    /// worker stopped. The stream can't be read any further once this is set.
+    pub fn error(&self) -> Option<PlayerErrorKind> {
+        self.error
+    }
+
     pub fn read(&mut self, frames: usize) -> Option<ReadData> {
         self.poll();
         if let Some(mut block) = self.blocks.as_mut() {
@@ -199,7 +364,13 @@ impl FileStream {
             let mut is_eof = false;
             let frames_to_read = frames.min(READ_BUFFER_SIZE);
             while frames_read < frames_to_read {
-                let available_in_block = block.num_frames - block.playhead;
+                // Frames after a wrap belong to the start of the loop region, so they are read
+                // separately
+                let wrap_at = block
+                    .wrap
+                    .map_or(block.num_frames, |wrap| wrap.at)
+                    .max(block.playhead);
+                let available_in_block = wrap_at - block.playhead;
                 let read_from_block = available_in_block.min(frames_to_read - frames_read);
 
                 for (i, channel) in self.read_buffer.iter_mut().enumerate() {
@@ -212,14 +383,20 @@ impl FileStream {
                 frames_read += read_from_block;
                 source_frames_read += (read_from_block as f64 / block.resample_ratio) as usize;
 
-                if read_from_block == available_in_block {
+                if let Some(wrap) = block.wrap.filter(|wrap| wrap.at <= block.playhead) {
+                    block.wrap = None;
+                    self.playhead = wrap.to;
+                    source_frames_read = 0;
+                }
+                if block.playhead == block.num_frames {
                     is_eof = block.is_eof;
+                    if block.error.is_some() {
+                        self.error = block.error;
+                    }
                     if block.next.is_none() {
                         break;
                     }
                     block = block.next.as_mut().unwrap();
-                } else {
-                    break;
                 }
             }
             while let Some(block) = self.blocks.as_mut() {
@@ -264,6 +441,83 @@ impl FileStream {
         }
     }
 
+    /// Loops playback between two positions in frames of the file, or stops looping. The audio
+    /// that was already decoded is cut at the end of the region and the worker continues from its
+    /// start, so that playback wraps around without a gap. If the playhead is already past the end
+    /// of the region, the start of the region is returned for the caller to seek to.
+    pub fn set_loop(&mut self, loop_region: Option<(usize, usize)>) -> Option<usize> {
+        self.poll();
+        self.loop_region = loop_region;
+        let _ = self
+            .message_producer
+            .push(FileStreamToDecodeWorkerMessage::SetLoop(loop_region));
+        if let Some((start, end)) = loop_region {
+            if self.playhead >= end {
+                return Some(start);
+            }
+        }
+
+        // Find the last block to keep, which is the one that reaches the end of the region or
+        // wraps around for an earlier region
+        let limit = loop_region.map_or(usize::MAX, |(_, end)| end);
+        let mut resume_from = self.playhead;
+        let mut num_kept = 0;
+        let mut block = self.blocks.as_deref();
+        while let Some(current) = block {
+            num_kept += 1;
+            if current.wrap.is_some() {
+                break;
+            }
+            resume_from = block_end(current);
+            if resume_from >= limit {
+                break;
+            }
+            block = current.next.as_deref();
+        }
+
+        let mut dropped = None;
+        if num_kept > 0 {
+            let mut last = self.blocks.as_mut().unwrap();
+            for _ in 1..num_kept {
+                last = last.next.as_mut().unwrap();
+            }
+            dropped = last.next.take();
+            if let Some(wrap) = last.wrap.take() {
+                // What comes after the wrap is the start of the previous region
+                last.num_frames = wrap.at;
+            }
+            resume_from = block_end(last);
+            if let Some((start, end)) = loop_region {
+                // A region that extends past the end of the file wraps around at the end
+                if resume_from >= end || last.is_eof {
+                    let kept_frames = (end.min(resume_from).saturating_sub(last.source_pos) as f64
+                        * last.resample_ratio) as usize;
+                    last.num_frames = last.num_frames.min(kept_frames).max(last.playhead);
+                    last.is_eof = false;
+                    last.wrap = Some(LoopWrap {
+                        at: last.num_frames,
+                        to: start,
+                    });
+                    resume_from = start;
+                }
+            }
+        }
+
+        self.stream_id += 1;
+        let _ = self
+            .message_producer
+            .push(FileStreamToDecodeWorkerMessage::Seek(
+                resume_from,
+                self.stream_id,
+            ));
+        if let Some(block) = dropped {
+            let _ = self
+                .message_producer
+                .push(FileStreamToDecodeWorkerMessage::DisposeBlock(block));
+        }
+        None
+    }
+
     fn poll(&mut self) {
         while let Ok(message) = self.message_consumer.pop() {
             match message {
@@ -287,9 +541,18 @@ impl FileStream {
                 }
             }
         }
+        if self.blocks.is_none() && self.error.is_none() && self.message_consumer.is_abandoned() {
+            // The decode worker stopped without ending the stream, so nothing more will arrive
+            self.error = Some(PlayerErrorKind::CorruptData);
+        }
     }
 }
 
+/// Position in frames of the file just after the end of a block.
+fn block_end(block: &DecodedBlock) -> usize {
+    block.source_pos + (block.num_frames as f64 / block.resample_ratio) as usize
+}
+
 impl Drop for FileStream {
     fn drop(&mut self) {
         if let Some(block) = self.blocks.take() {
@@ -304,3 +567,278 @@ impl Drop for FileStream {
             ));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Range;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use symphonia::core::errors::{decode_error, unsupported_error, Result};
+    use symphonia::core::formats::{Cue, FormatOptions, Packet, SeekMode, SeekTo, SeekedTo, Track};
+    use symphonia::core::meta::Metadata;
+
+    use super::*;
+
+    const STALL_TIMEOUT: Duration = Duration::from_secs(5);
+    /// The tone that `tests/fixtures/generate.py` writes to the lossless fixtures.
+    const TONE_SAMPLE_RATE: u32 = 8000;
+    const TONE_FRAMES: usize = 2000;
+    const TONE_FREQUENCIES: [f64; 2] = [440.0, 660.0];
+    const MP3_FRAME_SIZE: usize = 1152;
+    /// How far the samples of a lossless fixture may be off, which is only from rounding.
+    const LOSSLESS_TOLERANCE: f32 = 1.0 / 32768.0;
+
+    fn fixture(name: &str) -> PathBuf {
+        [env!("CARGO_MANIFEST_DIR"), "tests", "fixtures", name]
+            .iter()
+            .collect()
+    }
+
+    fn tone_sample(channel: usize, frame: usize) -> f32 {
+        let phase = 2.0 * std::f64::consts::PI * TONE_FREQUENCIES[channel] * frame as f64
+            / TONE_SAMPLE_RATE as f64;
+        (0.5 * 32767.0 * phase.sin()).round() as f32 / 32768.0
+    }
+
+    /// Checks that the samples are the tone from `start` frames in.
+    fn assert_tone(samples: &[Vec<f32>], start: usize) {
+        assert_tone_within(samples, start, LOSSLESS_TOLERANCE);
+    }
+
+    fn assert_tone_within(samples: &[Vec<f32>], start: usize, tolerance: f32) {
+        assert_eq!(samples.len(), TONE_FREQUENCIES.len());
+        for (channel, samples) in samples.iter().enumerate() {
+            for (frame, &sample) in samples.iter().enumerate() {
+                let expected = tone_sample(channel, start + frame);
+                assert!(
+                    (sample - expected).abs() <= tolerance,
+                    "Frame {frame} of channel {channel} is {sample} instead of {expected}"
+                );
+            }
+        }
+    }
+
+    fn frames(samples: &[Vec<f32>], range: Range<usize>) -> Vec<Vec<f32>> {
+        samples
+            .iter()
+            .map(|channel| channel[range.clone()].to_vec())
+            .collect()
+    }
+
+    struct Decoded {
+        sample_rate: u32,
+        samples: Vec<Vec<f32>>,
+        error: Option<PlayerErrorKind>,
+    }
+
+    /// Reads a stream until it ends, at the sample rate of the file.
+    fn read_to_end(mut file_stream: FileStream) -> Decoded {
+        let mut samples = vec![Vec::new(); file_stream.num_channels()];
+        let mut last_read = Instant::now();
+        loop {
+            if !file_stream.is_ready() {
+                if file_stream.error().is_some() {
+                    break;
+                }
+                assert!(last_read.elapsed() < STALL_TIMEOUT, "Decoding stalled");
+                thread::sleep(Duration::from_millis(1));
+                continue;
+            }
+            last_read = Instant::now();
+            let read_data = file_stream.read(READ_BUFFER_SIZE).unwrap();
+            for (channel, samples) in samples.iter_mut().enumerate() {
+                samples.extend_from_slice(read_data.read_channel(channel));
+            }
+            if read_data.reached_end_of_file() {
+                break;
+            }
+        }
+        Decoded {
+            sample_rate: file_stream.sample_rate(),
+            samples,
+            error: file_stream.error(),
+        }
+    }
+
+    fn decode(name: &str) -> Decoded {
+        let file_stream = FileStream::open(fixture(name), |_| None, ResamplerKind::default(), None)
+            .unwrap_or_else(|e| panic!("Failed to open {name}: {e}"));
+        read_to_end(file_stream)
+    }
+
+    /// Checks a fixture of a lossless format, which holds the whole tone.
+    fn assert_decodes_tone(name: &str) {
+        let decoded = decode(name);
+        assert_eq!(decoded.error, None);
+        assert_eq!(decoded.sample_rate, TONE_SAMPLE_RATE);
+        assert_eq!(decoded.samples[0].len(), TONE_FRAMES);
+        assert_tone(&decoded.samples, 0);
+    }
+
+    /// Checks a fixture of a lossy format, which holds mono silence since there's no encoder to
+    /// write anything else.
+    fn assert_decodes_silence(name: &str, sample_rate: u32, num_frames: usize) {
+        let decoded = decode(name);
+        assert_eq!(decoded.error, None);
+        assert_eq!(decoded.sample_rate, sample_rate);
+        assert_eq!(decoded.samples.len(), 1);
+        assert_eq!(decoded.samples[0].len(), num_frames);
+        assert!(decoded.samples[0].iter().all(|sample| sample.abs() < 1e-4));
+    }
+
+    /// Passes packets through until `packets_left` runs out, and then fails like a file that is
+    /// damaged beyond what the decoder can skip.
+    struct FailingReader {
+        reader: Box<dyn FormatReader>,
+        packets_left: usize,
+    }
+
+    impl FormatReader for FailingReader {
+        fn try_new(_source: MediaSourceStream, _options: &FormatOptions) -> Result<Self> {
+            unsupported_error("test: not a format")
+        }
+
+        fn cues(&self) -> &[Cue] {
+            self.reader.cues()
+        }
+
+        fn metadata(&mut self) -> Metadata<'_> {
+            self.reader.metadata()
+        }
+
+        fn seek(&mut self, mode: SeekMode, to: SeekTo) -> Result<SeekedTo> {
+            self.reader.seek(mode, to)
+        }
+
+        fn tracks(&self) -> &[Track] {
+            self.reader.tracks()
+        }
+
+        fn next_packet(&mut self) -> Result<Packet> {
+            if self.packets_left == 0 {
+                return decode_error("test: damaged packet");
+            }
+            self.packets_left -= 1;
+            self.reader.next_packet()
+        }
+
+        fn into_inner(self: Box<Self>) -> MediaSourceStream {
+            self.reader.into_inner()
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "flac")]
+    fn truncated_flac_plays_up_to_the_cut() {
+        let decoded = decode("truncated.flac");
+        assert_eq!(decoded.error, None);
+        // The file is cut in its third frame of 576
+        assert_eq!(decoded.samples[0].len(), 1152);
+        assert_tone(&decoded.samples, 0);
+    }
+
+    #[test]
+    fn truncated_mp3_plays_up_to_the_cut() {
+        let decoded = decode("truncated.mp3");
+        assert_eq!(decoded.error, None);
+        assert_eq!(decoded.sample_rate, 44100);
+        // The file is cut in its 19th frame
+        assert_eq!(decoded.samples[0].len(), 18 * MP3_FRAME_SIZE);
+    }
+
+    #[test]
+    #[cfg(feature = "flac")]
+    fn damaged_flac_frame_is_skipped() {
+        let decoded = decode("damaged.flac");
+        assert_eq!(decoded.error, None);
+        // The second frame of 576 is left out and playback goes on with the third
+        assert_eq!(decoded.samples[0].len(), TONE_FRAMES - 576);
+        assert_tone(&frames(&decoded.samples, 0..576), 0);
+        assert_tone(&frames(&decoded.samples, 576..TONE_FRAMES - 576), 1152);
+    }
+
+    #[test]
+    fn stream_that_fails_ends_with_error() {
+        let path = fixture("silence.mp3");
+        let mut track = TrackDecoder::open(&path).unwrap();
+        // The first packet is decoded when the stream starts, and two more by the worker
+        track.reader = Box::new(FailingReader {
+            reader: track.reader,
+            packets_left: 3,
+        });
+        let file_stream =
+            FileStream::start(path, track, |_| None, ResamplerKind::default(), None).unwrap();
+        let decoded = read_to_end(file_stream);
+        assert_eq!(decoded.error, Some(PlayerErrorKind::CorruptData));
+        // Frames that were decoded before the failure are still played
+        assert_eq!(decoded.samples[0].len(), 3 * MP3_FRAME_SIZE);
+    }
+
+    #[test]
+    fn decodes_mp3() {
+        assert_decodes_silence("silence.mp3", 44100, 20 * MP3_FRAME_SIZE);
+    }
+
+    #[test]
+    #[cfg(feature = "flac")]
+    fn decodes_flac() {
+        assert_decodes_tone("tone.flac");
+    }
+
+    #[test]
+    #[cfg(feature = "vorbis")]
+    fn decodes_vorbis() {
+        // Short blocks of 256 overlap by half, and the first of the 40 packets only primes the
+        // decoder
+        assert_decodes_silence("silence.ogg", 44100, 39 * 128);
+    }
+
+    #[test]
+    #[cfg(feature = "wav")]
+    fn decodes_wav() {
+        assert_decodes_tone("tone.wav");
+    }
+
+    #[test]
+    #[cfg(feature = "aiff")]
+    fn decodes_aiff() {
+        assert_decodes_tone("tone.aiff");
+    }
+
+    #[test]
+    #[cfg(feature = "alac")]
+    fn decodes_alac() {
+        assert_decodes_tone("tone-alac.m4a");
+    }
+
+    #[test]
+    #[cfg(feature = "aac")]
+    fn decodes_aac() {
+        assert_decodes_silence("silence.aac", 44100, 20 * 1024);
+    }
+
+    #[test]
+    #[cfg(feature = "adpcm")]
+    fn decodes_adpcm() {
+        let decoded = decode("tone-adpcm.wav");
+        assert_eq!(decoded.error, None);
+        assert_eq!(decoded.sample_rate, TONE_SAMPLE_RATE);
+        // Blocks of 256 bytes hold 249 frames, and the frames that don't fill a block are left out
+        assert_eq!(decoded.samples[0].len(), 8 * 249);
+        assert_tone_within(&decoded.samples, 0, 0.04);
+    }
+
+    #[test]
+    #[cfg(feature = "mkv")]
+    fn decodes_mkv() {
+        assert_decodes_tone("tone.mkv");
+    }
+
+    #[test]
+    #[cfg(feature = "opus")]
+    fn decodes_opus() {
+        // Packets of 20 ms at 48 kHz, of which the pre-skip is dropped
+        assert_decodes_silence("silence.opus", 48000, 20 * 960 - 312);
+    }
+}
@@ -0,0 +1,104 @@
+use std::path::Path;
+
+use serde::Serialize;
+
+/// A format that the player can decode, with the file extensions it's recognized by.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct SupportedFormat {
+    pub name: &'static str,
+    /// Lowercase extensions without the leading dot. Some are shared between formats, e.g. WAV
+    /// files can hold PCM or ADPCM audio.
+    pub extensions: &'static [&'static str],
+}
+
+/// The formats that this build can decode. MP3 is always available, everything else depends on
+/// the cargo features that the player was built with.
+pub fn supported_formats() -> Vec<SupportedFormat> {
+    let formats = [
+        (
+            true,
+            SupportedFormat {
+                name: "MP3",
+                extensions: &["mp3"],
+            },
+        ),
+        (
+            cfg!(feature = "flac"),
+            SupportedFormat {
+                name: "FLAC",
+                extensions: &["flac"],
+            },
+        ),
+        (
+            cfg!(feature = "vorbis"),
+            SupportedFormat {
+                name: "Ogg Vorbis",
+                extensions: &["ogg", "oga"],
+            },
+        ),
+        (
+            cfg!(feature = "wav"),
+            SupportedFormat {
+                name: "WAV",
+                extensions: &["wav", "wave"],
+            },
+        ),
+        (
+            cfg!(feature = "aiff"),
+            SupportedFormat {
+                name: "AIFF",
+                extensions: &["aiff", "aif", "aifc"],
+            },
+        ),
+        (
+            cfg!(feature = "alac"),
+            SupportedFormat {
+                name: "ALAC",
+                extensions: &["m4a", "mp4"],
+            },
+        ),
+        (
+            cfg!(feature = "aac"),
+            SupportedFormat {
+                name: "AAC",
+                extensions: &["m4a", "mp4", "aac"],
+            },
+        ),
+        (
+            cfg!(feature = "opus"),
+            SupportedFormat {
+                name: "Opus",
+                extensions: &["opus"],
+            },
+        ),
+        (
+            cfg!(feature = "adpcm"),
+            SupportedFormat {
+                name: "ADPCM",
+                extensions: &["wav"],
+            },
+        ),
+        (
+            cfg!(feature = "mkv"),
+            SupportedFormat {
+                name: "Matroska",
+                extensions: &["mkv", "mka", "webm"],
+            },
+        ),
+    ];
+    formats
+        .into_iter()
+        .filter_map(|(is_enabled, format)| is_enabled.then_some(format))
+        .collect()
+}
+
+/// Whether the extension of a file is one of a supported format.
+pub fn is_supported_path(path: &Path) -> bool {
+    let Some(extension) = path.extension().and_then(|extension| extension.to_str()) else {
+        return false;
+    };
+    let extension = extension.to_lowercase();
+    supported_formats()
+        .iter()
+        .any(|format| format.extensions.contains(&extension.as_str()))
+}
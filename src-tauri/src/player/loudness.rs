@@ -0,0 +1,292 @@
+use std::{collections::VecDeque, f64::consts::PI};
+
+use serde::{Deserialize, Serialize};
+use symphonia::core::audio::Channels;
+
+use super::{
+    biquad::{BiquadCoefficients, BiquadState},
+    file_stream::ReadData,
+};
+
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+const RELATIVE_GATE_LU: f64 = -10.0;
+const LOUDNESS_RANGE_RELATIVE_GATE_LU: f64 = -20.0;
+
+/// Gating blocks are made up of 100 ms sub-blocks, which gives the 75% overlap of 400 ms momentary
+/// blocks required by ITU-R BS.1770 and a 10 Hz rate for 3 s short-term blocks.
+const SUB_BLOCK_SECONDS: f64 = 0.1;
+const MOMENTARY_SUB_BLOCKS: usize = 4;
+const SHORT_TERM_SUB_BLOCKS: usize = 30;
+
+const TRUE_PEAK_OVERSAMPLING: usize = 4;
+const TRUE_PEAK_TAPS_PER_PHASE: usize = 12;
+
+/// Weight of the surround channels in the loudness sum, which is +1.5 dB.
+const SURROUND_CHANNEL_WEIGHT: f64 = 1.41;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LoudnessMeasurement {
+    /// Integrated loudness in LUFS, or `None` if everything was below the absolute gate.
+    pub integrated: Option<f64>,
+    /// True peak as a linear sample amplitude.
+    pub true_peak: f64,
+    /// Loudness range in LU.
+    pub loudness_range: f64,
+}
+
+/// Block energies collected by a `LoudnessMeter`. Blocks from multiple tracks can be combined to
+/// measure an album as a whole.
+#[derive(Clone, Debug, Default)]
+pub struct LoudnessBlocks {
+    momentary: Vec<f64>,
+    short_term: Vec<f64>,
+    true_peak: f64,
+}
+
+impl LoudnessBlocks {
+    pub fn extend(&mut self, other: &LoudnessBlocks) {
+        self.momentary.extend_from_slice(&other.momentary);
+        self.short_term.extend_from_slice(&other.short_term);
+        self.true_peak = self.true_peak.max(other.true_peak);
+    }
+
+    pub fn measure(&self) -> LoudnessMeasurement {
+        LoudnessMeasurement {
+            integrated: gated_mean_energy(&self.momentary, RELATIVE_GATE_LU).map(loudness),
+            true_peak: self.true_peak,
+            loudness_range: loudness_range(&self.short_term),
+        }
+    }
+}
+
+fn loudness(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+fn mean(values: &[f64]) -> Option<f64> {
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.iter().sum::<f64>() / values.len() as f64)
+    }
+}
+
+fn above_absolute_gate(energies: &[f64]) -> Vec<f64> {
+    energies
+        .iter()
+        .copied()
+        .filter(|&energy| loudness(energy) >= ABSOLUTE_GATE_LUFS)
+        .collect()
+}
+
+/// Mean energy of the blocks that pass both the absolute gate and the relative gate.
+fn gated_mean_energy(energies: &[f64], relative_gate_lu: f64) -> Option<f64> {
+    let gated = above_absolute_gate(energies);
+    let relative_gate = loudness(mean(&gated)?) + relative_gate_lu;
+    let gated: Vec<f64> = gated
+        .into_iter()
+        .filter(|&energy| loudness(energy) >= relative_gate)
+        .collect();
+    mean(&gated)
+}
+
+/// Loudness range as defined by EBU Tech 3342.
+fn loudness_range(short_term: &[f64]) -> f64 {
+    let gated = above_absolute_gate(short_term);
+    let Some(mean_energy) = mean(&gated) else {
+        return 0.0;
+    };
+    let relative_gate = loudness(mean_energy) + LOUDNESS_RANGE_RELATIVE_GATE_LU;
+    let mut values: Vec<f64> = gated
+        .into_iter()
+        .map(loudness)
+        .filter(|&value| value >= relative_gate)
+        .collect();
+    if values.is_empty() {
+        return 0.0;
+    }
+    values.sort_by(|a, b| a.total_cmp(b));
+    let percentile = |p: f64| values[((values.len() - 1) as f64 * p).round() as usize];
+    percentile(0.95) - percentile(0.10)
+}
+
+/// Coefficients of the two stage K-weighting filter, derived for any sample rate as done by
+/// libebur128.
+fn k_weighting(sample_rate: u32) -> [BiquadCoefficients; 2] {
+    let rate = sample_rate as f64;
+
+    // High shelf modelling the acoustic effect of the head
+    let f0 = 1681.974450955533;
+    let gain_db = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * f0 / rate).tan();
+    let vh = 10_f64.powf(gain_db / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let pre_filter = BiquadCoefficients::new(
+        [
+            vh + vb * k / q + k * k,
+            2.0 * (k * k - vh),
+            vh - vb * k / q + k * k,
+        ],
+        [
+            1.0 + k / q + k * k,
+            2.0 * (k * k - 1.0),
+            1.0 - k / q + k * k,
+        ],
+    );
+
+    // RLB high pass
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * f0 / rate).tan();
+    let rlb_filter = BiquadCoefficients::new(
+        [1.0, -2.0, 1.0],
+        [
+            1.0 + k / q + k * k,
+            2.0 * (k * k - 1.0),
+            1.0 - k / q + k * k,
+        ],
+    );
+
+    [pre_filter, rlb_filter]
+}
+
+/// Windowed sinc interpolation filter for true peak detection, split into polyphase components.
+fn true_peak_filter() -> Vec<[f64; TRUE_PEAK_TAPS_PER_PHASE]> {
+    let len = TRUE_PEAK_OVERSAMPLING * TRUE_PEAK_TAPS_PER_PHASE;
+    let center = (len - 1) as f64 / 2.0;
+    let mut phases = vec![[0.0; TRUE_PEAK_TAPS_PER_PHASE]; TRUE_PEAK_OVERSAMPLING];
+    for n in 0..len {
+        let x = (n as f64 - center) / TRUE_PEAK_OVERSAMPLING as f64;
+        let sinc = if x == 0.0 {
+            1.0
+        } else {
+            (PI * x).sin() / (PI * x)
+        };
+        let window = 0.5 - 0.5 * (2.0 * PI * (n as f64 + 0.5) / len as f64).cos();
+        phases[n % TRUE_PEAK_OVERSAMPLING][n / TRUE_PEAK_OVERSAMPLING] = sinc * window;
+    }
+    phases
+}
+
+struct ChannelState {
+    filters: [BiquadState; 2],
+    history: [f64; TRUE_PEAK_TAPS_PER_PHASE],
+    history_pos: usize,
+    /// How much the channel counts towards the loudness.
+    weight: f64,
+}
+
+/// Weight of a channel in the loudness sum according to ITU-R BS.1770. Surround channels count
+/// for more and LFE channels are left out. Streams without a known layout are weighted equally.
+fn channel_weight(channel: Channels) -> f64 {
+    if channel.intersects(Channels::LFE1 | Channels::LFE2) {
+        0.0
+    } else if channel.intersects(
+        Channels::SIDE_LEFT | Channels::SIDE_RIGHT | Channels::REAR_LEFT | Channels::REAR_RIGHT,
+    ) {
+        SURROUND_CHANNEL_WEIGHT
+    } else {
+        1.0
+    }
+}
+
+/// Measures loudness according to ITU-R BS.1770 and EBU R128, with the channels weighted by their
+/// position in `layout`.
+pub struct LoudnessMeter {
+    k_weighting: [BiquadCoefficients; 2],
+    true_peak_filter: Vec<[f64; TRUE_PEAK_TAPS_PER_PHASE]>,
+    channels: Vec<ChannelState>,
+    sub_block_frames: usize,
+    sub_block_pos: usize,
+    sub_block_energy: f64,
+    sub_blocks: VecDeque<f64>,
+    blocks: LoudnessBlocks,
+}
+
+impl LoudnessMeter {
+    pub fn new(sample_rate: u32, layout: Channels) -> LoudnessMeter {
+        LoudnessMeter {
+            k_weighting: k_weighting(sample_rate),
+            true_peak_filter: true_peak_filter(),
+            channels: layout
+                .iter()
+                .map(|channel| ChannelState {
+                    filters: Default::default(),
+                    history: [0.0; TRUE_PEAK_TAPS_PER_PHASE],
+                    history_pos: 0,
+                    weight: channel_weight(channel),
+                })
+                .collect(),
+            sub_block_frames: ((sample_rate as f64 * SUB_BLOCK_SECONDS).round() as usize).max(1),
+            sub_block_pos: 0,
+            sub_block_energy: 0.0,
+            sub_blocks: VecDeque::with_capacity(SHORT_TERM_SUB_BLOCKS),
+            blocks: LoudnessBlocks::default(),
+        }
+    }
+
+    pub fn process(&mut self, read_data: &ReadData) {
+        let num_channels = read_data.num_channels().min(self.channels.len());
+        for i in 0..read_data.num_frames() {
+            for c in 0..num_channels {
+                let x = read_data.read_channel(c)[i] as f64;
+                let channel = &mut self.channels[c];
+
+                channel.history[channel.history_pos] = x;
+                for phase in self.true_peak_filter.iter() {
+                    let mut y = 0.0;
+                    for (k, tap) in phase.iter().enumerate() {
+                        let pos = (channel.history_pos + TRUE_PEAK_TAPS_PER_PHASE - k)
+                            % TRUE_PEAK_TAPS_PER_PHASE;
+                        y += tap * channel.history[pos];
+                    }
+                    self.blocks.true_peak = self.blocks.true_peak.max(y.abs());
+                }
+                self.blocks.true_peak = self.blocks.true_peak.max(x.abs());
+                channel.history_pos = (channel.history_pos + 1) % TRUE_PEAK_TAPS_PER_PHASE;
+
+                let [pre_filter, rlb_filter] = &mut channel.filters;
+                let y = rlb_filter.process(
+                    &self.k_weighting[1],
+                    pre_filter.process(&self.k_weighting[0], x),
+                );
+                self.sub_block_energy += channel.weight * y * y;
+            }
+
+            self.sub_block_pos += 1;
+            if self.sub_block_pos == self.sub_block_frames {
+                self.end_sub_block();
+            }
+        }
+    }
+
+    fn end_sub_block(&mut self) {
+        if self.sub_blocks.len() == SHORT_TERM_SUB_BLOCKS {
+            self.sub_blocks.pop_front();
+        }
+        self.sub_blocks
+            .push_back(self.sub_block_energy / self.sub_block_frames as f64);
+        self.sub_block_pos = 0;
+        self.sub_block_energy = 0.0;
+
+        let n = self.sub_blocks.len();
+        if n >= MOMENTARY_SUB_BLOCKS {
+            let energy = self
+                .sub_blocks
+                .range(n - MOMENTARY_SUB_BLOCKS..)
+                .sum::<f64>()
+                / MOMENTARY_SUB_BLOCKS as f64;
+            self.blocks.momentary.push(energy);
+        }
+        if n == SHORT_TERM_SUB_BLOCKS {
+            let energy = self.sub_blocks.iter().sum::<f64>() / SHORT_TERM_SUB_BLOCKS as f64;
+            self.blocks.short_term.push(energy);
+        }
+    }
+
+    /// Returns the blocks measured so far. A trailing partial sub-block is not included.
+    pub fn finish(self) -> LoudnessBlocks {
+        self.blocks
+    }
+}
@@ -0,0 +1,336 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    file_stream::FileStream,
+    formats::is_supported_path,
+    loudness::{LoudnessBlocks, LoudnessMeasurement, LoudnessMeter},
+    replay_gain::{ReplayGainTags, REFERENCE_LOUDNESS_LUFS},
+    resampler::ResamplerKind,
+    store::JsonStore,
+    PlayerEvent,
+};
+
+const CACHE_FILE_NAME: &str = "loudness-cache.json";
+const READ_FRAMES: usize = 4096;
+/// Give up on a file if the decoder doesn't produce any audio for this long.
+const STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct LoudnessScanProgress {
+    pub scanned: usize,
+    pub total: usize,
+    /// The file that is being scanned, or `None` once the scan is complete.
+    pub path: Option<String>,
+}
+
+/// Identifies a version of a file by its path and modification time.
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct FileSignature {
+    pub path: String,
+    pub modified: u64,
+}
+
+impl FileSignature {
+    pub fn read(path: &str) -> Option<FileSignature> {
+        let modified = fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()?;
+        Some(FileSignature {
+            path: path.to_owned(),
+            modified: modified.duration_since(UNIX_EPOCH).ok()?.as_millis() as u64,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedTrackLoudness {
+    modified: u64,
+    loudness: LoudnessMeasurement,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedAlbumLoudness {
+    /// Sorted signatures of the files that were measured as part of the album.
+    tracks: Vec<FileSignature>,
+    loudness: LoudnessMeasurement,
+}
+
+/// Loudness measurements of tracks, and of albums where an album is a directory.
+#[derive(Default, Serialize, Deserialize)]
+pub struct LoudnessCache {
+    tracks: HashMap<String, CachedTrackLoudness>,
+    albums: HashMap<String, CachedAlbumLoudness>,
+}
+
+pub type SharedLoudnessCache = Arc<Mutex<LoudnessCache>>;
+
+fn album_key(path: &str) -> Option<String> {
+    Path::new(path)
+        .parent()
+        .and_then(|parent| parent.to_str())
+        .map(|parent| parent.to_owned())
+}
+
+/// The files of an album, which are all the supported files in its directory along with the given
+/// paths, so that its measurement doesn't depend on which of them were asked for.
+fn album_paths(key: &str, paths: Vec<String>) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(key) else {
+        return paths;
+    };
+    let mut album_paths: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && is_supported_path(path))
+        .filter_map(|path| path.to_str().map(|path| path.to_owned()))
+        .collect();
+    for path in paths {
+        if !album_paths.contains(&path) {
+            album_paths.push(path);
+        }
+    }
+    album_paths.sort();
+    album_paths
+}
+
+fn gain_for_loudness(loudness: &LoudnessMeasurement) -> Option<f32> {
+    loudness
+        .integrated
+        .map(|integrated| REFERENCE_LOUDNESS_LUFS - integrated as f32)
+}
+
+impl LoudnessCache {
+    /// Gains derived from the measurements of a file, provided it hasn't changed since it was
+    /// scanned.
+    pub fn replay_gain_tags(&self, path: &str) -> ReplayGainTags {
+        let Some(signature) = FileSignature::read(path) else {
+            return ReplayGainTags::default();
+        };
+        let track = self
+            .tracks
+            .get(path)
+            .filter(|track| track.modified == signature.modified)
+            .map(|track| &track.loudness);
+        let album = album_key(path)
+            .and_then(|key| self.albums.get(&key))
+            .filter(|album| album.tracks.binary_search(&signature).is_ok())
+            .map(|album| &album.loudness);
+        ReplayGainTags {
+            track_gain: track.and_then(gain_for_loudness),
+            track_peak: track.map(|loudness| loudness.true_peak as f32),
+            album_gain: album.and_then(gain_for_loudness),
+            album_peak: album.map(|loudness| loudness.true_peak as f32),
+        }
+    }
+
+    fn is_album_current(&self, key: &str, tracks: &[FileSignature]) -> bool {
+        self.albums
+            .get(key)
+            .map_or(false, |album| album.tracks == tracks)
+    }
+}
+
+/// Measures the loudness of files on a background thread, decoding them through `FileStream`
+/// without any real-time output.
+pub struct LoudnessScanner {
+    job_tx: mpsc::Sender<Vec<String>>,
+    cache: SharedLoudnessCache,
+}
+
+impl LoudnessScanner {
+    pub fn new(data_dir: &Path, event_tx: tokio::sync::mpsc::Sender<PlayerEvent>) -> Self {
+        let store = JsonStore::new(data_dir.join(CACHE_FILE_NAME));
+        let cache: SharedLoudnessCache = Arc::new(Mutex::new(store.load().unwrap_or_default()));
+        let (job_tx, job_rx) = mpsc::channel();
+        thread::spawn({
+            let cache = cache.clone();
+            move || {
+                while let Ok(paths) = job_rx.recv() {
+                    scan(paths, &cache, &store, &event_tx);
+                }
+            }
+        });
+        LoudnessScanner { job_tx, cache }
+    }
+
+    pub fn cache(&self) -> SharedLoudnessCache {
+        self.cache.clone()
+    }
+
+    pub fn scan(&self, paths: Vec<String>) {
+        self.job_tx.send(paths).unwrap_or_else(|_| {
+            warn!("Failed to send scan job to the loudness scanner");
+        });
+    }
+}
+
+fn send_progress(
+    event_tx: &tokio::sync::mpsc::Sender<PlayerEvent>,
+    scanned: usize,
+    total: usize,
+    path: Option<String>,
+) {
+    let event = PlayerEvent::LoudnessScanProgress(LoudnessScanProgress {
+        scanned,
+        total,
+        path,
+    });
+    if let Err(e) = event_tx.blocking_send(event) {
+        warn!("Failed to send loudness scan progress with {e:?}");
+    }
+}
+
+fn scan(
+    paths: Vec<String>,
+    cache: &SharedLoudnessCache,
+    store: &JsonStore,
+    event_tx: &tokio::sync::mpsc::Sender<PlayerEvent>,
+) {
+    let mut albums: Vec<(String, Vec<String>)> = Vec::new();
+    for path in paths {
+        let key = album_key(&path).unwrap_or_default();
+        match albums.iter_mut().find(|(album, _)| *album == key) {
+            Some((_, album_paths)) => album_paths.push(path),
+            None => albums.push((key, vec![path])),
+        }
+    }
+    // An album is measured as a whole even if only some of its files were asked for
+    let albums: Vec<(String, Vec<String>)> = albums
+        .into_iter()
+        .map(|(key, paths)| {
+            let paths = album_paths(&key, paths);
+            (key, paths)
+        })
+        .collect();
+    let total = albums.iter().map(|(_, paths)| paths.len()).sum();
+
+    let mut scanned = 0;
+    for (key, album_paths) in albums {
+        let mut signatures: Vec<FileSignature> = album_paths
+            .iter()
+            .filter_map(|path| FileSignature::read(path))
+            .collect();
+        signatures.sort();
+
+        if cache.lock().unwrap().is_album_current(&key, &signatures) {
+            scanned += album_paths.len();
+            continue;
+        }
+
+        // Files that don't exist anymore still count towards progress
+        scanned += album_paths.len() - signatures.len();
+
+        let mut album_blocks = LoudnessBlocks::default();
+        let mut album_tracks = Vec::with_capacity(signatures.len());
+        for signature in signatures {
+            send_progress(event_tx, scanned, total, Some(signature.path.clone()));
+            scanned += 1;
+
+            let Some(blocks) = scan_file(&signature.path) else {
+                continue;
+            };
+            album_blocks.extend(&blocks);
+            cache.lock().unwrap().tracks.insert(
+                signature.path.clone(),
+                CachedTrackLoudness {
+                    modified: signature.modified,
+                    loudness: blocks.measure(),
+                },
+            );
+            album_tracks.push(signature);
+        }
+        if !album_tracks.is_empty() {
+            let mut cache = cache.lock().unwrap();
+            cache.albums.insert(
+                key,
+                CachedAlbumLoudness {
+                    tracks: album_tracks,
+                    loudness: album_blocks.measure(),
+                },
+            );
+            store.save(&*cache);
+        }
+    }
+
+    info!("Finished loudness scan of {total} files");
+    send_progress(event_tx, total, total, None);
+}
+
+fn scan_file(path: &str) -> Option<LoudnessBlocks> {
+    let mut file_stream = match FileStream::open(path, |_| None, ResamplerKind::default(), None) {
+        Ok(file_stream) => file_stream,
+        Err(e) => {
+            warn!("Failed to open {path:?} for loudness scan: {e:?}");
+            return None;
+        }
+    };
+    let mut meter = LoudnessMeter::new(file_stream.sample_rate(), file_stream.channel_layout());
+    let mut last_read = Instant::now();
+    loop {
+        if !file_stream.is_ready() {
+            if file_stream.error().is_some() {
+                break;
+            }
+            if last_read.elapsed() > STALL_TIMEOUT {
+                warn!("Decoding {path:?} stalled during loudness scan");
+                return None;
+            }
+            thread::sleep(Duration::from_millis(1));
+            continue;
+        }
+        last_read = Instant::now();
+
+        let read_data = file_stream
+            .read(READ_FRAMES)
+            .expect("Expected there to be available data to read");
+        meter.process(&read_data);
+        if read_data.reached_end_of_file() {
+            break;
+        }
+    }
+    if let Some(kind) = file_stream.error() {
+        warn!("Decoding {path:?} failed during loudness scan: {kind:?}");
+        return None;
+    }
+    Some(meter.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn album_includes_every_supported_file_in_its_directory() {
+        let dir: std::path::PathBuf = [env!("CARGO_MANIFEST_DIR"), "tests", "fixtures"]
+            .iter()
+            .collect();
+        let path = dir.join("silence.mp3").to_str().unwrap().to_owned();
+        let key = album_key(&path).unwrap();
+        let paths = album_paths(&key, vec![path.clone()]);
+        assert!(paths.contains(&path));
+        assert!(paths.iter().any(|path| path.ends_with("truncated.mp3")));
+        assert!(!paths.iter().any(|path| path.ends_with("generate.py")));
+        assert_eq!(
+            paths
+                .iter()
+                .filter(|album_path| **album_path == path)
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn album_without_a_directory_is_the_given_paths() {
+        let paths = vec!["missing/a.mp3".to_owned()];
+        assert_eq!(album_paths("missing", paths.clone()), paths);
+    }
+}
@@ -1,24 +1,53 @@
-use std::{path::Path, sync::mpsc, thread, time::Duration};
+use std::{
+    path::Path,
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
-use base64::{engine::general_purpose, Engine};
 use log::{error, info, warn};
 
 use rtrb::RingBuffer;
 use serde::{Deserialize, Serialize};
-use symphonia::core::{
-    meta::{StandardTagKey, StandardVisualKey, Value},
-    units::{Time, TimeBase},
-};
+use symphonia::core::units::{Time, TimeBase};
 
 use crate::player::{file_stream::FileStream, queue::Queue, PlaybackFile, StreamMetadata};
 
 use super::{
-    errors::FileStreamOpenError, output::Output, queue::GoNextMode, ManagerToProcessMsg,
-    PlaybackState, PlayerEvent, ProcessToManagerMsg, StartPlaybackState, StreamMetadataVisual,
-    StreamTiming,
+    analysis::{forward_analysis, AnalysisFrame, ANALYSIS_RING_CAPACITY},
+    dsp::{DspNode, DspNodeId, DspNodeInfo, DspNodeParams, MAX_DSP_NODES},
+    equalizer::{EqBand, Equalizer, EqualizerPreset, EqualizerSettings},
+    errors::{
+        FileStreamOpenError, OutputError, PlayerError, PlayerErrorKind, StreamErrorAction,
+        StreamErrorPolicy, MAX_STREAM_RETRIES,
+    },
+    loudness_scanner::SharedLoudnessCache,
+    output::{Output, OutputDeviceSelection, OutputRateMode, OutputStatus, PREFERRED_SAMPLE_RATE},
+    queue::GoNextMode,
+    replay_gain::{ReplayGainMode, ReplayGainTags},
+    resampler::ResamplerKind,
+    resume::{ResumePosition, ResumePositions, ResumeSettings},
+    session::{Session, SESSION_FILE_NAME},
+    settings::{PlayerSettings, SETTINGS_FILE_NAME},
+    sleep_timer::{self, SleepTimer, SleepTimerMode, SleepTimerStatus},
+    store::JsonStore,
+    time_stretch::{PlaybackRate, SharedPlaybackRate},
+    LoopRegion, ManagerToProcessMsg, PlaybackState, PlayerEvent, PlayerSnapshot,
+    ProcessToManagerMsg, QueueState, StartPlaybackState, StreamFormat, StreamTiming,
 };
 
 const STREAM_SEEK_BACK_THRESHOLD_SECONDS_PART: u8 = 3;
+const MAX_CROSSFADE_SECONDS: f64 = 12.0;
+const MAX_REPLAY_GAIN_PREAMP_DB: f32 = 15.0;
+const DEFAULT_FADE_DURATION_MS: u32 = 20;
+const MAX_FADE_DURATION_MS: u32 = 500;
+/// How often to try opening the output again while there isn't one.
+const OUTPUT_RETRY_INTERVAL: Duration = Duration::from_secs(2);
+/// How often the session is saved if it changed.
+const SESSION_SAVE_INTERVAL: Duration = Duration::from_secs(2);
 
 pub enum ManagerCommand {
     StartPlayback(Vec<String>, usize),
@@ -26,6 +55,10 @@ pub enum ManagerCommand {
     Stop,
     Progress(u64, usize),
     PlaybackEnded(u64),
+    PlaybackAdvanced(u64, u64),
+    /// Decoding or processing of the stream with the given playback id failed, and the audio
+    /// thread dropped it.
+    StreamError(u64, PlayerErrorKind),
     Resume,
     SetVolume(f64),
     SeekTo(usize),
@@ -35,6 +68,45 @@ pub enum ManagerCommand {
     SkipBack,
     SetShuffle(ShuffleMode),
     SetRepeat(RepeatMode),
+    SetCrossfade(f64),
+    SetFadeDuration(u32),
+    SetReplayGainMode(ReplayGainMode),
+    SetReplayGainPreamp(f32),
+    GetEqualizer(mpsc::Sender<EqualizerSettings>),
+    SetEqualizerEnabled(bool),
+    SetEqualizerBands(Vec<EqBand>),
+    SetEqualizerPreset(EqualizerPreset),
+    GetDspNodes(mpsc::Sender<Vec<DspNodeInfo>>),
+    MoveDspNode(DspNodeId, usize),
+    GetOutputDevice(mpsc::Sender<OutputDeviceSelection>),
+    SetOutputDevice(OutputDeviceSelection),
+    GetOutputRateMode(mpsc::Sender<OutputRateMode>),
+    SetOutputRateMode(OutputRateMode),
+    GetSettings(mpsc::Sender<PlayerSettings>),
+    SetMultichannelOutput(bool),
+    SetDownmixLfe(bool),
+    SetResampler(ResamplerKind),
+    SetStreamErrorPolicy(StreamErrorPolicy),
+    SetPlaybackRate(PlaybackRate),
+    SetLoopStart(Option<usize>),
+    SetLoopEnd(Option<usize>),
+    ClearLoop,
+    QueuePlayNext(Vec<String>),
+    QueueAppend(Vec<String>),
+    QueueRemove(usize),
+    QueueMove(usize, usize),
+    QueueClearUpcoming,
+    QueueJumpTo(usize),
+    SetResumeSettings(ResumeSettings),
+    GetResumePositions(mpsc::Sender<Vec<ResumePosition>>),
+    GetSnapshot(mpsc::Sender<PlayerSnapshot>),
+    SaveSession(mpsc::Sender<()>),
+    /// Forgets the saved position of a file, or of all files.
+    ClearResumePositions(Option<String>),
+    SetSleepTimer(Option<SleepTimerMode>),
+    SetStopAfterCurrent(bool),
+    /// The stream of the output with the given generation failed.
+    OutputError(u64, String),
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -55,6 +127,10 @@ impl StreamTimingInternal {
     }
 }
 
+fn time_seconds(time: Time) -> f64 {
+    time.seconds as f64 + time.frac
+}
+
 fn gain_for_volume(volume: f64) -> f32 {
     let clamped = volume.max(0_f64).min(100_f64);
     let normalized = clamped / 100.0;
@@ -62,32 +138,145 @@ fn gain_for_volume(volume: f64) -> f32 {
     (amp as f32).min(1.0)
 }
 
-#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+fn playback_file(path: &str) -> PlaybackFile {
+    let os_path = Path::new(path);
+    let file_name = os_path.file_name().unwrap().to_str().unwrap().to_owned();
+    PlaybackFile {
+        path: path.to_owned(),
+        name: file_name,
+    }
+}
+
+/// Timing and metadata captured from a file stream before it is handed off to the audio thread.
+struct StreamInfo {
+    timing: Option<StreamTimingInternal>,
+    format: StreamFormat,
+    metadata: StreamMetadata,
+    replay_gain: ReplayGainTags,
+}
+
+impl StreamInfo {
+    /// Captures the info of a file stream. Loudness measurements from `loudness_cache` are used
+    /// when the file doesn't have any gain tags.
+    fn from_file_stream(
+        path: &str,
+        file_stream: &FileStream,
+        loudness_cache: &SharedLoudnessCache,
+    ) -> StreamInfo {
+        let timing = file_stream.n_frames().and_then(|n_frames| {
+            file_stream
+                .time_base()
+                .map(|time_base| StreamTimingInternal {
+                    time_base: *time_base,
+                    n_frames,
+                    pos: 0,
+                })
+        });
+        let tags = file_stream
+            .metadata()
+            .map(ReplayGainTags::from_metadata)
+            .unwrap_or_default();
+        let replay_gain = if tags.is_empty() {
+            loudness_cache.lock().unwrap().replay_gain_tags(path)
+        } else {
+            tags
+        };
+        StreamInfo {
+            timing,
+            format: StreamFormat {
+                source_sample_rate: file_stream.source_sample_rate(),
+                output_sample_rate: file_stream.sample_rate(),
+                resampling: file_stream.source_sample_rate() != file_stream.sample_rate(),
+                channels: file_stream.num_channels(),
+            },
+            metadata: StreamMetadata::new(file_stream.metadata(), file_stream.codec_info().clone()),
+            replay_gain,
+        }
+    }
+}
+
+/// The next item in the queue, opened ahead of time so the audio thread can continue with it
+/// without a gap once the current stream ends.
+struct PreloadedPlayback {
+    playback_id: u64,
+    path: String,
+    /// Set once the stream is open and has been sent to the audio thread.
+    info: Option<StreamInfo>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
 pub enum ShuffleMode {
+    #[default]
     NotEnabled,
     Enabled,
 }
 
-#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
 pub enum RepeatMode {
+    #[default]
     None,
     RepeatAll,
     RepeatOne,
 }
 
 pub struct PlaybackManager {
-    output: Output,
+    /// The output, or `None` if it couldn't be opened and the player is waiting for a device.
+    output: Option<Output>,
+    /// Incremented whenever the output is reopened, to tell errors of old outputs apart.
+    output_generation: u64,
+    output_error: Option<String>,
+    /// Sample rate to open the output with, which follows the streams in the native rate mode.
+    requested_sample_rate: u32,
+    /// Native rates that the output failed to open at, which streams are resampled from instead.
+    failed_sample_rates: Vec<u32>,
     to_process_tx: rtrb::Producer<ManagerToProcessMsg>,
+    settings: PlayerSettings,
+    settings_store: JsonStore,
     command_rx: mpsc::Receiver<ManagerCommand>,
     command_tx: mpsc::Sender<ManagerCommand>,
     queue: Option<Queue<String>>,
     event_tx: tokio::sync::mpsc::Sender<PlayerEvent>,
     current_playback_id: Option<u64>,
+    /// Position to start the current playback from once its stream has been opened.
+    start_pos: Option<(u64, usize)>,
     next_playback_id: u64,
+    preloaded: Option<PreloadedPlayback>,
+    /// The file that is being retried after its stream failed, with the number of retries so far.
+    stream_retries: Option<(String, u32)>,
     playback_state: PlaybackState,
     stream_timing: Option<StreamTimingInternal>,
     shuffle_mode: ShuffleMode,
     repeat_mode: RepeatMode,
+    gain: f32,
+    /// The volume that `gain` was derived from, once it has been set.
+    volume: Option<f64>,
+    fade_duration_ms: u32,
+    crossfade_seconds: f64,
+    replay_gain_mode: ReplayGainMode,
+    replay_gain_preamp_db: f32,
+    current_replay_gain: ReplayGainTags,
+    loudness_cache: SharedLoudnessCache,
+    equalizer: EqualizerSettings,
+    equalizer_node: Option<DspNodeId>,
+    /// Mirrors the order of the DSP chain on the audio thread.
+    dsp_nodes: Vec<DspNodeInfo>,
+    next_dsp_node_id: DspNodeId,
+    /// Read by the decode workers of the open streams.
+    playback_rate: Arc<SharedPlaybackRate>,
+    /// Loop points of the current track.
+    loop_region: LoopRegion,
+    sleep_timer: Option<SleepTimer>,
+    /// The status that was last sent to the UI.
+    sleep_timer_status: Option<SleepTimerStatus>,
+    /// Gain factor of the fade out before the sleep timer stops playback.
+    sleep_fade: f32,
+    /// Stops playback once the current track ends, instead of going to the next one.
+    stop_after_current: bool,
+    session_store: JsonStore,
+    /// The session as it was last saved.
+    saved_session: Option<Session>,
+    session_save_at: Instant,
+    resume_positions: ResumePositions,
 }
 
 fn poll_process_to_gui_message(
@@ -108,14 +297,40 @@ fn poll_process_to_gui_message(
                         break;
                     }
                 }
+                ProcessToManagerMsg::PlaybackAdvanced(previous_playback_id, playback_id) => {
+                    failed_to_send = command_tx
+                        .send(ManagerCommand::PlaybackAdvanced(
+                            previous_playback_id,
+                            playback_id,
+                        ))
+                        .is_err();
+                    if failed_to_send {
+                        break;
+                    }
+                }
+                ProcessToManagerMsg::StreamError(playback_id, kind) => {
+                    failed_to_send = command_tx
+                        .send(ManagerCommand::StreamError(playback_id, kind))
+                        .is_err();
+                    if failed_to_send {
+                        break;
+                    }
+                }
                 ProcessToManagerMsg::PlaybackPos(playback_id, pos) => {
                     debounced_progress_message = Some(ManagerCommand::Progress(playback_id, pos));
                 }
+                ProcessToManagerMsg::DspNodeRemoved(node) => {
+                    drop(node);
+                }
             };
         }
         if let Some(message) = debounced_progress_message {
             failed_to_send = command_tx.send(message).is_err();
         }
+        if from_process_rx.is_abandoned() && from_process_rx.is_empty() {
+            // The output was closed
+            break;
+        }
 
         if !failed_to_send {
             thread::sleep(Duration::from_millis(1));
@@ -123,41 +338,165 @@ fn poll_process_to_gui_message(
     }
 }
 
+/// Opens an output with a new audio thread state, along with a thread that forwards messages from
+/// the audio thread to the manager until the output is closed. Messages can still be sent if the
+/// output failed to open, they just won't go anywhere.
+fn open_output(
+    settings: &PlayerSettings,
+    sample_rate: u32,
+    generation: u64,
+    command_tx: &mpsc::Sender<ManagerCommand>,
+    event_tx: &tokio::sync::mpsc::Sender<PlayerEvent>,
+) -> (
+    Result<Output, OutputError>,
+    rtrb::Producer<ManagerToProcessMsg>,
+) {
+    let (to_manager_tx, from_process_rx) = RingBuffer::<ProcessToManagerMsg>::new(256);
+    let (to_process_tx, from_manager_rx) = RingBuffer::<ManagerToProcessMsg>::new(64);
+    let (to_analysis_tx, from_process_analysis_rx) =
+        RingBuffer::<AnalysisFrame>::new(ANALYSIS_RING_CAPACITY);
+    let output = Output::new(
+        &settings.output_device,
+        sample_rate,
+        settings.multichannel_output,
+        to_manager_tx,
+        from_manager_rx,
+        to_analysis_tx,
+        {
+            let tx = command_tx.clone();
+            move |e| match e {
+                cpal::StreamError::DeviceNotAvailable => {
+                    let _ = tx.send(ManagerCommand::OutputError(generation, e.to_string()));
+                }
+                // Backend errors such as xruns don't stop the stream, so playback carries on
+                cpal::StreamError::BackendSpecific { err } => {
+                    warn!("Output stream error: {err}");
+                }
+            }
+        },
+    );
+
+    thread::spawn({
+        let tx = command_tx.clone();
+        move || {
+            poll_process_to_gui_message(tx, from_process_rx);
+        }
+    });
+    thread::spawn({
+        let event_tx = event_tx.clone();
+        move || forward_analysis(from_process_analysis_rx, event_tx)
+    });
+
+    (output, to_process_tx)
+}
+
 impl PlaybackManager {
     pub fn new(
         event_tx: tokio::sync::mpsc::Sender<PlayerEvent>,
         command_tx: mpsc::Sender<ManagerCommand>,
         command_rx: mpsc::Receiver<ManagerCommand>,
+        loudness_cache: SharedLoudnessCache,
+        data_dir: &Path,
     ) -> PlaybackManager {
-        let (to_manager_tx, from_process_rx) = RingBuffer::<ProcessToManagerMsg>::new(256);
-        let (to_process_tx, from_manager_rx) = RingBuffer::<ManagerToProcessMsg>::new(64);
-        let output = Output::new(to_manager_tx, from_manager_rx);
-
-        thread::spawn({
-            let tx = command_tx.clone();
-            move || {
-                poll_process_to_gui_message(tx, from_process_rx);
-            }
-        });
+        let settings_store = JsonStore::new(data_dir.join(SETTINGS_FILE_NAME));
+        let settings: PlayerSettings = settings_store.load().unwrap_or_default();
+        let playback_rate = Arc::new(SharedPlaybackRate::new(settings.playback_rate.clamped()));
+        let (output, to_process_tx) =
+            open_output(&settings, PREFERRED_SAMPLE_RATE, 0, &command_tx, &event_tx);
 
-        PlaybackManager {
-            output,
+        let mut manager = PlaybackManager {
+            output: None,
+            output_generation: 0,
+            output_error: None,
+            requested_sample_rate: PREFERRED_SAMPLE_RATE,
+            failed_sample_rates: Vec::new(),
             to_process_tx,
+            settings,
+            settings_store,
             command_rx,
             command_tx,
             queue: None,
             event_tx,
             current_playback_id: None,
+            start_pos: None,
             next_playback_id: 0,
+            preloaded: None,
+            stream_retries: None,
             playback_state: PlaybackState::Stopped,
             stream_timing: None,
             shuffle_mode: ShuffleMode::NotEnabled,
             repeat_mode: RepeatMode::None,
+            gain: 0.0,
+            volume: None,
+            fade_duration_ms: DEFAULT_FADE_DURATION_MS,
+            crossfade_seconds: 0.0,
+            replay_gain_mode: ReplayGainMode::Off,
+            replay_gain_preamp_db: 0.0,
+            current_replay_gain: ReplayGainTags::default(),
+            loudness_cache,
+            equalizer: EqualizerSettings::default(),
+            equalizer_node: None,
+            dsp_nodes: Vec::new(),
+            next_dsp_node_id: 0,
+            playback_rate,
+            loop_region: LoopRegion::default(),
+            sleep_timer: None,
+            sleep_timer_status: None,
+            sleep_fade: 1.0,
+            stop_after_current: false,
+            session_store: JsonStore::new(data_dir.join(SESSION_FILE_NAME)),
+            saved_session: None,
+            session_save_at: Instant::now() + SESSION_SAVE_INTERVAL,
+            resume_positions: ResumePositions::load(data_dir),
+        };
+        manager.set_output(output);
+        manager.configure_process();
+        manager.restore_session();
+        manager
+    }
+
+    /// Waits for the next command, trying to open the output again in the meantime if there isn't
+    /// one, keeping the sleep timer up to date and saving the session.
+    fn next_command(&mut self) -> Option<ManagerCommand> {
+        let mut output_retry_at = Instant::now() + OUTPUT_RETRY_INTERVAL;
+        loop {
+            if self
+                .sleep_timer
+                .as_ref()
+                .map_or(false, |timer| timer.is_due())
+            {
+                self.update_sleep_timer();
+            }
+            if Instant::now() >= self.session_save_at {
+                self.save_session();
+                self.resume_positions.save_if_changed();
+            }
+
+            let mut deadline = self.session_save_at;
+            if let Some(timer) = self.sleep_timer.as_ref() {
+                deadline = deadline.min(timer.next_tick);
+            }
+            if self.output.is_none() {
+                deadline = deadline.min(output_retry_at);
+            }
+            match self
+                .command_rx
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            {
+                Ok(msg) => return Some(msg),
+                Err(RecvTimeoutError::Timeout) => {
+                    if self.output.is_none() && Instant::now() >= output_retry_at {
+                        self.reopen_output();
+                        output_retry_at = Instant::now() + OUTPUT_RETRY_INTERVAL;
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => return None,
+            }
         }
     }
 
     pub fn run(mut self) {
-        while let Ok(msg) = self.command_rx.recv() {
+        while let Some(msg) = self.next_command() {
             match msg {
                 ManagerCommand::StartPlayback(file_paths, start_index) => {
                     self.start_playback_impl(file_paths, start_index);
@@ -187,13 +526,16 @@ impl PlaybackManager {
                 ManagerCommand::PlaybackEnded(playback_id) => {
                     self.playback_ended_impl(playback_id);
                 }
+                ManagerCommand::PlaybackAdvanced(previous_playback_id, playback_id) => {
+                    self.playback_advanced_impl(previous_playback_id, playback_id);
+                }
+                ManagerCommand::StreamError(playback_id, kind) => {
+                    self.stream_error_impl(playback_id, kind);
+                }
                 ManagerCommand::SetVolume(volume) => {
-                    let gain = gain_for_volume(volume);
-                    self.to_process_tx
-                        .push(ManagerToProcessMsg::SetGain(gain))
-                        .unwrap_or_else(|_| {
-                            error!("Failed to send gain message to audio thread");
-                        })
+                    self.volume = Some(volume);
+                    self.gain = gain_for_volume(volume);
+                    self.send_gain();
                 }
                 ManagerCommand::SeekTo(offset) => {
                     self.seek_to_impl(offset);
@@ -202,6 +544,12 @@ impl PlaybackManager {
                     self.open_file_stream_impl(playback_id, path, file_stream);
                 }
                 ManagerCommand::OpenFileStreamError(playback_id, path, e) => {
+                    if self.is_preloaded(playback_id) {
+                        // The error will come up again when the queue advances to this item
+                        warn!("Failed to preload file stream for {path:?}: {e:?}");
+                        self.preloaded = None;
+                        continue;
+                    }
                     if Some(playback_id) != self.current_playback_id {
                         info!(
                             "Ignoring open stream error for {:?} as it is no longer the current playback",
@@ -210,8 +558,12 @@ impl PlaybackManager {
                         continue;
                     }
 
-                    // TODO: Surface errors to the UI
                     error!("Failed to open file stream for {path:?}: {e:?}");
+                    self.send_error(PlayerError {
+                        kind: e.kind(),
+                        path: Some(path),
+                        message: e.to_string(),
+                    });
 
                     self.play_next();
                 }
@@ -227,6 +579,141 @@ impl PlaybackManager {
                 ManagerCommand::SetRepeat(repeat_mode) => {
                     self.set_repeat_impl(repeat_mode);
                 }
+                ManagerCommand::SetCrossfade(crossfade_seconds) => {
+                    self.set_crossfade_impl(crossfade_seconds);
+                }
+                ManagerCommand::SetFadeDuration(fade_duration_ms) => {
+                    self.set_fade_duration_impl(fade_duration_ms);
+                }
+                ManagerCommand::SetReplayGainMode(replay_gain_mode) => {
+                    self.replay_gain_mode = replay_gain_mode;
+                    self.apply_replay_gain();
+                }
+                ManagerCommand::GetEqualizer(reply_tx) => {
+                    let _ = reply_tx.send(self.equalizer.clone());
+                }
+                ManagerCommand::SetEqualizerEnabled(enabled) => {
+                    self.equalizer.enabled = enabled;
+                    self.apply_equalizer();
+                }
+                ManagerCommand::SetEqualizerBands(bands) => {
+                    self.equalizer.bands = bands;
+                    self.equalizer.preset = None;
+                    self.apply_equalizer();
+                }
+                ManagerCommand::SetEqualizerPreset(preset) => {
+                    self.equalizer.bands = preset.bands();
+                    self.equalizer.preset = Some(preset);
+                    self.apply_equalizer();
+                }
+                ManagerCommand::GetDspNodes(reply_tx) => {
+                    let _ = reply_tx.send(self.dsp_nodes.clone());
+                }
+                ManagerCommand::MoveDspNode(id, index) => {
+                    self.move_dsp_node(id, index);
+                }
+                ManagerCommand::GetOutputDevice(reply_tx) => {
+                    let _ = reply_tx.send(self.settings.output_device.clone());
+                }
+                ManagerCommand::SetOutputDevice(selection) => {
+                    self.set_output_device_impl(selection);
+                }
+                ManagerCommand::GetOutputRateMode(reply_tx) => {
+                    let _ = reply_tx.send(self.settings.output_rate_mode);
+                }
+                ManagerCommand::SetOutputRateMode(output_rate_mode) => {
+                    self.set_output_rate_mode_impl(output_rate_mode);
+                }
+                ManagerCommand::GetSettings(reply_tx) => {
+                    let _ = reply_tx.send(self.settings.clone());
+                }
+                ManagerCommand::SetMultichannelOutput(enabled) => {
+                    self.set_multichannel_output_impl(enabled);
+                }
+                ManagerCommand::SetDownmixLfe(enabled) => {
+                    self.set_downmix_lfe_impl(enabled);
+                }
+                ManagerCommand::SetLoopStart(pos) => {
+                    self.set_loop_region(LoopRegion {
+                        start: pos,
+                        ..self.loop_region
+                    });
+                }
+                ManagerCommand::SetLoopEnd(pos) => {
+                    self.set_loop_region(LoopRegion {
+                        end: pos,
+                        ..self.loop_region
+                    });
+                }
+                ManagerCommand::ClearLoop => {
+                    self.set_loop_region(LoopRegion::default());
+                }
+                ManagerCommand::QueuePlayNext(file_paths) => {
+                    self.queue_insert_impl(file_paths, true);
+                }
+                ManagerCommand::QueueAppend(file_paths) => {
+                    self.queue_insert_impl(file_paths, false);
+                }
+                ManagerCommand::QueueRemove(position) => {
+                    self.edit_queue(|queue| queue.remove(position));
+                }
+                ManagerCommand::QueueMove(from, to) => {
+                    self.edit_queue(|queue| queue.move_item(from, to));
+                }
+                ManagerCommand::QueueClearUpcoming => {
+                    self.edit_queue(|queue| {
+                        queue.clear_upcoming();
+                        true
+                    });
+                }
+                ManagerCommand::QueueJumpTo(position) => {
+                    self.queue_jump_to_impl(position);
+                }
+                ManagerCommand::SetResumeSettings(resume_settings) => {
+                    self.settings.resume = resume_settings;
+                    self.settings_store.save(&self.settings);
+                }
+                ManagerCommand::GetResumePositions(reply_tx) => {
+                    let _ = reply_tx.send(self.resume_positions.list());
+                }
+                ManagerCommand::GetSnapshot(reply_tx) => {
+                    let _ = reply_tx.send(self.snapshot());
+                }
+                ManagerCommand::SaveSession(reply_tx) => {
+                    self.save_session();
+                    let _ = reply_tx.send(());
+                }
+                ManagerCommand::ClearResumePositions(path) => match path {
+                    Some(path) => self.resume_positions.remove(&path),
+                    None => self.resume_positions.clear(),
+                },
+                ManagerCommand::SetSleepTimer(mode) => {
+                    self.set_sleep_timer_impl(mode);
+                }
+                ManagerCommand::SetStopAfterCurrent(enabled) => {
+                    self.set_stop_after_current_impl(enabled);
+                }
+                ManagerCommand::SetPlaybackRate(playback_rate) => {
+                    self.set_playback_rate_impl(playback_rate);
+                }
+                ManagerCommand::SetStreamErrorPolicy(policy) => {
+                    self.settings.stream_error_policy = policy;
+                    self.settings_store.save(&self.settings);
+                }
+                ManagerCommand::SetResampler(resampler) => {
+                    // Streams that are already open keep their resampler
+                    self.settings.resampler = resampler;
+                    self.settings_store.save(&self.settings);
+                }
+                ManagerCommand::OutputError(generation, message) => {
+                    self.output_error_impl(generation, message);
+                }
+                ManagerCommand::SetReplayGainPreamp(preamp_db) => {
+                    self.replay_gain_preamp_db = preamp_db
+                        .max(-MAX_REPLAY_GAIN_PREAMP_DB)
+                        .min(MAX_REPLAY_GAIN_PREAMP_DB);
+                    self.apply_replay_gain();
+                }
             }
         }
     }
@@ -241,10 +728,543 @@ impl PlaybackManager {
             self.queue = self.queue.take().map(|queue| queue.to_unshuffled());
         }
         self.shuffle_mode = shuffle_mode;
+        self.send_queue_change();
+        self.refresh_preloaded();
+        if self.replay_gain_mode == ReplayGainMode::Auto {
+            self.apply_replay_gain();
+        }
     }
 
     fn set_repeat_impl(&mut self, repeat_mode: RepeatMode) {
+        if repeat_mode == self.repeat_mode {
+            return;
+        }
         self.repeat_mode = repeat_mode;
+        self.refresh_preloaded();
+    }
+
+    fn set_crossfade_impl(&mut self, crossfade_seconds: f64) {
+        self.crossfade_seconds = crossfade_seconds.max(0.0).min(MAX_CROSSFADE_SECONDS);
+        let crossfade_frames = (self.crossfade_seconds * self.output_sample_rate() as f64) as usize;
+        self.to_process_tx
+            .push(ManagerToProcessMsg::SetCrossfade(crossfade_frames))
+            .unwrap_or_else(|_| {
+                error!("Failed to send crossfade message to audio thread");
+            });
+    }
+
+    fn set_output_device_impl(&mut self, selection: OutputDeviceSelection) {
+        if selection == self.settings.output_device {
+            return;
+        }
+        self.settings.output_device = selection;
+        self.settings_store.save(&self.settings);
+        self.reopen_output();
+    }
+
+    fn set_output_rate_mode_impl(&mut self, output_rate_mode: OutputRateMode) {
+        if output_rate_mode == self.settings.output_rate_mode {
+            return;
+        }
+        self.settings.output_rate_mode = output_rate_mode;
+        self.settings_store.save(&self.settings);
+        // Native rates are picked up again as the current stream is reopened
+        self.requested_sample_rate = PREFERRED_SAMPLE_RATE;
+        self.reopen_output();
+    }
+
+    fn set_multichannel_output_impl(&mut self, enabled: bool) {
+        if enabled == self.settings.multichannel_output {
+            return;
+        }
+        self.settings.multichannel_output = enabled;
+        self.settings_store.save(&self.settings);
+        self.reopen_output();
+    }
+
+    fn set_downmix_lfe_impl(&mut self, enabled: bool) {
+        self.settings.downmix_lfe = enabled;
+        self.settings_store.save(&self.settings);
+        self.to_process_tx
+            .push(ManagerToProcessMsg::SetDownmixLfe(enabled))
+            .unwrap_or_else(|_| {
+                error!("Failed to send downmix LFE message to audio thread");
+            });
+    }
+
+    /// The region to loop, if both loop points are set and in order.
+    fn active_loop_region(&self) -> Option<(usize, usize)> {
+        match (self.loop_region.start, self.loop_region.end) {
+            (Some(start), Some(end)) if start < end => Some((start, end)),
+            _ => None,
+        }
+    }
+
+    /// Updates the loop points. The audio thread is only told when the region that is looped
+    /// changes, since applying it cuts the decoded audio and restarts decoding.
+    fn set_loop_region(&mut self, loop_region: LoopRegion) {
+        let previous_region = self.active_loop_region();
+        self.loop_region = loop_region;
+        self.try_send_event(PlayerEvent::LoopRegionChange(self.loop_region));
+        let active_region = self.active_loop_region();
+        if active_region == previous_region {
+            return;
+        }
+        if let Some(playback_id) = self.current_playback_id {
+            self.to_process_tx
+                .push(ManagerToProcessMsg::SetLoop(playback_id, active_region))
+                .unwrap_or_else(|_| {
+                    error!("Failed to send loop message to audio thread");
+                });
+        }
+    }
+
+    /// Forgets the loop points when the track changes.
+    fn clear_loop_region(&mut self) {
+        if self.loop_region != LoopRegion::default() {
+            self.loop_region = LoopRegion::default();
+            self.try_send_event(PlayerEvent::LoopRegionChange(self.loop_region));
+        }
+    }
+
+    /// Saves the session if it changed since it was last saved.
+    fn save_session(&mut self) {
+        self.session_save_at = Instant::now() + SESSION_SAVE_INTERVAL;
+        let pos = self
+            .stream_timing
+            .as_ref()
+            .map(|timing| timing.pos)
+            .or(self.start_pos.map(|(_, pos)| pos))
+            .unwrap_or(0);
+        let session = Session {
+            queue: self.current_playback_id.and(self.queue.clone()),
+            pos,
+            shuffle_mode: self.shuffle_mode,
+            repeat_mode: self.repeat_mode,
+            volume: self.volume,
+        };
+        if self.saved_session.as_ref() != Some(&session) {
+            self.session_store.save(&session);
+            self.saved_session = Some(session);
+        }
+    }
+
+    /// Restores the session of the last launch, paused where it left off. Files that have been
+    /// moved or deleted since are dropped from the queue.
+    fn restore_session(&mut self) {
+        let Some(session) = self.session_store.load::<Session>() else {
+            return;
+        };
+        if !session.is_valid() {
+            warn!("Ignoring the session of the last launch, which has an invalid queue");
+            self.saved_session = Some(Session::default());
+            return;
+        }
+        self.shuffle_mode = session.shuffle_mode;
+        self.repeat_mode = session.repeat_mode;
+        if let Some(volume) = session.volume {
+            self.volume = Some(volume);
+            self.gain = gain_for_volume(volume);
+            self.send_gain();
+        }
+        self.saved_session = Some(session.clone());
+
+        let Some(queue) = session.queue else {
+            return;
+        };
+        let Some((queue, is_current_kept)) = queue.retain(|path| Path::new(path).is_file()) else {
+            info!("None of the files of the last session exist anymore");
+            return;
+        };
+        let pos = if is_current_kept { session.pos } else { 0 };
+        let path = queue.current().to_owned();
+        info!("Restoring session at {path:?}");
+        self.queue = Some(queue);
+        self.send_queue_change();
+        self.start_playback_at(path, pos);
+        self.set_playback_state(PlaybackState::Paused);
+    }
+
+    /// Sends the volume to the audio thread, along with the fade of the sleep timer.
+    fn send_gain(&mut self) {
+        self.to_process_tx
+            .push(ManagerToProcessMsg::SetGain(self.gain * self.sleep_fade))
+            .unwrap_or_else(|_| {
+                error!("Failed to send gain message to audio thread");
+            });
+    }
+
+    fn set_sleep_timer_impl(&mut self, mode: Option<SleepTimerMode>) {
+        match mode {
+            Some(mode) => info!("Setting sleep timer to {mode:?}"),
+            None => info!("Cancelling sleep timer"),
+        }
+        self.sleep_timer = mode.map(SleepTimer::new);
+        self.update_sleep_timer();
+        // The next track shouldn't be queued for gapless playback if playback stops before it
+        self.refresh_preloaded();
+    }
+
+    fn set_stop_after_current_impl(&mut self, enabled: bool) {
+        if self.stop_after_current == enabled {
+            return;
+        }
+        self.stop_after_current = enabled;
+        self.try_send_event(PlayerEvent::StopAfterCurrentChange(enabled));
+        self.refresh_preloaded();
+    }
+
+    fn stops_after_current_track(&self) -> bool {
+        self.stop_after_current
+            || self
+                .sleep_timer
+                .as_ref()
+                .map_or(false, |timer| timer.ends_with_current_track())
+    }
+
+    /// Counts a track that played to the end, returning whether playback should stop after it.
+    /// The stop after current toggle only applies once.
+    fn take_stop_after_track(&mut self) -> bool {
+        if self.stop_after_current {
+            self.set_stop_after_current_impl(false);
+            return true;
+        }
+        if let Some(timer) = self.sleep_timer.as_mut() {
+            if timer.track_finished() {
+                info!("Sleep timer ran out at the end of the track");
+                self.sleep_timer = None;
+                return true;
+            }
+        }
+        false
+    }
+
+    fn stop_after_track(&mut self) {
+        self.stop_playback();
+        // Restores the gain from the fade, which the audio thread applies once it has stopped
+        self.update_sleep_timer();
+    }
+
+    /// Time left until the end of the current track.
+    fn track_remaining(&self) -> Option<Duration> {
+        self.stream_timing.as_ref().map(|timing| {
+            let time = timing
+                .time_base
+                .calc_time(timing.n_frames.saturating_sub(timing.pos as u64));
+            Duration::from_secs(time.seconds) + Duration::from_secs_f64(time.frac)
+        })
+    }
+
+    /// Updates the fade and the status of the sleep timer, stopping playback if it ran out.
+    fn update_sleep_timer(&mut self) {
+        if self
+            .sleep_timer
+            .as_ref()
+            .map_or(false, |timer| timer.is_expired())
+        {
+            info!("Sleep timer ran out");
+            self.sleep_timer = None;
+            if self.current_playback_id.is_some() {
+                self.stop_playback();
+            }
+        }
+
+        let track_remaining = self.track_remaining();
+        let (fade, status) = match self.sleep_timer.as_mut() {
+            Some(timer) => {
+                let remaining = timer.remaining(track_remaining);
+                timer.schedule_next_tick(remaining);
+                (
+                    sleep_timer::fade_gain(remaining),
+                    Some(timer.status(remaining)),
+                )
+            }
+            None => (1.0, None),
+        };
+        if fade != self.sleep_fade {
+            self.sleep_fade = fade;
+            self.send_gain();
+        }
+        if status != self.sleep_timer_status {
+            self.sleep_timer_status = status;
+            self.try_send_event(PlayerEvent::SleepTimerChange(status));
+        }
+    }
+
+    fn set_playback_rate_impl(&mut self, playback_rate: PlaybackRate) {
+        let playback_rate = playback_rate.clamped();
+        if playback_rate == self.settings.playback_rate {
+            return;
+        }
+        self.settings.playback_rate = playback_rate;
+        self.settings_store.save(&self.settings);
+        self.playback_rate.store(playback_rate);
+        self.try_send_event(PlayerEvent::PlaybackRateChange(playback_rate));
+
+        // Streams are decoded ahead, so seek to flush what was decoded at the old rate
+        if let Some(pos) = self.stream_timing.as_ref().map(|timing| timing.pos) {
+            self.to_process_tx
+                .push(ManagerToProcessMsg::SeekTo(pos))
+                .unwrap_or_else(|_| {
+                    error!("Failed to send seek message to audio thread for playback rate");
+                });
+        }
+        self.refresh_preloaded();
+    }
+
+    fn output_error_impl(&mut self, generation: u64, message: String) {
+        if generation != self.output_generation || self.output.is_none() {
+            return;
+        }
+        error!("Output stream failed: {message}");
+        self.send_error(PlayerError {
+            kind: PlayerErrorKind::Device,
+            path: None,
+            message: message.clone(),
+        });
+        self.output_error = Some(message);
+        if self.playback_state == PlaybackState::Playing {
+            self.set_playback_state(PlaybackState::Paused);
+        }
+        // The device is gone, so switch to whatever is available now
+        self.output = None;
+        self.send_output_status();
+        self.reopen_output();
+    }
+
+    /// Replaces the output, and with it the audio thread state, then picks the current playback
+    /// back up from where it was if the new output could be opened.
+    fn reopen_output(&mut self) {
+        let current_path = self
+            .current_playback_id
+            .and(self.queue.as_ref())
+            .map(|queue| queue.current().to_owned());
+        let pos = self.stream_timing.as_ref().map_or(0, |timing| timing.pos);
+        let playback_state = self.playback_state;
+
+        // A different device or setting may support the rates that failed before
+        self.failed_sample_rates.clear();
+        if !self.replace_output() {
+            return;
+        }
+
+        if let Some(path) = current_path {
+            self.start_playback_at(path, pos);
+            if playback_state == PlaybackState::Paused {
+                self.set_playback_state(PlaybackState::Paused);
+            }
+        }
+    }
+
+    /// Opens the output again with a new audio thread state, returning whether it succeeded.
+    fn replace_output(&mut self) -> bool {
+        // Close the old output first, since some devices can only be opened once
+        let had_output = self.output.take().is_some();
+        // The preloaded stream goes away with the old audio thread state
+        self.preloaded = None;
+        self.output_generation += 1;
+        let (output, to_process_tx) = open_output(
+            &self.settings,
+            self.requested_sample_rate,
+            self.output_generation,
+            &self.command_tx,
+            &self.event_tx,
+        );
+        self.to_process_tx = to_process_tx;
+        if output.is_err() && !had_output {
+            // Still waiting for a device, which has already been reported
+            return false;
+        }
+        self.set_output(output);
+        if self.output.is_none() {
+            return false;
+        }
+        self.configure_process();
+        true
+    }
+
+    fn set_output(&mut self, output: Result<Output, OutputError>) {
+        match output {
+            Ok(output) => {
+                info!(
+                    "Opened output {:?} at {} Hz",
+                    output.device_name, output.sample_rate
+                );
+                self.output = Some(output);
+                self.output_error = None;
+            }
+            Err(e) => {
+                error!("Failed to open output: {e:?}");
+                self.output = None;
+                self.output_error = Some(e.to_string());
+            }
+        }
+        self.send_output_status();
+    }
+
+    fn send_output_status(&mut self) {
+        let status = OutputStatus {
+            device: self
+                .output
+                .as_ref()
+                .map(|output| output.device_name.clone()),
+            sample_rate: self.output.as_ref().map(|output| output.sample_rate),
+            num_channels: self.output.as_ref().map(|output| output.num_channels),
+            error: self.output_error.clone(),
+        };
+        self.try_send_event(PlayerEvent::OutputStatusChange(status));
+    }
+
+    /// Sample rate that streams are opened with. Without an output the preferred rate is used, and
+    /// the streams are opened again once there is one.
+    fn output_sample_rate(&self) -> u32 {
+        self.output
+            .as_ref()
+            .map_or(PREFERRED_SAMPLE_RATE, |output| output.sample_rate)
+    }
+
+    /// Number of channels that DSP nodes are prepared for.
+    fn output_num_channels(&self) -> usize {
+        self.output.as_ref().map_or(2, |output| output.num_channels)
+    }
+
+    /// Sends the current settings to a newly created audio thread.
+    fn configure_process(&mut self) {
+        self.send_gain();
+        self.to_process_tx
+            .push(ManagerToProcessMsg::SetDownmixLfe(
+                self.settings.downmix_lfe,
+            ))
+            .unwrap_or_else(|_| {
+                error!("Failed to send downmix LFE message to audio thread");
+            });
+        self.set_fade_duration_impl(self.fade_duration_ms);
+        self.set_crossfade_impl(self.crossfade_seconds);
+        self.dsp_nodes.clear();
+        self.equalizer_node = None;
+        self.apply_equalizer();
+    }
+
+    fn set_fade_duration_impl(&mut self, fade_duration_ms: u32) {
+        self.fade_duration_ms = fade_duration_ms.min(MAX_FADE_DURATION_MS);
+        let fade_frames =
+            (self.fade_duration_ms as u64 * self.output_sample_rate() as u64 / 1000) as usize;
+        self.to_process_tx
+            .push(ManagerToProcessMsg::SetFade(fade_frames))
+            .unwrap_or_else(|_| {
+                error!("Failed to send fade message to audio thread");
+            });
+    }
+
+    /// Brings the equalizer node in the DSP chain in line with the settings, inserting it at the
+    /// front of the chain when it is enabled and removing it when it is disabled.
+    fn apply_equalizer(&mut self) {
+        match (self.equalizer.enabled, self.equalizer_node) {
+            (true, Some(id)) => {
+                let params = DspNodeParams::Equalizer(self.equalizer.params());
+                self.set_dsp_node_params(id, params);
+            }
+            (true, None) => {
+                let node = Box::new(Equalizer::new(self.equalizer.params()));
+                self.equalizer_node = self.insert_dsp_node(0, node);
+            }
+            (false, Some(id)) => {
+                self.remove_dsp_node(id);
+                self.equalizer_node = None;
+            }
+            (false, None) => {}
+        }
+    }
+
+    /// Inserts a node into the DSP chain, or returns `None` if the chain is full.
+    fn insert_dsp_node(&mut self, index: usize, mut node: Box<dyn DspNode>) -> Option<DspNodeId> {
+        if self.dsp_nodes.len() >= MAX_DSP_NODES {
+            error!("Failed to insert DSP node as the chain is full");
+            return None;
+        }
+        node.prepare(self.output_sample_rate(), self.output_num_channels());
+        let id = self.next_dsp_node_id;
+        self.next_dsp_node_id += 1;
+        let index = index.min(self.dsp_nodes.len());
+        let info = DspNodeInfo {
+            id,
+            name: node.name().to_owned(),
+        };
+        self.to_process_tx
+            .push(ManagerToProcessMsg::InsertDspNode(index, id, node))
+            .unwrap_or_else(|_| {
+                error!("Failed to send DSP node to audio thread");
+            });
+        self.dsp_nodes.insert(index, info);
+        Some(id)
+    }
+
+    fn remove_dsp_node(&mut self, id: DspNodeId) {
+        self.dsp_nodes.retain(|info| info.id != id);
+        self.to_process_tx
+            .push(ManagerToProcessMsg::RemoveDspNode(id))
+            .unwrap_or_else(|_| {
+                error!("Failed to send DSP node removal to audio thread");
+            });
+    }
+
+    fn move_dsp_node(&mut self, id: DspNodeId, index: usize) {
+        let Some(from) = self.dsp_nodes.iter().position(|info| info.id == id) else {
+            return;
+        };
+        let info = self.dsp_nodes.remove(from);
+        let index = index.min(self.dsp_nodes.len());
+        self.dsp_nodes.insert(index, info);
+        self.to_process_tx
+            .push(ManagerToProcessMsg::MoveDspNode(id, index))
+            .unwrap_or_else(|_| {
+                error!("Failed to send DSP node move to audio thread");
+            });
+    }
+
+    fn set_dsp_node_params(&mut self, id: DspNodeId, params: DspNodeParams) {
+        self.to_process_tx
+            .push(ManagerToProcessMsg::SetDspNodeParams(id, params))
+            .unwrap_or_else(|_| {
+                error!("Failed to send DSP node parameters to audio thread");
+            });
+    }
+
+    fn replay_gain_factor(&self, tags: &ReplayGainTags) -> f32 {
+        let mode = match self.replay_gain_mode {
+            ReplayGainMode::Auto if self.shuffle_mode == ShuffleMode::Enabled => {
+                ReplayGainMode::Track
+            }
+            ReplayGainMode::Auto => ReplayGainMode::Album,
+            mode => mode,
+        };
+        tags.gain_factor(mode, self.replay_gain_preamp_db)
+    }
+
+    /// Updates the ReplayGain factor of the streams on the audio thread after a settings change.
+    fn apply_replay_gain(&mut self) {
+        let mut updates = Vec::with_capacity(2);
+        if let Some(playback_id) = self.current_playback_id {
+            updates.push((
+                playback_id,
+                self.replay_gain_factor(&self.current_replay_gain),
+            ));
+        }
+        if let Some(PreloadedPlayback {
+            playback_id,
+            info: Some(info),
+            ..
+        }) = self.preloaded.as_ref()
+        {
+            updates.push((*playback_id, self.replay_gain_factor(&info.replay_gain)));
+        }
+        for (playback_id, replay_gain) in updates {
+            self.to_process_tx
+                .push(ManagerToProcessMsg::SetReplayGain(playback_id, replay_gain))
+                .unwrap_or_else(|_| {
+                    error!("Failed to send ReplayGain message to audio thread");
+                });
+        }
     }
 
     fn start_playback_impl(&mut self, file_paths: Vec<String>, start_index: usize) {
@@ -253,19 +1273,153 @@ impl PlaybackManager {
         } else {
             Queue::from_iter(file_paths, start_index)
         };
+        self.send_queue_change();
         if let Some(queue) = self.queue.as_ref() {
             self.start_playback(queue.current().to_owned());
         }
     }
 
+    /// Adds files to the queue, after the current item or at the end. Playback starts with them
+    /// if there is no queue.
+    fn queue_insert_impl(&mut self, file_paths: Vec<String>, play_next: bool) {
+        let Some(queue) = self.queue.as_mut() else {
+            self.start_playback_impl(file_paths, 0);
+            return;
+        };
+        if play_next {
+            queue.insert_next(file_paths);
+        } else {
+            queue.push_back(file_paths);
+        }
+        self.send_queue_change();
+        self.refresh_preloaded();
+    }
+
+    /// Applies an edit to the upcoming items of the queue, which may change what plays next.
+    fn edit_queue<F: FnOnce(&mut Queue<String>) -> bool>(&mut self, edit: F) {
+        let Some(queue) = self.queue.as_mut() else {
+            return;
+        };
+        if !edit(queue) {
+            warn!("Ignoring invalid queue edit");
+            return;
+        }
+        self.send_queue_change();
+        self.refresh_preloaded();
+    }
+
+    fn queue_jump_to_impl(&mut self, position: usize) {
+        let path = self
+            .queue
+            .as_mut()
+            .and_then(|queue| queue.jump_to(position))
+            .map(|path| path.to_owned());
+        if let Some(path) = path {
+            self.send_queue_change();
+            self.start_playback(path);
+        }
+    }
+
+    fn queue_state(&self) -> QueueState {
+        match self.queue.as_ref() {
+            Some(queue) => QueueState {
+                history: queue.history().cloned().collect(),
+                current: Some(queue.current().to_owned()),
+                upcoming: queue.upcoming().cloned().collect(),
+            },
+            None => QueueState::default(),
+        }
+    }
+
+    fn send_queue_change(&mut self) {
+        let queue_state = self.queue_state();
+        self.try_send_event(PlayerEvent::QueueChange(queue_state));
+    }
+
+    fn snapshot(&self) -> PlayerSnapshot {
+        let playback_file = self
+            .current_playback_id
+            .and(self.queue.as_ref())
+            .map(|queue| playback_file(queue.current()));
+        PlayerSnapshot {
+            playback_file,
+            playback_state: self.playback_state,
+            stream_timing: self
+                .stream_timing
+                .as_ref()
+                .map(|timing| timing.as_stream_timing()),
+            queue: self.queue_state(),
+            shuffle_mode: self.shuffle_mode,
+            repeat_mode: self.repeat_mode,
+            volume: self.volume,
+        }
+    }
+
     fn playback_ended_impl(&mut self, playback_id: u64) {
         if self.current_playback_id != Some(playback_id) {
             return;
         }
-        self.play_next();
+        self.forget_resume_position();
+        if self.take_stop_after_track() {
+            self.stop_after_track();
+        } else {
+            self.play_next();
+        }
     }
 
-    fn open_file_stream_impl(&mut self, playback_id: u64, path: String, file_stream: FileStream) {
+    fn playback_advanced_impl(&mut self, previous_playback_id: u64, playback_id: u64) {
+        if self.current_playback_id != Some(previous_playback_id) {
+            return;
+        }
+        self.forget_resume_position();
+        if self.take_stop_after_track() {
+            // The next stream was queued before playback was set to stop
+            self.stop_after_track();
+            return;
+        }
+        match self.preloaded.take() {
+            Some(PreloadedPlayback {
+                playback_id: preloaded_playback_id,
+                path,
+                info: Some(info),
+            }) if preloaded_playback_id == playback_id => {
+                if self.repeat_mode != RepeatMode::RepeatOne {
+                    let go_next_mode = self.go_next_mode();
+                    if let Some(queue) = self.queue.as_mut() {
+                        queue.go_next(go_next_mode);
+                    }
+                    self.send_queue_change();
+                }
+
+                info!("Continued gapless playback with {:?}", path);
+                self.current_playback_id = Some(playback_id);
+                self.clear_loop_region();
+                self.current_replay_gain = info.replay_gain;
+                self.send_playback_file_change(&path);
+                self.set_stream_timing(info.timing);
+                self.try_send_event(PlayerEvent::StreamMetadataChange(Some(info.metadata)));
+                self.try_send_event(PlayerEvent::StreamFormatChange(Some(info.format)));
+                self.preload_next();
+            }
+            _ => {
+                // The audio thread moved on to a stream that is no longer the next item in the
+                // queue, so start over with the right one.
+                self.play_next();
+            }
+        }
+    }
+
+    fn open_file_stream_impl(
+        &mut self,
+        playback_id: u64,
+        path: String,
+        mut file_stream: FileStream,
+    ) {
+        if self.is_preloaded(playback_id) {
+            self.preloaded_file_stream_opened(&path, file_stream);
+            return;
+        }
+
         if Some(playback_id) != self.current_playback_id {
             info!(
                 "Ignoring stream for {:?} as it is no longer the current playback",
@@ -274,79 +1428,224 @@ impl PlaybackManager {
             return;
         }
 
-        let n_frames = file_stream.n_frames();
-        let time_base = file_stream.time_base();
-
-        if let Some(n_frames) = n_frames {
-            if let Some(time_base) = time_base {
-                self.set_stream_timing(Some(StreamTimingInternal {
-                    time_base: *time_base,
-                    n_frames,
-                    pos: 0,
-                }));
+        let mut info = StreamInfo::from_file_stream(&path, &file_stream, &self.loudness_cache);
+        let mut start_pos = 0;
+        if let Some((_, pos)) = self.start_pos.take().filter(|(id, _)| *id == playback_id) {
+            start_pos = pos;
+            file_stream.seek(pos);
+            if let Some(timing) = info.timing.as_mut() {
+                timing.pos = pos;
             }
         }
+        if let Some(loop_region) = self.active_loop_region() {
+            // The output was reopened in the middle of a loop
+            if let Some(start) = file_stream.set_loop(Some(loop_region)) {
+                start_pos = start;
+                file_stream.seek(start);
+                if let Some(timing) = info.timing.as_mut() {
+                    timing.pos = start;
+                }
+            }
+        }
+        self.current_replay_gain = info.replay_gain;
+        let replay_gain = self.replay_gain_factor(&info.replay_gain);
+        if info.timing.is_some() {
+            self.set_stream_timing(info.timing);
+        }
+        self.try_send_event(PlayerEvent::StreamMetadataChange(Some(info.metadata)));
 
-        let meta: StreamMetadata = file_stream
-            .metadata()
-            .map(|metadata| {
-                let tags = metadata.tags();
-                let track_title_tag = tags
-                    .into_iter()
-                    .find(|tag| tag.std_key == Some(StandardTagKey::TrackTitle));
-                let track_title = track_title_tag.as_ref().and_then(|tag| {
-                    if let Value::String(s) = tag.value.clone() {
-                        Some(s)
-                    } else {
-                        None
-                    }
-                });
-                let artist_tag = tags
-                    .into_iter()
-                    .find(|tag| tag.std_key == Some(StandardTagKey::Artist));
-                let artist = artist_tag.as_ref().and_then(|tag| {
-                    if let Value::String(s) = tag.value.clone() {
-                        Some(s)
-                    } else {
-                        None
-                    }
-                });
-                let album_cover_visual = metadata
-                    .visuals()
-                    .into_iter()
-                    .find(|visual| visual.usage == Some(StandardVisualKey::FrontCover));
-                let album_cover = album_cover_visual.map(|visual| StreamMetadataVisual {
-                    media_type: visual.media_type.to_owned(),
-                    data_base64: general_purpose::STANDARD.encode(visual.data.as_ref()),
-                });
-                StreamMetadata {
-                    track_title,
-                    artist,
-                    album_cover,
-                }
-            })
-            .unwrap_or(StreamMetadata {
-                track_title: None,
-                artist: None,
-                album_cover: None,
-            });
-        self.try_send_event(PlayerEvent::StreamMetadataChange(Some(meta)));
+        if self.output.is_some() && file_stream.sample_rate() != self.output_sample_rate() {
+            // The stream kept its native rate, so the output has to follow
+            info!(
+                "Reopening output at {} Hz for {:?}",
+                file_stream.sample_rate(),
+                path
+            );
+            let previous_sample_rate = self.requested_sample_rate;
+            self.requested_sample_rate = file_stream.sample_rate();
+            if !self.replace_output() {
+                self.native_sample_rate_failed(path, start_pos, previous_sample_rate);
+                return;
+            }
+        }
+        self.try_send_event(PlayerEvent::StreamFormatChange(Some(info.format)));
 
         assert_ne!(self.playback_state, PlaybackState::Stopped);
 
         let start_playback_state = if self.playback_state == PlaybackState::Paused {
             StartPlaybackState::Paused
         } else {
-            StartPlaybackState::Playing
+            StartPlaybackState::Playing {
+                fade_in: start_pos > 0,
+            }
         };
 
         self.to_process_tx
             .push(ManagerToProcessMsg::StartPlayback(
                 playback_id,
                 file_stream,
+                replay_gain,
                 start_playback_state,
             ))
             .unwrap_or_else(|_| warn!("Failed to send message to start playback to audio thread"));
+
+        self.preload_next();
+    }
+
+    /// Goes back to the previous rate after the output couldn't be reopened at the native rate of
+    /// a stream, and opens the stream again so that it's resampled to that rate. Playback stops if
+    /// the output can't be opened at the previous rate either.
+    fn native_sample_rate_failed(&mut self, path: String, pos: usize, previous_sample_rate: u32) {
+        warn!(
+            "Failed to reopen output at {} Hz, resampling {:?} to {} Hz instead",
+            self.requested_sample_rate, path, previous_sample_rate
+        );
+        self.failed_sample_rates.push(self.requested_sample_rate);
+        self.requested_sample_rate = previous_sample_rate;
+        let playback_state = self.playback_state;
+        if self.replace_output() {
+            self.start_playback_at(path, pos);
+            if playback_state == PlaybackState::Paused {
+                self.set_playback_state(PlaybackState::Paused);
+            }
+        } else {
+            self.send_error(PlayerError {
+                kind: PlayerErrorKind::Device,
+                path: None,
+                message: self.output_error.clone().unwrap_or_default(),
+            });
+            self.stop_playback();
+        }
+    }
+
+    fn preloaded_file_stream_opened(&mut self, path: &str, file_stream: FileStream) {
+        if file_stream.sample_rate() != self.output_sample_rate() {
+            // Continuing with this stream needs the output to be reopened, so it can't be gapless
+            info!("Not preloading {path:?} as its sample rate differs from the output");
+            self.preloaded = None;
+            return;
+        }
+        let info = StreamInfo::from_file_stream(path, &file_stream, &self.loudness_cache);
+        let replay_gain = self.replay_gain_factor(&info.replay_gain);
+        let Some(preloaded) = self.preloaded.as_mut() else {
+            return;
+        };
+        preloaded.info = Some(info);
+        let playback_id = preloaded.playback_id;
+        self.to_process_tx
+            .push(ManagerToProcessMsg::EnqueueNext(
+                playback_id,
+                file_stream,
+                replay_gain,
+            ))
+            .unwrap_or_else(|_| {
+                warn!("Failed to send message to enqueue next stream to audio thread");
+                self.preloaded = None;
+            });
+    }
+
+    /// Goes on after the current stream failed partway through, according to the stream error
+    /// policy. Errors are only reported once the player gives up on the file.
+    fn stream_error_impl(&mut self, playback_id: u64, kind: PlayerErrorKind) {
+        if Some(playback_id) != self.current_playback_id {
+            return;
+        }
+        let Some(path) = self.queue.as_ref().map(|queue| queue.current().to_owned()) else {
+            return;
+        };
+        let pos = self.stream_timing.as_ref().map_or(0, |timing| timing.pos);
+        let retries = match self.stream_retries.as_ref() {
+            Some((retried_path, retries)) if *retried_path == path => *retries,
+            _ => 0,
+        };
+
+        let action = self.settings.stream_error_policy.action(retries);
+        if action == StreamErrorAction::Retry {
+            warn!(
+                "Stream for {path:?} failed with {kind:?}, retrying at {pos} ({}/{MAX_STREAM_RETRIES})",
+                retries + 1
+            );
+            self.stream_retries = Some((path.clone(), retries + 1));
+            self.start_playback_at(path, pos);
+            return;
+        }
+
+        error!("Stream for {path:?} failed: {kind:?}");
+        self.stream_retries = None;
+        self.send_error(PlayerError {
+            kind,
+            path: Some(path),
+            message: kind.to_string(),
+        });
+        if action == StreamErrorAction::Stop {
+            self.stop_playback();
+        } else {
+            self.play_next();
+        }
+    }
+
+    fn send_error(&mut self, error: PlayerError) {
+        self.try_send_event(PlayerEvent::Error(error));
+    }
+
+    fn is_preloaded(&self, playback_id: u64) -> bool {
+        self.preloaded
+            .as_ref()
+            .map_or(false, |preloaded| preloaded.playback_id == playback_id)
+    }
+
+    /// Opens the stream for the item that follows the current one in the queue, if any, so that
+    /// the audio thread can continue with it without a gap.
+    fn preload_next(&mut self) {
+        self.discard_preloaded();
+
+        if self.current_playback_id.is_none() || self.stops_after_current_track() {
+            return;
+        }
+
+        let next = if self.repeat_mode == RepeatMode::RepeatOne {
+            self.queue.as_ref().map(|queue| queue.current().to_owned())
+        } else {
+            self.queue
+                .as_ref()
+                .and_then(|queue| queue.peek_next(self.go_next_mode()))
+                .map(|path| path.to_owned())
+        };
+
+        if let Some(path) = next {
+            if self.resume_pos(&path).is_some() {
+                // The next file should start where it was left off, which a gapless transition
+                // can't do
+                return;
+            }
+            info!("Preloading stream for {:?}", path);
+            let playback_id = self.take_playback_id();
+            self.preloaded = Some(PreloadedPlayback {
+                playback_id,
+                path: path.clone(),
+                info: None,
+            });
+            self.open_file_stream(playback_id, path);
+        }
+    }
+
+    /// Preloads the next item again after the queue order or repeat mode changes.
+    fn refresh_preloaded(&mut self) {
+        if self.current_playback_id.is_some() {
+            self.preload_next();
+        }
+    }
+
+    fn discard_preloaded(&mut self) {
+        if let Some(preloaded) = self.preloaded.take() {
+            if preloaded.info.is_some() {
+                self.to_process_tx
+                    .push(ManagerToProcessMsg::ClearNext)
+                    .unwrap_or_else(|_| {
+                        warn!("Failed to send message to clear next stream to audio thread");
+                    });
+            }
+        }
     }
 
     fn skip_forward_impl(&mut self) {
@@ -369,6 +1668,7 @@ impl PlaybackManager {
                 .as_mut()
                 .map(|queue| queue.go_previous_clamped().to_owned());
             if let Some(path) = previous {
+                self.send_queue_change();
                 self.start_playback(path);
             }
         } else if self.stream_timing.as_ref().map_or(0, |timing| timing.pos) > 0 {
@@ -393,7 +1693,42 @@ impl PlaybackManager {
                 ..*stream_timing
             };
             self.set_stream_timing(Some(updated));
+            self.update_resume_position();
+        }
+    }
+
+    /// Remembers the playhead of the current file, if it is one that is resumed.
+    fn update_resume_position(&mut self) {
+        let (Some(timing), Some(queue)) = (self.stream_timing.as_ref(), self.queue.as_ref()) else {
+            return;
+        };
+        let pos_seconds = time_seconds(timing.time_base.calc_time(timing.pos as u64));
+        let duration_seconds = time_seconds(timing.time_base.calc_time(timing.n_frames));
+        self.resume_positions.update(
+            &self.settings.resume,
+            queue.current(),
+            timing.pos,
+            pos_seconds,
+            duration_seconds,
+        );
+    }
+
+    /// Forgets the saved position of the current file once it has played to the end.
+    fn forget_resume_position(&mut self) {
+        if let Some(queue) = self.queue.as_ref() {
+            self.resume_positions.remove(queue.current());
+        }
+    }
+
+    /// Where to start a file, which is a little before where it was left off if it is resumed.
+    fn resume_pos(&self, path: &str) -> Option<usize> {
+        let resume = &self.settings.resume;
+        if !resume.enabled {
+            return None;
         }
+        self.resume_positions
+            .get(path)
+            .map(|position| position.rewound(resume.rewind_seconds))
     }
 
     fn stop_impl(&mut self) {
@@ -421,27 +1756,34 @@ impl PlaybackManager {
             }
             return;
         }
+        let go_next_mode = self.go_next_mode();
         let next = self
             .queue
             .as_mut()
-            .and_then(|queue| {
-                queue.go_next(if self.repeat_mode == RepeatMode::RepeatAll {
-                    GoNextMode::RepeatAll
-                } else {
-                    GoNextMode::Default
-                })
-            })
+            .and_then(|queue| queue.go_next(go_next_mode))
             .map(|path| path.to_owned());
         if let Some(path) = next {
+            self.send_queue_change();
             self.start_playback(path);
         } else {
             self.stop_playback();
             self.queue = None;
+            self.send_queue_change();
+        }
+    }
+
+    fn go_next_mode(&self) -> GoNextMode {
+        if self.repeat_mode == RepeatMode::RepeatAll {
+            GoNextMode::RepeatAll
+        } else {
+            GoNextMode::Default
         }
     }
 
     fn stop_playback(&mut self) {
         self.current_playback_id = None;
+        self.clear_loop_region();
+        self.discard_preloaded();
 
         self.to_process_tx
             .push(ManagerToProcessMsg::Stop)
@@ -453,9 +1795,21 @@ impl PlaybackManager {
         self.set_stream_timing(None);
         self.set_playback_state(PlaybackState::Stopped);
         self.try_send_event(PlayerEvent::StreamMetadataChange(None));
+        self.try_send_event(PlayerEvent::StreamFormatChange(None));
     }
 
     fn start_playback(&mut self, path: String) {
+        self.stream_retries = None;
+        self.clear_loop_region();
+        let pos = self.resume_pos(&path).unwrap_or(0);
+        if pos > 0 {
+            info!("Resuming {path:?} at {pos}");
+        }
+        self.start_playback_at(path, pos);
+    }
+
+    fn start_playback_at(&mut self, path: String, start_pos: usize) {
+        self.discard_preloaded();
         self.to_process_tx
             .push(ManagerToProcessMsg::Stop)
             .unwrap_or_else(|_| {
@@ -467,31 +1821,61 @@ impl PlaybackManager {
         self.set_playback_state(PlaybackState::Playing);
         self.try_send_event(PlayerEvent::StreamMetadataChange(None));
 
-        let playback_id = self.next_playback_id;
-
-        self.next_playback_id += 1;
+        let playback_id = self.take_playback_id();
         self.current_playback_id = Some(playback_id);
+        self.start_pos = Some((playback_id, start_pos)).filter(|(_, pos)| *pos > 0);
 
-        let os_path = Path::new(&path);
-        let file_name = os_path.file_name().unwrap().to_str().unwrap().to_owned();
+        self.send_playback_file_change(&path);
+        self.open_file_stream(playback_id, path);
+    }
 
-        self.try_send_event(PlayerEvent::PlaybackFileChange(Some(PlaybackFile {
-            path: path.clone(),
-            name: file_name,
-        })));
+    fn take_playback_id(&mut self) -> u64 {
+        let playback_id = self.next_playback_id;
+        self.next_playback_id += 1;
+        playback_id
+    }
 
-        let output_sample_rate = self.output.sample_rate;
+    fn send_playback_file_change(&mut self, path: &str) {
+        self.try_send_event(PlayerEvent::PlaybackFileChange(Some(playback_file(path))));
+    }
+
+    fn open_file_stream(&self, playback_id: u64, path: String) {
+        let output_sample_rate = self.output_sample_rate();
+        // Streams can keep their rate if the output can be reopened at it
+        let native_sample_rates = match (&self.output, self.settings.output_rate_mode) {
+            (Some(output), OutputRateMode::Native) => output.supported_sample_rates().to_vec(),
+            _ => Vec::new(),
+        };
+        let failed_sample_rates = self.failed_sample_rates.clone();
+        let select_sample_rate = move |sample_rate: u32| {
+            let is_native = native_sample_rates
+                .iter()
+                .any(|&(min, max)| min <= sample_rate && sample_rate <= max)
+                && !failed_sample_rates.contains(&sample_rate);
+            if is_native {
+                None
+            } else {
+                Some(output_sample_rate)
+            }
+        };
+        let resampler_kind = self.settings.resampler;
+        let playback_rate = self.playback_rate.clone();
         let tx = self.command_tx.clone();
-        thread::spawn(
-            move || match FileStream::open(path.clone(), output_sample_rate) {
+        thread::spawn(move || {
+            match FileStream::open(
+                path.clone(),
+                select_sample_rate,
+                resampler_kind,
+                Some(playback_rate),
+            ) {
                 Ok(file_stream) => tx.send(ManagerCommand::OpenFileStream(
                     playback_id,
                     path,
                     file_stream,
                 )),
                 Err(e) => tx.send(ManagerCommand::OpenFileStreamError(playback_id, path, e)),
-            },
-        );
+            }
+        });
     }
 
     fn set_playback_state(&mut self, playback_state: PlaybackState) {
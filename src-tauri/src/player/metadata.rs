@@ -0,0 +1,236 @@
+use std::path::Path;
+
+use base64::{engine::general_purpose, Engine};
+use serde::{Deserialize, Serialize};
+use symphonia::core::{
+    codecs::CodecParameters,
+    meta::{MetadataRevision, StandardTagKey, StandardVisualKey, Value, Visual},
+};
+
+use super::{
+    errors::FileStreamOpenError,
+    file_stream::{codec_registry, TrackDecoder},
+};
+
+/// What an embedded picture shows, following the picture types of ID3v2 and FLAC.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum VisualUsage {
+    FileIcon,
+    OtherIcon,
+    FrontCover,
+    BackCover,
+    Leaflet,
+    Media,
+    LeadArtist,
+    Artist,
+    Conductor,
+    Band,
+    Composer,
+    Lyricist,
+    RecordingLocation,
+    RecordingSession,
+    Performance,
+    ScreenCapture,
+    Illustration,
+    BandLogo,
+    PublisherLogo,
+}
+
+impl From<StandardVisualKey> for VisualUsage {
+    fn from(value: StandardVisualKey) -> Self {
+        match value {
+            StandardVisualKey::FileIcon => VisualUsage::FileIcon,
+            StandardVisualKey::OtherIcon => VisualUsage::OtherIcon,
+            StandardVisualKey::FrontCover => VisualUsage::FrontCover,
+            StandardVisualKey::BackCover => VisualUsage::BackCover,
+            StandardVisualKey::Leaflet => VisualUsage::Leaflet,
+            StandardVisualKey::Media => VisualUsage::Media,
+            StandardVisualKey::LeadArtistPerformerSoloist => VisualUsage::LeadArtist,
+            StandardVisualKey::ArtistPerformer => VisualUsage::Artist,
+            StandardVisualKey::Conductor => VisualUsage::Conductor,
+            StandardVisualKey::BandOrchestra => VisualUsage::Band,
+            StandardVisualKey::Composer => VisualUsage::Composer,
+            StandardVisualKey::Lyricist => VisualUsage::Lyricist,
+            StandardVisualKey::RecordingLocation => VisualUsage::RecordingLocation,
+            StandardVisualKey::RecordingSession => VisualUsage::RecordingSession,
+            StandardVisualKey::Performance => VisualUsage::Performance,
+            StandardVisualKey::ScreenCapture => VisualUsage::ScreenCapture,
+            StandardVisualKey::Illustration => VisualUsage::Illustration,
+            StandardVisualKey::BandArtistLogo => VisualUsage::BandLogo,
+            StandardVisualKey::PublisherStudioLogo => VisualUsage::PublisherLogo,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct StreamMetadataVisual {
+    pub data_base64: String,
+    pub media_type: String,
+    /// `None` if the file doesn't say what the picture shows.
+    pub usage: Option<VisualUsage>,
+}
+
+impl StreamMetadataVisual {
+    fn new(visual: &Visual) -> StreamMetadataVisual {
+        StreamMetadataVisual {
+            data_base64: general_purpose::STANDARD.encode(visual.data.as_ref()),
+            media_type: visual.media_type.to_owned(),
+            usage: visual.usage.map(VisualUsage::from),
+        }
+    }
+}
+
+/// Technical details of the audio track of a file, as far as the container or codec headers
+/// provide them.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct StreamCodecInfo {
+    pub codec: Option<String>,
+    /// Average bitrate in bits per second, from the file size and duration. This includes tags and
+    /// container overhead.
+    pub bitrate: Option<u64>,
+    pub bits_per_sample: Option<u32>,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<usize>,
+}
+
+impl StreamCodecInfo {
+    pub fn new(codec_params: &CodecParameters, file_size: Option<u64>) -> StreamCodecInfo {
+        let duration_seconds = codec_params
+            .time_base
+            .zip(codec_params.n_frames)
+            .map(|(time_base, n_frames)| {
+                let time = time_base.calc_time(n_frames);
+                time.seconds as f64 + time.frac
+            })
+            .filter(|&seconds| seconds > 0.0);
+        StreamCodecInfo {
+            codec: codec_registry()
+                .get_codec(codec_params.codec)
+                .map(|descriptor| descriptor.long_name.to_owned()),
+            bitrate: file_size
+                .zip(duration_seconds)
+                .map(|(file_size, seconds)| (file_size as f64 * 8.0 / seconds) as u64),
+            bits_per_sample: codec_params.bits_per_sample,
+            sample_rate: codec_params.sample_rate,
+            channels: codec_params.channels.map(|channels| channels.count()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct StreamMetadata {
+    pub track_title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track_number: Option<u32>,
+    pub track_total: Option<u32>,
+    pub disc_number: Option<u32>,
+    pub disc_total: Option<u32>,
+    /// As written in the tags, which can be anything from a year to a full timestamp.
+    pub date: Option<String>,
+    pub genre: Option<String>,
+    pub composer: Option<String>,
+    pub comment: Option<String>,
+    /// All embedded pictures, in the order of the file.
+    pub visuals: Vec<StreamMetadataVisual>,
+    pub codec: StreamCodecInfo,
+}
+
+impl StreamMetadata {
+    /// Collects the tags and pictures of `metadata`. Where a tag appears more than once, the first
+    /// one is used.
+    pub fn new(metadata: Option<&MetadataRevision>, codec: StreamCodecInfo) -> StreamMetadata {
+        let mut stream_metadata = StreamMetadata {
+            codec,
+            ..Default::default()
+        };
+        let Some(metadata) = metadata else {
+            return stream_metadata;
+        };
+
+        let mut original_date = None;
+        for tag in metadata.tags() {
+            let Some(key) = tag.std_key else {
+                continue;
+            };
+            let value = &tag.value;
+            match key {
+                StandardTagKey::TrackTitle => set_text(&mut stream_metadata.track_title, value),
+                StandardTagKey::Artist => set_text(&mut stream_metadata.artist, value),
+                StandardTagKey::Album => set_text(&mut stream_metadata.album, value),
+                StandardTagKey::AlbumArtist => set_text(&mut stream_metadata.album_artist, value),
+                StandardTagKey::Date | StandardTagKey::ReleaseDate => {
+                    set_text(&mut stream_metadata.date, value)
+                }
+                StandardTagKey::OriginalDate => set_text(&mut original_date, value),
+                StandardTagKey::Genre => set_text(&mut stream_metadata.genre, value),
+                StandardTagKey::Composer => set_text(&mut stream_metadata.composer, value),
+                StandardTagKey::Comment => set_text(&mut stream_metadata.comment, value),
+                StandardTagKey::TrackNumber => set_position(
+                    &mut stream_metadata.track_number,
+                    &mut stream_metadata.track_total,
+                    value,
+                ),
+                StandardTagKey::TrackTotal => {
+                    set_position(&mut stream_metadata.track_total, &mut None, value)
+                }
+                StandardTagKey::DiscNumber => set_position(
+                    &mut stream_metadata.disc_number,
+                    &mut stream_metadata.disc_total,
+                    value,
+                ),
+                StandardTagKey::DiscTotal => {
+                    set_position(&mut stream_metadata.disc_total, &mut None, value)
+                }
+                _ => {}
+            }
+        }
+        stream_metadata.date = stream_metadata.date.or(original_date);
+
+        stream_metadata.visuals = metadata
+            .visuals()
+            .iter()
+            .map(StreamMetadataVisual::new)
+            .collect();
+        stream_metadata
+    }
+}
+
+/// Reads the metadata of a file without decoding any audio.
+pub fn read_metadata(path: &str) -> Result<StreamMetadata, FileStreamOpenError> {
+    let mut track = TrackDecoder::open(Path::new(path))?;
+    let metadata = track.metadata();
+    Ok(StreamMetadata::new(
+        metadata.as_ref(),
+        StreamCodecInfo::new(&track.codec_params, track.file_size),
+    ))
+}
+
+fn set_text(field: &mut Option<String>, value: &Value) {
+    if field.is_some() {
+        return;
+    }
+    *field = match value {
+        Value::Binary(_) | Value::Flag => None,
+        Value::String(s) => Some(s.trim().to_owned()).filter(|s| !s.is_empty()),
+        value => Some(value.to_string()),
+    };
+}
+
+/// Sets a track or disc number, which can be written as "3" or "3/12" with the total.
+fn set_position(number: &mut Option<u32>, total: &mut Option<u32>, value: &Value) {
+    let (parsed_number, parsed_total) = match value {
+        Value::UnsignedInt(n) => (u32::try_from(*n).ok(), None),
+        Value::SignedInt(n) => (u32::try_from(*n).ok(), None),
+        Value::String(s) => {
+            let (n, t) = s
+                .split_once('/')
+                .map_or((s.as_str(), None), |(n, t)| (n, Some(t)));
+            (n.trim().parse().ok(), t.and_then(|t| t.trim().parse().ok()))
+        }
+        _ => (None, None),
+    };
+    *number = number.or(parsed_number);
+    *total = total.or(parsed_total);
+}
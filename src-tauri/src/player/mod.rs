@@ -1,43 +1,111 @@
-use std::thread;
+use std::{path::PathBuf, thread};
 
 use log::error;
 use serde::{Deserialize, Serialize};
 use std::sync::mpsc;
 
 use self::{
+    dsp::{DspNode, DspNodeParams},
     file_stream::FileStream,
+    loudness_scanner::{LoudnessScanProgress, LoudnessScanner},
     manager::{ManagerCommand, PlaybackManager},
+    output::OutputStatus,
+    waveform::WaveformCache,
 };
 
+mod analysis;
+mod biquad;
+mod channel_mix;
 mod decode_worker;
+mod dsp;
+mod equalizer;
 mod errors;
 mod file_stream;
+mod formats;
+mod loudness;
+mod loudness_scanner;
 mod manager;
+mod metadata;
+#[cfg(feature = "opus")]
+mod opus_decoder;
 mod output;
 mod process;
 mod queue;
+mod replay_gain;
+mod resampler;
+mod resume;
+mod session;
+mod settings;
+mod sleep_timer;
+mod store;
+mod time_stretch;
+mod waveform;
 
+pub use analysis::ChannelLevel;
+pub use dsp::{DspNodeId, DspNodeInfo};
+pub use equalizer::{EqBand, EqualizerPreset, EqualizerSettings};
+pub use errors::{PlayerError, PlayerErrorKind, StreamErrorPolicy};
+pub use formats::{supported_formats, SupportedFormat};
 pub use manager::RepeatMode;
 pub use manager::ShuffleMode;
+pub use metadata::{read_metadata, StreamMetadata};
+pub use output::{list_output_hosts, OutputDeviceSelection, OutputHostInfo, OutputRateMode};
+pub use replay_gain::ReplayGainMode;
+pub use resampler::ResamplerKind;
+pub use resume::{ResumePosition, ResumeSettings};
+pub use settings::PlayerSettings;
+pub use sleep_timer::{SleepTimerMode, SleepTimerStatus};
+pub use time_stretch::PlaybackRate;
+pub use waveform::WaveformPeak;
 
 pub enum StartPlaybackState {
-    Playing,
+    /// Plays right away, fading in if the stream starts somewhere other than its first frame,
+    /// where the waveform would otherwise jump in.
+    Playing {
+        fade_in: bool,
+    },
     Paused,
 }
 
 pub enum ManagerToProcessMsg {
-    StartPlayback(u64, FileStream, StartPlaybackState),
+    /// Starts playback of a stream with its ReplayGain factor.
+    StartPlayback(u64, FileStream, f32, StartPlaybackState),
+    /// Stream to continue with once the current one reaches the end of the file, with its
+    /// ReplayGain factor.
+    EnqueueNext(u64, FileStream, f32),
+    ClearNext,
+    SetReplayGain(u64, f32),
     Pause,
     Resume,
     Stop,
     SetGain(f32),
+    /// Whether the LFE channel is mixed into the front speakers when downmixing to stereo.
+    SetDownmixLfe(bool),
+    /// Length of the fades around pause, resume, stop, seek and buffer underruns in frames.
+    SetFade(usize),
+    /// Length of the crossfade between consecutive streams in frames, or zero to disable it.
+    SetCrossfade(usize),
+    /// Inserts a node, already prepared for the output format, into the DSP chain at an index.
+    InsertDspNode(usize, DspNodeId, Box<dyn DspNode>),
+    RemoveDspNode(DspNodeId),
+    /// Moves a node to an index in the DSP chain, leaving out the nodes that are being removed.
+    MoveDspNode(DspNodeId, usize),
+    SetDspNodeParams(DspNodeId, DspNodeParams),
     SeekTo(usize),
+    /// Loops the stream with the given playback ID between two positions, or stops looping.
+    SetLoop(u64, Option<(usize, usize)>),
 }
 
-#[derive(Debug)]
 pub enum ProcessToManagerMsg {
     PlaybackPos(u64, usize),
     PlaybackEnded(u64),
+    /// The current stream ended and the enqueued stream took over, as (previous, next).
+    PlaybackAdvanced(u64, u64),
+    /// Decoding or processing of a stream failed. The stream is dropped rather than ended, so
+    /// that the manager can decide how to go on.
+    StreamError(u64, PlayerErrorKind),
+    /// A node that was taken out of the DSP chain and needs to be dropped.
+    DspNodeRemoved(Box<dyn DspNode>),
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
@@ -60,39 +128,89 @@ pub struct StreamTiming {
     pub duration_seconds: u64,
 }
 
-#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+/// Sample rates of the current stream, before and after resampling, and its number of channels.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct StreamFormat {
+    pub source_sample_rate: u32,
+    pub output_sample_rate: u32,
+    pub resampling: bool,
+    pub channels: usize,
+}
+
+/// The play queue as shown to the user, in play order.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct QueueState {
+    pub history: Vec<String>,
+    pub current: Option<String>,
+    pub upcoming: Vec<String>,
+}
+
+/// Everything that the UI otherwise learns from events, for when it's loaded after they were sent,
+/// e.g. the session that is restored at launch.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PlayerSnapshot {
+    pub playback_file: Option<PlaybackFile>,
+    pub playback_state: PlaybackState,
+    pub stream_timing: Option<StreamTiming>,
+    pub queue: QueueState,
+    pub shuffle_mode: ShuffleMode,
+    pub repeat_mode: RepeatMode,
+    /// `None` until the volume has been set once.
+    pub volume: Option<f64>,
+}
+
+/// Loop points A and B of the current track in frames. The region is only looped once both are
+/// set.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct LoopRegion {
+    pub start: Option<usize>,
+    pub end: Option<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum PlayerEvent {
     PlaybackFileChange(Option<PlaybackFile>),
     PlaybackStateChange(PlaybackState),
     StreamTimingChange(Option<StreamTiming>),
     StreamMetadataChange(Option<StreamMetadata>),
-}
-
-#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
-pub struct StreamMetadataVisual {
-    pub data_base64: String,
-    pub media_type: String,
-}
-
-#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
-pub struct StreamMetadata {
-    pub track_title: Option<String>,
-    pub artist: Option<String>,
-    pub album_cover: Option<StreamMetadataVisual>,
+    LoudnessScanProgress(LoudnessScanProgress),
+    OutputStatusChange(OutputStatus),
+    StreamFormatChange(Option<StreamFormat>),
+    PlaybackRateChange(PlaybackRate),
+    LoopRegionChange(LoopRegion),
+    /// Levels of the output channels, sent about 30 times per second during playback.
+    Levels(Vec<ChannelLevel>),
+    /// Levels of the spectrum bands of the output in dBFS, from low to high frequencies.
+    Spectrum(Vec<f32>),
+    /// The sleep timer changed or counted down, or `None` if it was cancelled or ran out.
+    SleepTimerChange(Option<SleepTimerStatus>),
+    StopAfterCurrentChange(bool),
+    QueueChange(QueueState),
+    Error(PlayerError),
 }
 
 pub struct Player {
     command_tx: mpsc::Sender<ManagerCommand>,
+    loudness_scanner: LoudnessScanner,
+    waveforms: WaveformCache,
 }
 
 impl Player {
-    pub fn new(event_tx: tokio::sync::mpsc::Sender<PlayerEvent>) -> Player {
+    /// Creates the player, with `data_dir` as the directory for persisted caches and settings.
+    pub fn new(event_tx: tokio::sync::mpsc::Sender<PlayerEvent>, data_dir: PathBuf) -> Player {
+        let loudness_scanner = LoudnessScanner::new(&data_dir, event_tx.clone());
+        let waveforms = WaveformCache::new(&data_dir);
         let (command_tx, rx) = mpsc::channel();
         thread::spawn({
             let tx = command_tx.clone();
-            move || PlaybackManager::new(event_tx, tx, rx).run()
+            let loudness_cache = loudness_scanner.cache();
+            move || PlaybackManager::new(event_tx, tx, rx, loudness_cache, &data_dir).run()
         });
-        Player { command_tx }
+        Player {
+            command_tx,
+            loudness_scanner,
+            waveforms,
+        }
     }
 
     pub fn start_playback(&mut self, file_paths: &[String], start_index: usize) {
@@ -159,4 +277,296 @@ impl Player {
             .send(ManagerCommand::SetRepeat(repeat_mode))
             .unwrap_or_else(|_| error!("Failed to send repeat command to the manager"))
     }
+
+    pub fn set_crossfade(&mut self, crossfade_seconds: f64) {
+        self.command_tx
+            .send(ManagerCommand::SetCrossfade(crossfade_seconds))
+            .unwrap_or_else(|_| error!("Failed to send crossfade command to the manager"))
+    }
+
+    pub fn set_fade_duration(&mut self, fade_duration_ms: u32) {
+        self.command_tx
+            .send(ManagerCommand::SetFadeDuration(fade_duration_ms))
+            .unwrap_or_else(|_| error!("Failed to send fade duration command to the manager"))
+    }
+
+    pub fn output_device(&mut self) -> OutputDeviceSelection {
+        let (tx, rx) = mpsc::channel();
+        self.command_tx
+            .send(ManagerCommand::GetOutputDevice(tx))
+            .unwrap_or_else(|_| error!("Failed to send get output device command to the manager"));
+        rx.recv().unwrap_or_else(|_| {
+            error!("Failed to receive output device from the manager");
+            OutputDeviceSelection::default()
+        })
+    }
+
+    pub fn set_output_device(&mut self, selection: OutputDeviceSelection) {
+        self.command_tx
+            .send(ManagerCommand::SetOutputDevice(selection))
+            .unwrap_or_else(|_| error!("Failed to send output device command to the manager"));
+    }
+
+    pub fn output_rate_mode(&mut self) -> OutputRateMode {
+        let (tx, rx) = mpsc::channel();
+        self.command_tx
+            .send(ManagerCommand::GetOutputRateMode(tx))
+            .unwrap_or_else(|_| {
+                error!("Failed to send get output rate mode command to the manager")
+            });
+        rx.recv().unwrap_or_else(|_| {
+            error!("Failed to receive output rate mode from the manager");
+            OutputRateMode::default()
+        })
+    }
+
+    pub fn set_output_rate_mode(&mut self, output_rate_mode: OutputRateMode) {
+        self.command_tx
+            .send(ManagerCommand::SetOutputRateMode(output_rate_mode))
+            .unwrap_or_else(|_| error!("Failed to send output rate mode command to the manager"));
+    }
+
+    pub fn settings(&mut self) -> PlayerSettings {
+        let (tx, rx) = mpsc::channel();
+        self.command_tx
+            .send(ManagerCommand::GetSettings(tx))
+            .unwrap_or_else(|_| error!("Failed to send get settings command to the manager"));
+        rx.recv().unwrap_or_else(|_| {
+            error!("Failed to receive settings from the manager");
+            PlayerSettings::default()
+        })
+    }
+
+    pub fn set_multichannel_output(&mut self, enabled: bool) {
+        self.command_tx
+            .send(ManagerCommand::SetMultichannelOutput(enabled))
+            .unwrap_or_else(|_| {
+                error!("Failed to send multichannel output command to the manager")
+            });
+    }
+
+    pub fn set_downmix_lfe(&mut self, enabled: bool) {
+        self.command_tx
+            .send(ManagerCommand::SetDownmixLfe(enabled))
+            .unwrap_or_else(|_| error!("Failed to send downmix LFE command to the manager"));
+    }
+
+    /// Changes the playback speed, optionally time-stretching to keep the pitch. Positions are
+    /// still reported in frames of the file.
+    pub fn set_playback_rate(&mut self, playback_rate: PlaybackRate) {
+        self.command_tx
+            .send(ManagerCommand::SetPlaybackRate(playback_rate))
+            .unwrap_or_else(|_| error!("Failed to send playback rate command to the manager"));
+    }
+
+    /// Sets loop point A of the current track, in the same units as `seek`.
+    pub fn set_loop_start(&mut self, pos: Option<usize>) {
+        self.command_tx
+            .send(ManagerCommand::SetLoopStart(pos))
+            .unwrap_or_else(|_| error!("Failed to send loop start command to the manager"));
+    }
+
+    /// Sets loop point B of the current track, in the same units as `seek`.
+    pub fn set_loop_end(&mut self, pos: Option<usize>) {
+        self.command_tx
+            .send(ManagerCommand::SetLoopEnd(pos))
+            .unwrap_or_else(|_| error!("Failed to send loop end command to the manager"));
+    }
+
+    pub fn clear_loop(&mut self) {
+        self.command_tx
+            .send(ManagerCommand::ClearLoop)
+            .unwrap_or_else(|_| error!("Failed to send clear loop command to the manager"));
+    }
+
+    /// Queues files to be played after the current one.
+    pub fn queue_play_next(&mut self, file_paths: Vec<String>) {
+        self.command_tx
+            .send(ManagerCommand::QueuePlayNext(file_paths))
+            .unwrap_or_else(|_| error!("Failed to send queue play next command to the manager"));
+    }
+
+    /// Queues files to be played after everything else.
+    pub fn queue_append(&mut self, file_paths: Vec<String>) {
+        self.command_tx
+            .send(ManagerCommand::QueueAppend(file_paths))
+            .unwrap_or_else(|_| error!("Failed to send queue append command to the manager"));
+    }
+
+    /// Removes the item at `position` in the play order, which counts from the start of the
+    /// history so that the current item is at `history.len()`. The current item can't be removed.
+    pub fn queue_remove(&mut self, position: usize) {
+        self.command_tx
+            .send(ManagerCommand::QueueRemove(position))
+            .unwrap_or_else(|_| error!("Failed to send queue remove command to the manager"));
+    }
+
+    /// Moves the item at position `from` in the play order to `to`.
+    pub fn queue_move(&mut self, from: usize, to: usize) {
+        self.command_tx
+            .send(ManagerCommand::QueueMove(from, to))
+            .unwrap_or_else(|_| error!("Failed to send queue move command to the manager"));
+    }
+
+    pub fn queue_clear_upcoming(&mut self) {
+        self.command_tx
+            .send(ManagerCommand::QueueClearUpcoming)
+            .unwrap_or_else(|_| error!("Failed to send queue clear command to the manager"));
+    }
+
+    /// Starts playing the item at `position` in the play order.
+    pub fn queue_jump_to(&mut self, position: usize) {
+        self.command_tx
+            .send(ManagerCommand::QueueJumpTo(position))
+            .unwrap_or_else(|_| error!("Failed to send queue jump command to the manager"));
+    }
+
+    /// Sets which files start where they were left off.
+    pub fn set_resume_settings(&mut self, resume_settings: ResumeSettings) {
+        self.command_tx
+            .send(ManagerCommand::SetResumeSettings(resume_settings))
+            .unwrap_or_else(|_| error!("Failed to send resume settings command to the manager"));
+    }
+
+    pub fn resume_positions(&mut self) -> Vec<ResumePosition> {
+        let (tx, rx) = mpsc::channel();
+        self.command_tx
+            .send(ManagerCommand::GetResumePositions(tx))
+            .unwrap_or_else(|_| {
+                error!("Failed to send get resume positions command to the manager")
+            });
+        rx.recv().unwrap_or_else(|_| {
+            error!("Failed to receive resume positions from the manager");
+            Vec::new()
+        })
+    }
+
+    pub fn snapshot(&mut self) -> Option<PlayerSnapshot> {
+        let (tx, rx) = mpsc::channel();
+        self.command_tx
+            .send(ManagerCommand::GetSnapshot(tx))
+            .unwrap_or_else(|_| error!("Failed to send get snapshot command to the manager"));
+        rx.recv()
+            .map_err(|_| error!("Failed to receive snapshot from the manager"))
+            .ok()
+    }
+
+    /// Writes the session to disk right away instead of at the next periodic save, and waits until
+    /// it's done. Meant to be called when the app exits.
+    pub fn save_session(&mut self) {
+        let (tx, rx) = mpsc::channel();
+        self.command_tx
+            .send(ManagerCommand::SaveSession(tx))
+            .unwrap_or_else(|_| error!("Failed to send save session command to the manager"));
+        rx.recv()
+            .unwrap_or_else(|_| error!("Failed to save the session before exiting"));
+    }
+
+    /// Forgets the saved position of `path`, or of every file if it is `None`.
+    pub fn clear_resume_positions(&mut self, path: Option<String>) {
+        self.command_tx
+            .send(ManagerCommand::ClearResumePositions(path))
+            .unwrap_or_else(|_| {
+                error!("Failed to send clear resume positions command to the manager")
+            });
+    }
+
+    /// Stops playback after some time or number of tracks, fading out over the last minute.
+    /// `None` cancels the timer.
+    pub fn set_sleep_timer(&mut self, mode: Option<SleepTimerMode>) {
+        self.command_tx
+            .send(ManagerCommand::SetSleepTimer(mode))
+            .unwrap_or_else(|_| error!("Failed to send sleep timer command to the manager"));
+    }
+
+    pub fn set_stop_after_current(&mut self, enabled: bool) {
+        self.command_tx
+            .send(ManagerCommand::SetStopAfterCurrent(enabled))
+            .unwrap_or_else(|_| error!("Failed to send stop after current command to the manager"));
+    }
+
+    pub fn set_stream_error_policy(&mut self, policy: StreamErrorPolicy) {
+        self.command_tx
+            .send(ManagerCommand::SetStreamErrorPolicy(policy))
+            .unwrap_or_else(|_| {
+                error!("Failed to send stream error policy command to the manager")
+            });
+    }
+
+    pub fn set_resampler(&mut self, resampler: ResamplerKind) {
+        self.command_tx
+            .send(ManagerCommand::SetResampler(resampler))
+            .unwrap_or_else(|_| error!("Failed to send resampler command to the manager"));
+    }
+
+    pub fn equalizer(&mut self) -> EqualizerSettings {
+        let (tx, rx) = mpsc::channel();
+        self.command_tx
+            .send(ManagerCommand::GetEqualizer(tx))
+            .unwrap_or_else(|_| error!("Failed to send get equalizer command to the manager"));
+        rx.recv().unwrap_or_else(|_| {
+            error!("Failed to receive equalizer settings from the manager");
+            EqualizerSettings::default()
+        })
+    }
+
+    pub fn set_equalizer_enabled(&mut self, enabled: bool) {
+        self.command_tx
+            .send(ManagerCommand::SetEqualizerEnabled(enabled))
+            .unwrap_or_else(|_| error!("Failed to send equalizer command to the manager"));
+    }
+
+    pub fn set_equalizer_bands(&mut self, bands: Vec<EqBand>) {
+        self.command_tx
+            .send(ManagerCommand::SetEqualizerBands(bands))
+            .unwrap_or_else(|_| error!("Failed to send equalizer bands command to the manager"));
+    }
+
+    pub fn set_equalizer_preset(&mut self, preset: EqualizerPreset) {
+        self.command_tx
+            .send(ManagerCommand::SetEqualizerPreset(preset))
+            .unwrap_or_else(|_| error!("Failed to send equalizer preset command to the manager"));
+    }
+
+    /// The nodes in the DSP chain, in the order that the output passes through them.
+    pub fn dsp_nodes(&mut self) -> Vec<DspNodeInfo> {
+        let (tx, rx) = mpsc::channel();
+        self.command_tx
+            .send(ManagerCommand::GetDspNodes(tx))
+            .unwrap_or_else(|_| error!("Failed to send get DSP nodes command to the manager"));
+        rx.recv().unwrap_or_else(|_| {
+            error!("Failed to receive DSP nodes from the manager");
+            Vec::new()
+        })
+    }
+
+    pub fn move_dsp_node(&mut self, id: DspNodeId, index: usize) {
+        self.command_tx
+            .send(ManagerCommand::MoveDspNode(id, index))
+            .unwrap_or_else(|_| error!("Failed to send move DSP node command to the manager"));
+    }
+
+    pub fn set_replay_gain_mode(&mut self, replay_gain_mode: ReplayGainMode) {
+        self.command_tx
+            .send(ManagerCommand::SetReplayGainMode(replay_gain_mode))
+            .unwrap_or_else(|_| error!("Failed to send ReplayGain mode command to the manager"))
+    }
+
+    /// Measures the loudness of the files in the background, to be used in place of missing gain
+    /// tags. Files in the same directory are measured together as an album.
+    pub fn scan_loudness(&mut self, file_paths: Vec<String>) {
+        self.loudness_scanner.scan(file_paths);
+    }
+
+    /// The waveform cache, which can be used without holding on to the player while a file is
+    /// decoded.
+    pub fn waveforms(&self) -> WaveformCache {
+        self.waveforms.clone()
+    }
+
+    pub fn set_replay_gain_preamp(&mut self, preamp_db: f32) {
+        self.command_tx
+            .send(ManagerCommand::SetReplayGainPreamp(preamp_db))
+            .unwrap_or_else(|_| error!("Failed to send ReplayGain pre-amp command to the manager"))
+    }
 }
@@ -0,0 +1,179 @@
+use std::sync::{Mutex, PoisonError};
+
+use audiopus::{
+    coder::{Decoder as LibopusDecoder, GenericCtl},
+    packet::Packet as OpusPacket,
+    MutSignals, SampleRate,
+};
+
+use symphonia::core::{
+    audio::{AsAudioBufferRef, AudioBuffer, AudioBufferRef, Channels, Signal, SignalSpec},
+    codecs::{
+        CodecDescriptor, CodecParameters, Decoder, DecoderOptions, FinalizeResult, CODEC_TYPE_OPUS,
+    },
+    errors::{decode_error, unsupported_error, Result},
+    formats::Packet,
+    support_codec,
+};
+
+/// Opus always decodes at 48 kHz, and timestamps of Ogg Opus streams are in this rate too.
+const OPUS_SAMPLE_RATE: u32 = 48000;
+/// The longest packet that Opus allows, which is 120 ms.
+const MAX_FRAMES_PER_PACKET: usize = 5760;
+const OPUS_HEAD_SIZE: usize = 19;
+
+/// The fields of the Ogg Opus identification header that decoding depends on, see RFC 7845.
+struct OpusHead {
+    num_channels: usize,
+    /// Frames at the start of the stream that are only there to prime the decoder.
+    pre_skip: u16,
+    /// Gain to apply to the output in dB, as a Q7.8 fixed point number.
+    output_gain: i16,
+}
+
+impl OpusHead {
+    fn parse(data: &[u8]) -> Result<OpusHead> {
+        if data.len() < OPUS_HEAD_SIZE || &data[0..8] != b"OpusHead" {
+            return decode_error("opus: missing identification header");
+        }
+        let num_channels = data[9] as usize;
+        let pre_skip = u16::from_le_bytes([data[10], data[11]]);
+        let output_gain = i16::from_le_bytes([data[16], data[17]]);
+        let mapping_family = data[18];
+        if !(1..=2).contains(&num_channels) {
+            return unsupported_error("opus: multistream files are not supported");
+        }
+
+        // Only the layouts that fit in a single Opus stream are supported, which covers mono and
+        // stereo files
+        let is_single_stream = match mapping_family {
+            0 => true,
+            1 => {
+                data.len() >= OPUS_HEAD_SIZE + 2 + num_channels
+                    && data[19] == 1
+                    && data[20] as usize == num_channels - 1
+                    && (0..num_channels).all(|channel| data[21 + channel] as usize == channel)
+            }
+            _ => false,
+        };
+        if !is_single_stream {
+            return unsupported_error("opus: multistream files are not supported");
+        }
+
+        Ok(OpusHead {
+            num_channels,
+            pre_skip,
+            output_gain,
+        })
+    }
+}
+
+/// Decodes Opus packets from Ogg files with libopus, in a form that can be registered with
+/// symphonia.
+pub struct OpusDecoder {
+    params: CodecParameters,
+    // libopus decoders can be moved between threads but not shared, which `Decoder` requires
+    decoder: Mutex<LibopusDecoder>,
+    num_channels: usize,
+    pre_skip: u64,
+    gain: f32,
+    /// Interleaved output of libopus.
+    interleaved: Vec<f32>,
+    buf: AudioBuffer<f32>,
+}
+
+impl Decoder for OpusDecoder {
+    fn try_new(params: &CodecParameters, _options: &DecoderOptions) -> Result<Self> {
+        let head = OpusHead::parse(params.extra_data.as_deref().unwrap_or_default())?;
+        let (channels, layout) = if head.num_channels == 1 {
+            (audiopus::Channels::Mono, Channels::FRONT_LEFT)
+        } else {
+            (
+                audiopus::Channels::Stereo,
+                Channels::FRONT_LEFT | Channels::FRONT_RIGHT,
+            )
+        };
+        let Ok(decoder) = LibopusDecoder::new(SampleRate::Hz48000, channels) else {
+            return decode_error("opus: failed to create decoder");
+        };
+
+        Ok(OpusDecoder {
+            params: params.clone(),
+            decoder: Mutex::new(decoder),
+            num_channels: head.num_channels,
+            pre_skip: head.pre_skip as u64,
+            gain: 10_f32.powf(head.output_gain as f32 / 256.0 / 20.0),
+            interleaved: vec![0.0; MAX_FRAMES_PER_PACKET * head.num_channels],
+            buf: AudioBuffer::new(
+                MAX_FRAMES_PER_PACKET as u64,
+                SignalSpec::new(OPUS_SAMPLE_RATE, layout),
+            ),
+        })
+    }
+
+    fn supported_codecs() -> &'static [CodecDescriptor] {
+        &[support_codec!(CODEC_TYPE_OPUS, "opus", "Opus")]
+    }
+
+    fn reset(&mut self) {
+        let decoder = self
+            .decoder
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        // Resetting only fails for an invalid decoder, which would fail on the next packet too
+        let _ = decoder.reset_state();
+    }
+
+    fn codec_params(&self) -> &CodecParameters {
+        &self.params
+    }
+
+    fn decode(&mut self, packet: &Packet) -> Result<AudioBufferRef<'_>> {
+        let decoder = self
+            .decoder
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        // An empty packet stands for a lost one, which libopus conceals when it's given nothing
+        let input = match packet.data.len() {
+            0 => Ok(None),
+            _ => OpusPacket::try_from(&packet.data[..]).map(Some),
+        };
+        let num_frames = input.and_then(|input| {
+            let output = MutSignals::try_from(&mut self.interleaved[..])?;
+            decoder.decode_float(input, output, false)
+        });
+        let Ok(num_frames) = num_frames else {
+            self.buf.clear();
+            return decode_error("opus: invalid packet");
+        };
+
+        self.buf.clear();
+        self.buf.render_reserved(Some(num_frames));
+        for channel in 0..self.num_channels {
+            let samples = self.interleaved[0..num_frames * self.num_channels]
+                .iter()
+                .skip(channel)
+                .step_by(self.num_channels);
+            for (dst, &sample) in self.buf.chan_mut(channel).iter_mut().zip(samples) {
+                *dst = sample * self.gain;
+            }
+        }
+
+        // Timestamps count the pre-skip frames, so they are dropped here whether the stream was
+        // started from the beginning or seeked back to it
+        let pre_skip = self.pre_skip.saturating_sub(packet.ts) as usize;
+        self.buf.trim(
+            pre_skip.max(packet.trim_start as usize),
+            packet.trim_end as usize,
+        );
+        Ok(self.buf.as_audio_buffer_ref())
+    }
+
+    fn finalize(&mut self) -> FinalizeResult {
+        FinalizeResult::default()
+    }
+
+    fn last_decoded(&self) -> AudioBufferRef<'_> {
+        self.buf.as_audio_buffer_ref()
+    }
+}
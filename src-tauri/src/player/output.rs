@@ -1,40 +1,213 @@
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use log::trace;
+use log::{trace, warn};
 use rtrb::{Consumer, Producer};
+use serde::{Deserialize, Serialize};
 
+use crate::player::analysis::{AnalysisFrame, AnalysisTap};
+use crate::player::channel_mix::MAX_OUTPUT_CHANNELS;
+use crate::player::errors::OutputError;
 use crate::player::process::Process;
 use crate::player::{ManagerToProcessMsg, ProcessToManagerMsg};
 
 pub struct Output {
     _stream: cpal::Stream,
+    pub device_name: String,
     pub sample_rate: u32,
+    pub num_channels: usize,
     pub buffer_size: u32,
+    /// Ranges of sample rates that the device supports, as (min, max).
+    supported_sample_rates: Vec<(u32, u32)>,
 }
 
 const PREFERRED_BUFFER_SIZE: u32 = 1024;
-const PREFERRED_SAMPLE_RATE: u32 = 44100;
+pub const PREFERRED_SAMPLE_RATE: u32 = 44100;
+
+/// How the sample rate of the output is chosen.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub enum OutputRateMode {
+    /// The output always runs at the preferred rate, and streams are resampled to it.
+    #[default]
+    Fixed,
+    /// The output is reopened at the rate of each track if the device supports it, so that the
+    /// audio doesn't have to be resampled.
+    Native,
+}
+
+/// The state of the output as shown to the user. There is no device while the player is waiting
+/// for one to become available.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct OutputStatus {
+    pub device: Option<String>,
+    pub sample_rate: Option<u32>,
+    pub num_channels: Option<usize>,
+    /// The last error of the output, if it failed.
+    pub error: Option<String>,
+}
+
+/// The output device chosen by the user. Unset fields fall back to the system defaults.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct OutputDeviceSelection {
+    pub host: Option<String>,
+    pub device: Option<String>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct OutputConfigInfo {
+    pub channels: u16,
+    pub min_sample_rate: u32,
+    pub max_sample_rate: u32,
+    pub sample_format: String,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct OutputDeviceInfo {
+    pub name: String,
+    pub is_default: bool,
+    pub configs: Vec<OutputConfigInfo>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct OutputHostInfo {
+    pub name: String,
+    pub is_default: bool,
+    pub devices: Vec<OutputDeviceInfo>,
+}
+
+/// Lists the output devices of every audio host that is available on this system.
+pub fn list_output_hosts() -> Vec<OutputHostInfo> {
+    let default_host_name = cpal::default_host().id().name();
+    cpal::available_hosts()
+        .into_iter()
+        .filter_map(|host_id| match cpal::host_from_id(host_id) {
+            Ok(host) => Some(host),
+            Err(e) => {
+                warn!("Audio host {} is unavailable: {e:?}", host_id.name());
+                None
+            }
+        })
+        .map(|host| OutputHostInfo {
+            name: host.id().name().to_owned(),
+            is_default: host.id().name() == default_host_name,
+            devices: list_output_devices(&host),
+        })
+        .collect()
+}
+
+fn list_output_devices(host: &cpal::Host) -> Vec<OutputDeviceInfo> {
+    let default_device_name = host
+        .default_output_device()
+        .and_then(|device| device.name().ok());
+    let devices = match host.output_devices() {
+        Ok(devices) => devices,
+        Err(e) => {
+            warn!("Failed to list devices of {}: {e:?}", host.id().name());
+            return Vec::new();
+        }
+    };
+    devices
+        .filter_map(|device| {
+            let name = device.name().ok()?;
+            let configs = device
+                .supported_output_configs()
+                .map(|configs| {
+                    configs
+                        .map(|config| OutputConfigInfo {
+                            channels: config.channels(),
+                            min_sample_rate: config.min_sample_rate().0,
+                            max_sample_rate: config.max_sample_rate().0,
+                            sample_format: config.sample_format().to_string(),
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+            Some(OutputDeviceInfo {
+                is_default: default_device_name.as_ref() == Some(&name),
+                name,
+                configs,
+            })
+        })
+        .collect()
+}
+
+/// Finds the selected host, or the default one if it isn't set or isn't available.
+fn select_host(selection: &OutputDeviceSelection) -> cpal::Host {
+    let Some(host_name) = selection.host.as_ref() else {
+        return cpal::default_host();
+    };
+    let host_id = cpal::available_hosts()
+        .into_iter()
+        .find(|host_id| host_id.name() == host_name);
+    match host_id.map(cpal::host_from_id) {
+        Some(Ok(host)) => host,
+        _ => {
+            warn!("Audio host {host_name:?} is unavailable, using the default host");
+            cpal::default_host()
+        }
+    }
+}
+
+/// Finds the selected device on `host`, or the default one if it isn't set or can't be found.
+fn select_device(host: &cpal::Host, selection: &OutputDeviceSelection) -> Option<cpal::Device> {
+    if let Some(device_name) = selection.device.as_ref() {
+        let device = host.output_devices().ok().and_then(|mut devices| {
+            devices.find(|device| device.name().ok().as_ref() == Some(device_name))
+        });
+        if device.is_some() {
+            return device;
+        }
+        warn!("Output device {device_name:?} was not found, using the default device");
+    }
+    host.default_output_device()
+}
 
 impl Output {
-    pub fn new(
+    /// Opens the selected device, calling `on_error` from a background thread if the stream fails
+    /// after that. With `multichannel`, the output is opened with as many channels as the device
+    /// supports, up to 7.1, so that surround audio can be passed through instead of downmixed.
+    pub fn new<E>(
+        selection: &OutputDeviceSelection,
+        preferred_sample_rate: u32,
+        multichannel: bool,
         to_manager_tx: Producer<ProcessToManagerMsg>,
         from_manager_rx: Consumer<ManagerToProcessMsg>,
-    ) -> Output {
+        to_analysis_tx: Producer<AnalysisFrame>,
+        on_error: E,
+    ) -> Result<Output, OutputError>
+    where
+        E: FnMut(cpal::StreamError) + Send + 'static,
+    {
         // Setup cpal audio output
 
-        let host = cpal::default_host();
+        let host = select_host(selection);
 
-        let device = host
-            .default_output_device()
-            .expect("no output device available");
-        let default_config = device.default_output_config().unwrap();
+        let device = select_device(&host, selection).ok_or(OutputError::NoDevice)?;
+        let device_name = device.name().unwrap_or_default();
+        let default_config = device.default_output_config()?;
 
         // The only other property that could be relevant is
         // the buffer size range, but that seems unlikely to cause
         // problems.
-        let preferred_config = device.supported_output_configs().unwrap().find(|c| {
-            c.max_sample_rate().0 >= PREFERRED_SAMPLE_RATE
-                && c.min_sample_rate().0 <= PREFERRED_SAMPLE_RATE
-        });
+        let supported_configs: Vec<_> = device.supported_output_configs()?.collect();
+        let supported_sample_rates = supported_configs
+            .iter()
+            .map(|c| (c.min_sample_rate().0, c.max_sample_rate().0))
+            .collect();
+        let supports_rate = |c: &cpal::SupportedStreamConfigRange| {
+            c.max_sample_rate().0 >= preferred_sample_rate
+                && c.min_sample_rate().0 <= preferred_sample_rate
+        };
+        let preferred_config = if multichannel {
+            supported_configs
+                .into_iter()
+                .filter(|c| supports_rate(c) && c.channels() as usize <= MAX_OUTPUT_CHANNELS)
+                .max_by_key(|c| c.channels())
+        } else {
+            supported_configs.into_iter().find(supports_rate)
+        };
+        let num_channels = match preferred_config.as_ref() {
+            Some(config) if multichannel => config.channels().max(2),
+            _ => 2,
+        };
 
         let buffer_size_range = preferred_config
             .as_ref()
@@ -46,36 +219,47 @@ impl Output {
         let sample_rate = preferred_config
             .as_ref()
             .map_or(default_config.sample_rate(), |value| {
-                cpal::SampleRate(value.max_sample_rate().0.min(PREFERRED_SAMPLE_RATE))
+                cpal::SampleRate(value.max_sample_rate().0.min(preferred_sample_rate))
             });
 
         let config = cpal::StreamConfig {
-            channels: 2,
+            channels: num_channels,
             sample_rate,
             buffer_size: cpal::BufferSize::Fixed(buffer_size),
         };
 
-        let mut process = Process::new(to_manager_tx, from_manager_rx);
+        let analysis = AnalysisTap::new(num_channels as usize, sample_rate.0, to_analysis_tx);
+        let mut process = Process::new(
+            sample_rate.0,
+            num_channels as usize,
+            analysis,
+            to_manager_tx,
+            from_manager_rx,
+        );
 
-        let stream = device
-            .build_output_stream(
-                &config,
-                move |data: &mut [f32], _: &cpal::OutputCallbackInfo| process.process(data),
-                move |err| {
-                    eprintln!("{}", err);
-                },
-                None,
-            )
-            .unwrap();
+        let stream = device.build_output_stream(
+            &config,
+            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| process.process(data),
+            on_error,
+            None,
+        )?;
 
-        stream.play().unwrap();
+        stream.play()?;
 
         trace!("Stream sample rate: {:?}", sample_rate.0);
+        trace!("Stream channels: {num_channels}");
 
-        Output {
+        Ok(Output {
             _stream: stream,
+            device_name,
             sample_rate: sample_rate.0,
+            num_channels: num_channels as usize,
             buffer_size,
-        }
+            supported_sample_rates,
+        })
+    }
+
+    pub fn supported_sample_rates(&self) -> &[(u32, u32)] {
+        &self.supported_sample_rates
     }
 }
@@ -1,11 +1,18 @@
 // use creek::read::ReadError;
 // use creek::{Decoder, ReadDiskStream, SeekMode, SymphoniaDecoder};
 use log::error;
-use rtrb::{Consumer, Producer};
+use rtrb::{Consumer, Producer, PushError};
 
 use crate::player::{ManagerToProcessMsg, ProcessToManagerMsg};
 
-use super::{file_stream::FileStream, StartPlaybackState};
+use super::{
+    analysis::AnalysisTap,
+    channel_mix::{ChannelMix, MAX_OUTPUT_CHANNELS},
+    dsp::DspChain,
+    errors::PlayerErrorKind,
+    file_stream::FileStream,
+    StartPlaybackState,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ProcessPlaybackState {
@@ -16,29 +23,90 @@ pub enum ProcessPlaybackState {
 struct Stream {
     file_stream: FileStream,
     playback_id: u64,
+    /// Gain factor for loudness normalization of this stream.
+    replay_gain: f32,
+    channel_mix: ChannelMix,
 }
 
+/// Progress of a crossfade from the current stream into the next one, in frames.
+struct Crossfade {
+    pos: usize,
+    len: usize,
+}
+
+impl Crossfade {
+    /// Equal-power gains for the outgoing and incoming streams `offset` frames into the fade.
+    fn gains(&self, offset: usize) -> (f32, f32) {
+        let t = ((self.pos + offset) as f32 / self.len as f32).min(1.0);
+        let angle = t * std::f32::consts::FRAC_PI_2;
+        (angle.cos(), angle.sin())
+    }
+}
+
+/// A change that only takes effect once the output has faded out, to avoid clicks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Transition {
+    Pause,
+    Stop,
+    SeekTo(usize),
+}
+
+/// Size of the scratch buffer used to mix the incoming stream during a crossfade.
+const MIX_BUFFER_FRAMES: usize = 4096;
+
 pub struct Process {
+    /// Number of interleaved channels in the output buffer.
+    num_channels: usize,
+    /// Whether the LFE channel is kept when downmixing to stereo.
+    downmix_lfe: bool,
     stream: Option<Stream>,
+    next_stream: Option<Stream>,
     to_gui_tx: Producer<ProcessToManagerMsg>,
     from_gui_rx: Consumer<ManagerToProcessMsg>,
     playback_state: ProcessPlaybackState,
     gain: f32,
+    crossfade_frames: usize,
+    crossfade: Option<Crossfade>,
+    mix_buffer: Vec<f32>,
+    dsp_chain: DspChain,
+    fade_frames: usize,
+    /// Gain of the fade that is applied to the output, between zero and one.
+    fade_gain: f32,
+    fade_target: f32,
+    pending_transition: Option<Transition>,
     fatal_error: bool,
+    /// Measures the final output for the level meter and spectrum.
+    analysis: AnalysisTap,
 }
 
 impl Process {
     pub fn new(
+        sample_rate: u32,
+        num_channels: usize,
+        analysis: AnalysisTap,
         to_gui_tx: Producer<ProcessToManagerMsg>,
         from_gui_rx: Consumer<ManagerToProcessMsg>,
     ) -> Self {
+        let num_channels = num_channels.clamp(2, MAX_OUTPUT_CHANNELS);
         Self {
+            num_channels,
+            downmix_lfe: false,
             stream: None,
+            next_stream: None,
             to_gui_tx,
             from_gui_rx,
             playback_state: ProcessPlaybackState::Paused,
             gain: 0.0,
+            crossfade_frames: 0,
+            crossfade: None,
+            mix_buffer: vec![0.0; MIX_BUFFER_FRAMES * num_channels],
+            dsp_chain: DspChain::new(sample_rate, num_channels),
+            fade_frames: 0,
+            fade_gain: 1.0,
+            fade_target: 1.0,
+            pending_transition: None,
             fatal_error: false,
+            analysis,
         }
     }
 
@@ -50,133 +118,481 @@ impl Process {
 
         if let Err(e) = self.try_process(data) {
             error!("{:?}", e);
+            if let Some(playback_id) = self.stream.as_ref().map(|stream| stream.playback_id) {
+                self.drop_failed_stream(playback_id, (&e).into());
+            }
             silence(data);
         }
+        self.hand_back_dsp_nodes();
+    }
+
+    /// Sends nodes that left the DSP chain back to the manager, so that they aren't deallocated
+    /// on the audio thread. Ones that don't fit in the ring stay in the chain until the next
+    /// callback.
+    fn hand_back_dsp_nodes(&mut self) {
+        while let Some(node) = self.dsp_chain.take_removed() {
+            if let Err(PushError::Full(ProcessToManagerMsg::DspNodeRemoved(node))) = self
+                .to_gui_tx
+                .push(ProcessToManagerMsg::DspNodeRemoved(node))
+            {
+                self.dsp_chain.push_removed(node);
+                break;
+            }
+        }
+    }
+
+    /// Stops playback of a stream that failed, leaving it to the manager whether to retry it,
+    /// skip it or stop. The enqueued stream is dropped as well, so that it doesn't start by
+    /// itself.
+    fn drop_failed_stream(&mut self, playback_id: u64, kind: PlayerErrorKind) {
+        let _ = self
+            .to_gui_tx
+            .push(ProcessToManagerMsg::StreamError(playback_id, kind));
+        self.stream = None;
+        self.next_stream = None;
+        self.crossfade = None;
+        self.playback_state = ProcessPlaybackState::Paused;
     }
 
-    fn try_process(&mut self, mut data: &mut [f32]) -> symphonia::core::errors::Result<()> {
-        while let Ok(msg) = self.from_gui_rx.pop() {
-            match msg {
-                ManagerToProcessMsg::StartPlayback(
+    fn handle_message(&mut self, msg: ManagerToProcessMsg) {
+        match msg {
+            ManagerToProcessMsg::StartPlayback(
+                playback_id,
+                file_stream,
+                replay_gain,
+                start_playback_state,
+            ) => {
+                let channel_mix = self.channel_mix(&file_stream);
+                self.stream = Some(Stream {
+                    file_stream,
                     playback_id,
+                    replay_gain,
+                    channel_mix,
+                });
+                self.crossfade = None;
+                self.dsp_chain.reset();
+                // A stream that starts from its first frame doesn't need to be faded in, but one
+                // that starts in the middle does. One that starts paused fades in when it's
+                // resumed, like any other paused stream.
+                self.playback_state = match start_playback_state {
+                    StartPlaybackState::Playing { fade_in: true } => {
+                        self.fade_gain = 0.0;
+                        self.fade_in();
+                        ProcessPlaybackState::Playing
+                    }
+                    StartPlaybackState::Playing { fade_in: false } => {
+                        self.fade_gain = 1.0;
+                        self.fade_target = 1.0;
+                        ProcessPlaybackState::Playing
+                    }
+                    StartPlaybackState::Paused => {
+                        self.fade_gain = 0.0;
+                        self.fade_target = 0.0;
+                        ProcessPlaybackState::Paused
+                    }
+                };
+            }
+            ManagerToProcessMsg::EnqueueNext(playback_id, file_stream, replay_gain) => {
+                let channel_mix = self.channel_mix(&file_stream);
+                self.next_stream = Some(Stream {
                     file_stream,
-                    start_playback_state,
-                ) => {
-                    self.stream = Some(Stream {
-                        file_stream,
-                        playback_id,
-                    });
-                    self.playback_state = match start_playback_state {
-                        StartPlaybackState::Playing => ProcessPlaybackState::Playing,
-                        StartPlaybackState::Paused => ProcessPlaybackState::Paused,
-                    };
-                }
-                ManagerToProcessMsg::Stop => {
-                    self.stream = None;
-                    self.playback_state = ProcessPlaybackState::Paused;
-                }
-                ManagerToProcessMsg::Pause => {
-                    self.playback_state = ProcessPlaybackState::Paused;
+                    playback_id,
+                    replay_gain,
+                    channel_mix,
+                });
+            }
+            ManagerToProcessMsg::SetReplayGain(playback_id, replay_gain) => {
+                for stream in [&mut self.stream, &mut self.next_stream]
+                    .into_iter()
+                    .flatten()
+                {
+                    if stream.playback_id == playback_id {
+                        stream.replay_gain = replay_gain;
+                    }
                 }
-                ManagerToProcessMsg::Resume => {
+            }
+            ManagerToProcessMsg::ClearNext => {
+                self.next_stream = None;
+                self.crossfade = None;
+            }
+            ManagerToProcessMsg::Stop => {
+                self.begin_transition(Transition::Stop);
+            }
+            ManagerToProcessMsg::Pause => {
+                self.begin_transition(Transition::Pause);
+            }
+            ManagerToProcessMsg::Resume => {
+                if self.playback_state == ProcessPlaybackState::Paused {
                     self.playback_state = ProcessPlaybackState::Playing;
+                    self.fade_in();
                 }
-                ManagerToProcessMsg::SeekTo(pos) => {
-                    if let Some(Stream {
-                        file_stream,
-                        playback_id,
-                    }) = &mut self.stream
-                    {
-                        file_stream.seek(pos);
-                        let _ = self.to_gui_tx.push(ProcessToManagerMsg::PlaybackPos(
-                            *playback_id,
-                            file_stream.playhead(),
-                        ));
-                    }
+            }
+            ManagerToProcessMsg::SeekTo(pos) => {
+                self.begin_transition(Transition::SeekTo(pos));
+            }
+            ManagerToProcessMsg::SetLoop(playback_id, loop_region) => {
+                let start = self
+                    .stream
+                    .as_mut()
+                    .filter(|stream| stream.playback_id == playback_id)
+                    .and_then(|stream| stream.file_stream.set_loop(loop_region));
+                if let Some(start) = start {
+                    // Already past the end of the region, so jump back to its start
+                    self.begin_transition(Transition::SeekTo(start));
                 }
-                ManagerToProcessMsg::SetGain(gain) => {
-                    self.gain = gain;
+            }
+            ManagerToProcessMsg::SetGain(gain) => {
+                self.gain = gain;
+            }
+            ManagerToProcessMsg::SetDownmixLfe(downmix_lfe) => {
+                self.downmix_lfe = downmix_lfe;
+                for stream in [&mut self.stream, &mut self.next_stream]
+                    .into_iter()
+                    .flatten()
+                {
+                    stream.channel_mix = ChannelMix::new(
+                        stream.file_stream.channel_layout(),
+                        self.num_channels,
+                        downmix_lfe,
+                    );
                 }
             }
+            ManagerToProcessMsg::SetFade(fade_frames) => {
+                self.fade_frames = fade_frames;
+            }
+            ManagerToProcessMsg::SetCrossfade(crossfade_frames) => {
+                self.crossfade_frames = crossfade_frames;
+            }
+            ManagerToProcessMsg::InsertDspNode(index, id, node) => {
+                if let Err(node) = self.dsp_chain.insert(index, id, node) {
+                    error!("Failed to insert DSP node as the chain is full");
+                    self.dsp_chain.push_removed(node);
+                }
+            }
+            ManagerToProcessMsg::RemoveDspNode(id) => {
+                self.dsp_chain.remove(id);
+            }
+            ManagerToProcessMsg::MoveDspNode(id, index) => {
+                self.dsp_chain.move_node(id, index);
+            }
+            ManagerToProcessMsg::SetDspNodeParams(id, params) => {
+                self.dsp_chain.set_params(id, &params);
+            }
         }
+    }
 
-        let mut reached_end_of_file = false;
+    fn channel_mix(&self, file_stream: &FileStream) -> ChannelMix {
+        ChannelMix::new(
+            file_stream.channel_layout(),
+            self.num_channels,
+            self.downmix_lfe,
+        )
+    }
 
-        if self.playback_state == ProcessPlaybackState::Paused {
-            silence(data);
-        } else if let Some(Stream {
+    /// Starts fading out for a transition, or applies it right away if there is nothing audible to
+    /// fade out.
+    fn begin_transition(&mut self, transition: Transition) {
+        let is_audible = self.playback_state == ProcessPlaybackState::Playing
+            && self.stream.is_some()
+            && self.fade_gain > 0.0;
+        if is_audible && self.fade_frames > 0 {
+            self.fade_target = 0.0;
+            self.pending_transition = Some(transition);
+        } else {
+            self.fade_gain = 0.0;
+            self.complete_transition(transition);
+        }
+    }
+
+    fn complete_transition(&mut self, transition: Transition) {
+        self.pending_transition = None;
+        match transition {
+            Transition::Pause => {
+                self.playback_state = ProcessPlaybackState::Paused;
+            }
+            Transition::Stop => {
+                self.stream = None;
+                self.next_stream = None;
+                self.crossfade = None;
+                self.playback_state = ProcessPlaybackState::Paused;
+            }
+            Transition::SeekTo(pos) => {
+                self.seek_to(pos);
+            }
+        }
+    }
+
+    fn seek_to(&mut self, pos: usize) {
+        if let Some(Stream {
             file_stream,
             playback_id,
+            ..
         }) = &mut self.stream
         {
-            while !data.is_empty() {
-                if !file_stream.is_ready() {
-                    // Buffering...
-                    break;
+            if self.crossfade.take().is_some() {
+                if let Some(next_stream) = self.next_stream.as_mut() {
+                    next_stream.file_stream.seek(0);
+                }
+            }
+            file_stream.seek(pos);
+            self.dsp_chain.reset();
+            let _ = self.to_gui_tx.push(ProcessToManagerMsg::PlaybackPos(
+                *playback_id,
+                file_stream.playhead(),
+            ));
+        }
+        if self.playback_state == ProcessPlaybackState::Playing {
+            self.fade_in();
+        }
+    }
+
+    /// Fades in from silence, or jumps straight to full gain if fades are disabled.
+    fn fade_in(&mut self) {
+        self.fade_target = 1.0;
+        if self.fade_frames == 0 {
+            self.fade_gain = 1.0;
+        }
+    }
+
+    /// Applies the fade to the frames that were written to `data`, moving the fade gain towards its
+    /// target one frame at a time.
+    fn apply_fade(&mut self, data: &mut [f32]) {
+        if self.fade_gain == self.fade_target {
+            if self.fade_gain != 1.0 {
+                for sample in data.iter_mut() {
+                    *sample *= self.fade_gain;
                 }
+            }
+            return;
+        }
+        let step = 1.0 / self.fade_frames.max(1) as f32;
+        for frame in data.chunks_exact_mut(self.num_channels) {
+            self.fade_gain = if self.fade_target > self.fade_gain {
+                (self.fade_gain + step).min(self.fade_target)
+            } else {
+                (self.fade_gain - step).max(self.fade_target)
+            };
+            for sample in frame.iter_mut() {
+                *sample *= self.fade_gain;
+            }
+        }
+    }
 
-                let read_frames = data.len() / 2;
-                let read_data = file_stream
-                    .read(read_frames)
-                    .expect("Expected there to be available data to read");
-                let chunk_frames = read_data.num_frames();
+    fn try_process(&mut self, data: &mut [f32]) -> symphonia::core::errors::Result<()> {
+        // Messages that follow a transition have to wait until it has faded out
+        while self.pending_transition.is_none() {
+            let Ok(msg) = self.from_gui_rx.pop() else {
+                break;
+            };
+            self.handle_message(msg);
+        }
 
-                if read_data.num_channels() == 1 {
-                    let ch = read_data.read_channel(0);
+        if self.playback_state == ProcessPlaybackState::Paused {
+            silence(data);
+            // Keeps the meters going so that they fall back to silence
+            self.analysis.process(data);
+            return Ok(());
+        }
 
-                    for i in 0..chunk_frames {
-                        data[i * 2] = ch[i];
-                        data[i * 2 + 1] = ch[i];
-                    }
-                } else if read_data.num_channels() == 2 {
-                    let ch1 = read_data.read_channel(0);
-                    let ch2 = read_data.read_channel(1);
+        let num_channels = self.num_channels;
+        let mut written_frames = 0;
+        let total_frames = data.len() / num_channels;
 
-                    for i in 0..chunk_frames {
-                        data[i * 2] = ch1[i];
-                        data[i * 2 + 1] = ch2[i];
+        while let Some(Stream {
+            file_stream,
+            playback_id,
+            replay_gain,
+            channel_mix,
+        }) = &mut self.stream
+        {
+            if self.crossfade.is_none() && self.crossfade_frames > 0 && self.next_stream.is_some() {
+                if let Some(remaining_frames) = file_stream.remaining_frames() {
+                    if remaining_frames <= self.crossfade_frames {
+                        self.crossfade = Some(Crossfade {
+                            pos: 0,
+                            len: remaining_frames.max(1),
+                        });
                     }
                 }
+            }
 
-                for sample in &mut data[0..chunk_frames * 2] {
-                    *sample *= self.gain;
-                }
+            let mut filled_mix_buffer = false;
+            let reached_end_of_file = if let Some(crossfade) = self.crossfade.as_mut() {
+                let mix_frames = (total_frames - written_frames).min(MIX_BUFFER_FRAMES);
+                let out = &mut data
+                    [written_frames * num_channels..(written_frames + mix_frames) * num_channels];
+                let (out_frames, reached_end_of_file) =
+                    render_stream(file_stream, channel_mix, out, *replay_gain);
 
-                data = &mut data[chunk_frames * 2..];
+                let mix = &mut self.mix_buffer[0..out_frames * num_channels];
+                let in_frames = match self.next_stream.as_mut() {
+                    Some(next_stream) => {
+                        render_stream(
+                            &mut next_stream.file_stream,
+                            &next_stream.channel_mix,
+                            mix,
+                            next_stream.replay_gain,
+                        )
+                        .0
+                    }
+                    None => 0,
+                };
+                silence(&mut mix[in_frames * num_channels..]);
 
-                if read_data.reached_end_of_file() {
-                    reached_end_of_file = true;
-                    break;
+                for (i, (out_frame, mix_frame)) in out
+                    .chunks_exact_mut(num_channels)
+                    .zip(mix.chunks_exact(num_channels))
+                    .enumerate()
+                {
+                    let (out_gain, in_gain) = crossfade.gains(i);
+                    for (out_sample, mix_sample) in out_frame.iter_mut().zip(mix_frame) {
+                        *out_sample = *out_sample * out_gain + mix_sample * in_gain;
+                    }
                 }
-            }
+                crossfade.pos += out_frames;
+                written_frames += out_frames;
+                filled_mix_buffer = out_frames == MIX_BUFFER_FRAMES;
 
-            // Fill silence if we have reached the end of the stream
-            silence(data);
+                reached_end_of_file
+            } else {
+                let (frames, reached_end_of_file) = render_stream(
+                    file_stream,
+                    channel_mix,
+                    &mut data[written_frames * num_channels..],
+                    *replay_gain,
+                );
+                written_frames += frames;
+                reached_end_of_file
+            };
 
             let _ = self.to_gui_tx.push(ProcessToManagerMsg::PlaybackPos(
                 *playback_id,
                 file_stream.playhead(),
             ));
-            if reached_end_of_file {
+
+            if !reached_end_of_file {
+                if filled_mix_buffer && written_frames < total_frames {
+                    // The scratch buffer was filled but there is more to mix
+                    continue;
+                }
+                break;
+            }
+
+            let ended_playback_id = *playback_id;
+            if let Some(kind) = file_stream.error() {
+                self.drop_failed_stream(ended_playback_id, kind);
+                break;
+            }
+            self.crossfade = None;
+            if let Some(next_stream) = self.next_stream.take() {
+                // Continue with the next stream in the same buffer to avoid a gap between tracks
+                let _ = self.to_gui_tx.push(ProcessToManagerMsg::PlaybackAdvanced(
+                    ended_playback_id,
+                    next_stream.playback_id,
+                ));
+                self.stream = Some(next_stream);
+            } else {
                 let _ = self
                     .to_gui_tx
-                    .push(ProcessToManagerMsg::PlaybackEnded(*playback_id));
+                    .push(ProcessToManagerMsg::PlaybackEnded(ended_playback_id));
+                self.stream = None;
+                self.playback_state = ProcessPlaybackState::Paused;
             }
-        } else {
-            silence(data);
         }
 
-        if reached_end_of_file {
-            self.stream = None;
-            self.playback_state = ProcessPlaybackState::Paused;
+        // Fill silence if we have reached the end of the stream or are buffering
+        silence(&mut data[written_frames * num_channels..]);
+
+        let output = &mut data[0..written_frames * num_channels];
+        self.dsp_chain.process(output);
+        self.apply_fade(output);
+        for sample in output.iter_mut() {
+            *sample *= self.gain;
         }
 
-        // TODO: Fade in/out audio when buffering?
+        if written_frames < total_frames {
+            let is_underrun = self
+                .stream
+                .as_ref()
+                .map_or(false, |stream| stream.file_stream.playhead() > 0);
+            if is_underrun {
+                // Ramp down what we have and fade back in once there is more to play
+                fade_out_tail(output, num_channels, self.fade_frames);
+                self.fade_gain = 0.0;
+            }
+            if let Some(transition) = self.pending_transition {
+                // The output already ran out, so there is nothing left to fade
+                self.complete_transition(transition);
+            }
+        } else if self.fade_gain == 0.0 {
+            if let Some(transition) = self.pending_transition {
+                self.complete_transition(transition);
+            }
+        }
+
+        self.analysis.process(data);
 
         Ok(())
     }
 }
 
+/// Reads from `file_stream` into the interleaved `data` buffer, mixed to the output channels by
+/// `channel_mix`, until it is full, the stream is buffering or the end of the file is reached.
+/// Returns the number of frames written and whether the end of the file was reached.
+fn render_stream(
+    file_stream: &mut FileStream,
+    channel_mix: &ChannelMix,
+    data: &mut [f32],
+    gain: f32,
+) -> (usize, bool) {
+    let num_channels = channel_mix.num_output_channels();
+    let mut written_frames = 0;
+    let total_frames = data.len() / num_channels;
+
+    while written_frames < total_frames {
+        if !file_stream.is_ready() {
+            if file_stream.error().is_some() {
+                // The decode worker is gone, so this would otherwise buffer forever
+                return (written_frames, true);
+            }
+            // Buffering...
+            break;
+        }
+
+        let read_data = file_stream
+            .read(total_frames - written_frames)
+            .expect("Expected there to be available data to read");
+        let out = &mut data[written_frames * num_channels..];
+        let chunk_frames = channel_mix.apply(&read_data, out);
+        for sample in &mut out[0..chunk_frames * num_channels] {
+            *sample *= gain;
+        }
+        written_frames += chunk_frames;
+
+        if read_data.reached_end_of_file() {
+            return (written_frames, true);
+        }
+    }
+
+    (written_frames, false)
+}
+
+/// Ramps the last `fade_frames` frames of the interleaved `data` buffer down to silence.
+fn fade_out_tail(data: &mut [f32], num_channels: usize, fade_frames: usize) {
+    let num_frames = data.len() / num_channels;
+    let fade_frames = fade_frames.min(num_frames);
+    let start = num_frames - fade_frames;
+    for (i, frame) in data[start * num_channels..]
+        .chunks_exact_mut(num_channels)
+        .enumerate()
+    {
+        let gain = (fade_frames - i) as f32 / (fade_frames + 1) as f32;
+        for sample in frame.iter_mut() {
+            *sample *= gain;
+        }
+    }
+}
+
 fn silence(data: &mut [f32]) {
     for sample in data.iter_mut() {
         *sample = 0.0;
@@ -1,6 +1,7 @@
 use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct Queue<T> {
     elements: Vec<T>,
     ordering: Vec<usize>,
@@ -83,6 +84,18 @@ impl<T> Queue<T> {
         }
     }
 
+    /// Whether the queue is non-empty and its play order and index point at its elements. Queues
+    /// built with the methods here always are, but deserialized ones don't have to be.
+    pub fn is_valid(&self) -> bool {
+        let mut seen = vec![false; self.elements.len()];
+        let is_permutation = self.ordering.len() == self.elements.len()
+            && self
+                .ordering
+                .iter()
+                .all(|&i| i < seen.len() && !std::mem::replace(&mut seen[i], true));
+        is_permutation && self.index < self.ordering.len()
+    }
+
     pub fn has_previous(&self) -> bool {
         self.index > 0
     }
@@ -103,10 +116,347 @@ impl<T> Queue<T> {
         }
     }
 
+    /// Returns the element that `go_next` would move to, without advancing the queue.
+    pub fn peek_next(&self, mode: GoNextMode) -> Option<&T> {
+        if mode == GoNextMode::RepeatAll {
+            let index = (self.index + 1) % self.elements.len();
+            Some(&self.elements[self.ordering[index]])
+        } else if self.index + 1 < self.elements.len() {
+            Some(&self.elements[self.ordering[self.index + 1]])
+        } else {
+            None
+        }
+    }
+
     pub fn go_previous_clamped(&mut self) -> &T {
         if self.index > 0 {
             self.index -= 1;
         }
         self.current()
     }
+
+    /// Elements that were played before the current one, in play order.
+    pub fn history(&self) -> impl Iterator<Item = &T> {
+        self.ordering[0..self.index]
+            .iter()
+            .map(|&i| &self.elements[i])
+    }
+
+    /// Elements that come after the current one, in play order.
+    pub fn upcoming(&self) -> impl Iterator<Item = &T> {
+        self.ordering[self.index + 1..]
+            .iter()
+            .map(|&i| &self.elements[i])
+    }
+
+    /// Inserts `items` at `position` in the play order and at `element_index` in the unshuffled
+    /// order, so that they are still in the expected place if shuffle is turned off.
+    fn insert(&mut self, position: usize, element_index: usize, items: Vec<T>) {
+        let count = items.len();
+        for i in self.ordering.iter_mut() {
+            if *i >= element_index {
+                *i += count;
+            }
+        }
+        self.elements.splice(element_index..element_index, items);
+        self.ordering
+            .splice(position..position, element_index..element_index + count);
+    }
+
+    /// Inserts `items` to be played right after the current element.
+    pub fn insert_next(&mut self, items: Vec<T>) {
+        self.insert(self.index + 1, self.ordering[self.index] + 1, items);
+    }
+
+    /// Appends `items` to the end of the queue.
+    pub fn push_back(&mut self, items: Vec<T>) {
+        self.insert(self.ordering.len(), self.elements.len(), items);
+    }
+
+    /// Removes the elements at the play order positions for which `remove` returns true, except
+    /// for the current element.
+    fn remove_where<F: FnMut(usize) -> bool>(&mut self, mut remove: F) {
+        let removed_positions: Vec<bool> = (0..self.ordering.len())
+            .map(|position| position != self.index && remove(position))
+            .collect();
+        let mut removed_elements = vec![false; self.elements.len()];
+        for (&removed, &i) in removed_positions.iter().zip(self.ordering.iter()) {
+            removed_elements[i] = removed;
+        }
+
+        // Indices of the remaining elements once the removed ones are gone
+        let mut new_indices = Vec::with_capacity(self.elements.len());
+        let mut next_index = 0;
+        for &removed in removed_elements.iter() {
+            new_indices.push(next_index);
+            if !removed {
+                next_index += 1;
+            }
+        }
+
+        let mut removed = removed_elements.iter();
+        self.elements.retain(|_| !removed.next().unwrap());
+        self.index -= removed_positions[0..self.index]
+            .iter()
+            .filter(|&&removed| removed)
+            .count();
+        self.ordering = self
+            .ordering
+            .iter()
+            .zip(removed_positions.iter())
+            .filter(|(_, &removed)| !removed)
+            .map(|(&i, _)| new_indices[i])
+            .collect();
+    }
+
+    /// Keeps only the elements for which `keep` returns true. If the current element is removed,
+    /// the next remaining one becomes current, or the last one if there is none after it. Returns
+    /// the queue along with whether the current element was kept, or `None` if nothing is left.
+    pub fn retain<F: FnMut(&T) -> bool>(mut self, mut keep: F) -> Option<(Queue<T>, bool)> {
+        let kept: Vec<bool> = self
+            .ordering
+            .iter()
+            .map(|&i| keep(&self.elements[i]))
+            .collect();
+        let is_current_kept = kept[self.index];
+        self.index = (self.index..kept.len())
+            .chain((0..self.index).rev())
+            .find(|&position| kept[position])?;
+        self.remove_where(|position| !kept[position]);
+        Some((self, is_current_kept))
+    }
+
+    /// Removes the element at `position` in the play order. The current element can't be
+    /// removed.
+    pub fn remove(&mut self, position: usize) -> bool {
+        if position >= self.ordering.len() || position == self.index {
+            return false;
+        }
+        self.remove_where(|i| i == position);
+        true
+    }
+
+    /// Removes everything after the current element.
+    pub fn clear_upcoming(&mut self) {
+        let index = self.index;
+        self.remove_where(|i| i > index);
+    }
+
+    /// Moves the element at `from` to `to` in the play order.
+    pub fn move_item(&mut self, from: usize, to: usize) -> bool {
+        let len = self.ordering.len();
+        if from >= len || to >= len {
+            return false;
+        }
+        if self.is_shuffled {
+            let i = self.ordering.remove(from);
+            self.ordering.insert(to, i);
+        } else {
+            // The unshuffled order is the play order
+            let element = self.elements.remove(from);
+            self.elements.insert(to, element);
+        }
+
+        if from == self.index {
+            self.index = to;
+        } else if from < self.index && to >= self.index {
+            self.index -= 1;
+        } else if from > self.index && to <= self.index {
+            self.index += 1;
+        }
+        true
+    }
+
+    /// Makes the element at `position` in the play order the current one.
+    pub fn jump_to(&mut self, position: usize) -> Option<&T> {
+        if position < self.ordering.len() {
+            self.index = position;
+            Some(self.current())
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ELEMENTS: [&str; 5] = ["a", "b", "c", "d", "e"];
+
+    /// Plays a, b, c, d, e with c as the current element.
+    fn unshuffled() -> Queue<&'static str> {
+        Queue::from_iter(ELEMENTS, 2).unwrap()
+    }
+
+    /// Plays c, a, e, b, d with e as the current element.
+    fn shuffled() -> Queue<&'static str> {
+        Queue {
+            elements: ELEMENTS.to_vec(),
+            ordering: vec![2, 0, 4, 1, 3],
+            index: 2,
+            is_shuffled: true,
+        }
+    }
+
+    /// Checks that the queue is valid and has the given history, current element and upcoming
+    /// elements.
+    fn assert_queue(
+        queue: &Queue<&'static str>,
+        history: &[&str],
+        current: &str,
+        upcoming: &[&str],
+    ) {
+        assert!(queue.is_valid());
+        assert_eq!(queue.history().copied().collect::<Vec<_>>(), history);
+        assert_eq!(*queue.current(), current);
+        assert_eq!(queue.upcoming().copied().collect::<Vec<_>>(), upcoming);
+    }
+
+    #[test]
+    fn remove_before_current() {
+        let mut queue = unshuffled();
+        assert!(queue.remove(0));
+        assert_queue(&queue, &["b"], "c", &["d", "e"]);
+
+        let mut queue = shuffled();
+        assert!(queue.remove(1));
+        assert_queue(&queue, &["c"], "e", &["b", "d"]);
+        assert_queue(&queue.to_unshuffled(), &["b", "c", "d"], "e", &[]);
+    }
+
+    #[test]
+    fn remove_current_is_refused() {
+        let mut queue = unshuffled();
+        assert!(!queue.remove(2));
+        assert_queue(&queue, &["a", "b"], "c", &["d", "e"]);
+
+        let mut queue = shuffled();
+        assert!(!queue.remove(2));
+        assert_queue(&queue, &["c", "a"], "e", &["b", "d"]);
+    }
+
+    #[test]
+    fn remove_after_current() {
+        let mut queue = unshuffled();
+        assert!(queue.remove(4));
+        assert_queue(&queue, &["a", "b"], "c", &["d"]);
+
+        let mut queue = shuffled();
+        assert!(queue.remove(3));
+        assert_queue(&queue, &["c", "a"], "e", &["d"]);
+        assert_queue(&queue.to_unshuffled(), &["a", "c", "d"], "e", &[]);
+    }
+
+    #[test]
+    fn remove_out_of_bounds_is_refused() {
+        let mut queue = shuffled();
+        assert!(!queue.remove(5));
+        assert_queue(&queue, &["c", "a"], "e", &["b", "d"]);
+    }
+
+    #[test]
+    fn move_across_current() {
+        let mut queue = unshuffled();
+        assert!(queue.move_item(0, 3));
+        assert_queue(&queue, &["b"], "c", &["d", "a", "e"]);
+        assert!(queue.move_item(4, 0));
+        assert_queue(&queue, &["e", "b"], "c", &["d", "a"]);
+
+        let mut queue = shuffled();
+        assert!(queue.move_item(4, 1));
+        assert_queue(&queue, &["c", "d", "a"], "e", &["b"]);
+        assert!(queue.move_item(0, 4));
+        assert_queue(&queue, &["d", "a"], "e", &["b", "c"]);
+    }
+
+    #[test]
+    fn move_current() {
+        let mut queue = unshuffled();
+        assert!(queue.move_item(2, 0));
+        assert_queue(&queue, &[], "c", &["a", "b", "d", "e"]);
+
+        let mut queue = shuffled();
+        assert!(queue.move_item(2, 4));
+        assert_queue(&queue, &["c", "a", "b", "d"], "e", &[]);
+        assert!(!queue.move_item(4, 5));
+    }
+
+    #[test]
+    fn insert_next() {
+        let mut queue = unshuffled();
+        queue.insert_next(vec!["x", "y"]);
+        assert_queue(&queue, &["a", "b"], "c", &["x", "y", "d", "e"]);
+
+        let mut queue = shuffled();
+        queue.insert_next(vec!["x", "y"]);
+        assert_queue(&queue, &["c", "a"], "e", &["x", "y", "b", "d"]);
+        // The inserted elements follow the current one when shuffle is turned off as well
+        assert_queue(
+            &queue.to_unshuffled(),
+            &["a", "b", "c", "d"],
+            "e",
+            &["x", "y"],
+        );
+    }
+
+    #[test]
+    fn push_back() {
+        let mut queue = shuffled();
+        queue.push_back(vec!["x"]);
+        assert_queue(&queue, &["c", "a"], "e", &["b", "d", "x"]);
+        assert_queue(&queue.to_unshuffled(), &["a", "b", "c", "d"], "e", &["x"]);
+    }
+
+    #[test]
+    fn retain_dropping_current() {
+        let (queue, is_current_kept) = unshuffled().retain(|&element| element != "c").unwrap();
+        assert!(!is_current_kept);
+        assert_queue(&queue, &["a", "b"], "d", &["e"]);
+
+        let (queue, is_current_kept) = shuffled()
+            .retain(|&element| element != "a" && element != "e")
+            .unwrap();
+        assert!(!is_current_kept);
+        assert_queue(&queue, &["c"], "b", &["d"]);
+    }
+
+    #[test]
+    fn retain_dropping_current_and_everything_after() {
+        let (queue, is_current_kept) = shuffled()
+            .retain(|&element| element == "a" || element == "c")
+            .unwrap();
+        assert!(!is_current_kept);
+        assert_queue(&queue, &["c"], "a", &[]);
+    }
+
+    #[test]
+    fn retain_keeping_current() {
+        let (queue, is_current_kept) = shuffled().retain(|&element| element != "b").unwrap();
+        assert!(is_current_kept);
+        assert_queue(&queue, &["c", "a"], "e", &["d"]);
+    }
+
+    #[test]
+    fn retain_nothing() {
+        assert!(shuffled().retain(|_| false).is_none());
+    }
+
+    #[test]
+    fn jump_to() {
+        let mut queue = shuffled();
+        assert_eq!(queue.jump_to(4), Some(&"d"));
+        assert_queue(&queue, &["c", "a", "e", "b"], "d", &[]);
+        assert_eq!(queue.jump_to(5), None);
+        assert_eq!(queue.jump_to(0), Some(&"c"));
+        assert_queue(&queue, &[], "c", &["a", "e", "b", "d"]);
+    }
+
+    #[test]
+    fn clear_upcoming() {
+        let mut queue = shuffled();
+        queue.clear_upcoming();
+        assert_queue(&queue, &["c", "a"], "e", &[]);
+        assert_queue(&queue.to_unshuffled(), &["a", "c"], "e", &[]);
+    }
 }
@@ -0,0 +1,135 @@
+use serde::{Deserialize, Serialize};
+use symphonia::core::meta::{MetadataRevision, StandardTagKey, Tag, Value};
+
+/// Loudness that ReplayGain 2.0 gains normalize to.
+pub const REFERENCE_LOUDNESS_LUFS: f32 = -18.0;
+/// Reference level of R128 gain tags.
+const R128_REFERENCE_LOUDNESS_LUFS: f32 = -23.0;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum ReplayGainMode {
+    Off,
+    Track,
+    Album,
+    /// Track gain when shuffle is enabled, album gain otherwise.
+    Auto,
+}
+
+/// Gain and peak values read from the tags of a file. Gains are in dB relative to the ReplayGain
+/// reference level, and peaks are linear sample amplitudes.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ReplayGainTags {
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+impl ReplayGainTags {
+    pub fn from_metadata(metadata: &MetadataRevision) -> ReplayGainTags {
+        let mut tags = ReplayGainTags::default();
+        for tag in metadata.tags() {
+            match tag.std_key {
+                Some(StandardTagKey::ReplayGainTrackGain) => {
+                    tags.track_gain = parse_gain(&tag.value);
+                }
+                Some(StandardTagKey::ReplayGainTrackPeak) => {
+                    tags.track_peak = parse_number(&tag.value);
+                }
+                Some(StandardTagKey::ReplayGainAlbumGain) => {
+                    tags.album_gain = parse_gain(&tag.value);
+                }
+                Some(StandardTagKey::ReplayGainAlbumPeak) => {
+                    tags.album_peak = parse_number(&tag.value);
+                }
+                _ => {
+                    // R128 tags are only used when there are no ReplayGain tags for the same value
+                    if is_key(tag, "R128_TRACK_GAIN") && tags.track_gain.is_none() {
+                        tags.track_gain = parse_r128_gain(&tag.value);
+                    } else if is_key(tag, "R128_ALBUM_GAIN") && tags.album_gain.is_none() {
+                        tags.album_gain = parse_r128_gain(&tag.value);
+                    }
+                }
+            }
+        }
+        tags
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.track_gain.is_none() && self.album_gain.is_none()
+    }
+
+    /// Linear gain factor to apply for `mode`, which should already be resolved to either
+    /// `ReplayGainMode::Track` or `ReplayGainMode::Album`. `preamp_db` is used for files that
+    /// don't have any gain tags.
+    pub fn gain_factor(&self, mode: ReplayGainMode, preamp_db: f32) -> f32 {
+        let (gain, peak) = match mode {
+            ReplayGainMode::Off => return 1.0,
+            ReplayGainMode::Album => (
+                self.album_gain.or(self.track_gain),
+                self.album_peak.or(self.track_peak),
+            ),
+            ReplayGainMode::Track | ReplayGainMode::Auto => (
+                self.track_gain.or(self.album_gain),
+                self.track_peak.or(self.album_peak),
+            ),
+        };
+        match gain {
+            Some(gain) => {
+                let factor = db_to_gain(gain);
+                // Prevent clipping by never amplifying the peak above full scale
+                match peak {
+                    Some(peak) if peak > 0.0 => factor.min(1.0 / peak),
+                    _ => factor,
+                }
+            }
+            None => db_to_gain(preamp_db),
+        }
+    }
+}
+
+pub fn db_to_gain(db: f32) -> f32 {
+    10_f32.powf(db / 20.0)
+}
+
+fn is_key(tag: &Tag, key: &str) -> bool {
+    // Formats like ID3v2 may prefix user defined keys, e.g. "TXXX:R128_TRACK_GAIN"
+    tag.key
+        .rsplit(':')
+        .next()
+        .map_or(false, |suffix| suffix.eq_ignore_ascii_case(key))
+}
+
+fn parse_number(value: &Value) -> Option<f32> {
+    match value {
+        Value::Float(f) => Some(*f as f32),
+        Value::SignedInt(i) => Some(*i as f32),
+        Value::UnsignedInt(u) => Some(*u as f32),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+/// Parses gains like "-6.54 dB".
+fn parse_gain(value: &Value) -> Option<f32> {
+    if let Value::String(s) = value {
+        let s = s.trim();
+        let s = match s
+            .len()
+            .checked_sub(2)
+            .and_then(|i| s.get(i..).map(|unit| (i, unit)))
+        {
+            Some((i, unit)) if unit.eq_ignore_ascii_case("db") => &s[..i],
+            _ => s,
+        };
+        s.trim().parse().ok()
+    } else {
+        parse_number(value)
+    }
+}
+
+/// Parses R128 gains, which are Q7.8 fixed point integers relative to -23 LUFS.
+fn parse_r128_gain(value: &Value) -> Option<f32> {
+    parse_number(value)
+        .map(|q78| q78 / 256.0 + REFERENCE_LOUDNESS_LUFS - R128_REFERENCE_LOUDNESS_LUFS)
+}
@@ -0,0 +1,106 @@
+use rubato::{
+    calculate_cutoff, FastFixedIn, FftFixedIn, PolynomialDegree, ResampleResult, Resampler,
+    ResamplerConstructionError, SincFixedIn, SincInterpolationParameters, SincInterpolationType,
+    WindowFunction,
+};
+use serde::{Deserialize, Serialize};
+
+/// The algorithm used to convert streams to the output sample rate.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub enum ResamplerKind {
+    /// Linear interpolation, which is cheap but lets through some aliasing.
+    Linear,
+    /// FFT based resampling, which is fast and accurate for the fixed ratios we need.
+    #[default]
+    Fft,
+    SincLow,
+    SincMedium,
+    SincHigh,
+}
+
+/// The parts of `rubato::Resampler` that the decode worker uses, in a form that can be boxed.
+pub trait BlockResampler: Send {
+    fn process_into_buffer(
+        &mut self,
+        input: &[Vec<f32>],
+        output: &mut [Vec<f32>],
+    ) -> ResampleResult<(usize, usize)>;
+
+    fn output_buffer_allocate(&self, filled: bool) -> Vec<Vec<f32>>;
+
+    fn reset(&mut self);
+}
+
+impl<R: Resampler<f32>> BlockResampler for R {
+    fn process_into_buffer(
+        &mut self,
+        input: &[Vec<f32>],
+        output: &mut [Vec<f32>],
+    ) -> ResampleResult<(usize, usize)> {
+        Resampler::process_into_buffer(self, input, output, None)
+    }
+
+    fn output_buffer_allocate(&self, filled: bool) -> Vec<Vec<f32>> {
+        Resampler::output_buffer_allocate(self, filled)
+    }
+
+    fn reset(&mut self) {
+        Resampler::reset(self)
+    }
+}
+
+impl ResamplerKind {
+    fn sinc_parameters(
+        sinc_len: usize,
+        interpolation: SincInterpolationType,
+    ) -> SincInterpolationParameters {
+        let window = WindowFunction::BlackmanHarris2;
+        SincInterpolationParameters {
+            sinc_len,
+            f_cutoff: calculate_cutoff(sinc_len, window),
+            oversampling_factor: 256,
+            interpolation,
+            window,
+        }
+    }
+
+    /// Creates a resampler that takes blocks of `block_size` frames.
+    pub fn build(
+        self,
+        sample_rate: u32,
+        target_sample_rate: u32,
+        block_size: usize,
+        num_channels: usize,
+    ) -> Result<Box<dyn BlockResampler>, ResamplerConstructionError> {
+        let ratio = target_sample_rate as f64 / sample_rate as f64;
+        let sinc = |sinc_len, interpolation| {
+            SincFixedIn::new(
+                ratio,
+                1.0,
+                Self::sinc_parameters(sinc_len, interpolation),
+                block_size,
+                num_channels,
+            )
+            .map(|resampler| Box::new(resampler) as Box<dyn BlockResampler>)
+        };
+        match self {
+            ResamplerKind::Linear => Ok(Box::new(FastFixedIn::new(
+                ratio,
+                1.0,
+                PolynomialDegree::Linear,
+                block_size,
+                num_channels,
+            )?)),
+            ResamplerKind::Fft => Ok(Box::new(FftFixedIn::new(
+                sample_rate as usize,
+                target_sample_rate as usize,
+                block_size,
+                2,
+                num_channels,
+            )?)),
+            ResamplerKind::SincLow => sinc(64, SincInterpolationType::Linear),
+            ResamplerKind::SincMedium => sinc(128, SincInterpolationType::Quadratic),
+            ResamplerKind::SincHigh => sinc(256, SincInterpolationType::Cubic),
+        }
+    }
+}
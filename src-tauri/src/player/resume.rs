@@ -0,0 +1,154 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::store::JsonStore;
+
+const RESUME_POSITIONS_FILE_NAME: &str = "resume-positions.json";
+/// Positions this close to the end count as finished, so the file starts over next time.
+const FINISHED_THRESHOLD_SECONDS: f64 = 10.0;
+
+/// Which files remember their position, for audiobooks, lectures and long mixes.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ResumeSettings {
+    pub enabled: bool,
+    /// Files that are at least this long are resumed.
+    pub min_duration_minutes: u32,
+    /// Files in these directories or their subdirectories are resumed regardless of length.
+    pub directories: Vec<String>,
+    /// How far to go back from the saved position when resuming.
+    pub rewind_seconds: u32,
+}
+
+impl Default for ResumeSettings {
+    fn default() -> Self {
+        ResumeSettings {
+            enabled: true,
+            min_duration_minutes: 20,
+            directories: Vec::new(),
+            rewind_seconds: 5,
+        }
+    }
+}
+
+impl ResumeSettings {
+    fn applies_to(&self, path: &str, duration_seconds: f64) -> bool {
+        self.enabled
+            && (duration_seconds >= self.min_duration_minutes as f64 * 60.0
+                || self
+                    .directories
+                    .iter()
+                    .any(|directory| Path::new(path).starts_with(directory)))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ResumePosition {
+    pub path: String,
+    /// Position in frames, as used for seeking.
+    pub pos: usize,
+    pub pos_seconds: f64,
+    pub duration_seconds: f64,
+    /// When the position was last updated, in milliseconds since the Unix epoch.
+    pub updated_at: u64,
+}
+
+impl ResumePosition {
+    /// The position to resume from, `rewind_seconds` before the saved one.
+    pub fn rewound(&self, rewind_seconds: u32) -> usize {
+        if self.pos_seconds <= 0.0 {
+            return 0;
+        }
+        let frames_per_second = self.pos as f64 / self.pos_seconds;
+        self.pos
+            .saturating_sub((rewind_seconds as f64 * frames_per_second) as usize)
+    }
+}
+
+/// The last playhead of the files that are resumed, persisted in the app data directory.
+pub struct ResumePositions {
+    positions: HashMap<String, ResumePosition>,
+    store: JsonStore,
+    is_changed: bool,
+}
+
+impl ResumePositions {
+    pub fn load(data_dir: &Path) -> ResumePositions {
+        let store = JsonStore::new(data_dir.join(RESUME_POSITIONS_FILE_NAME));
+        ResumePositions {
+            positions: store.load().unwrap_or_default(),
+            store,
+            is_changed: false,
+        }
+    }
+
+    pub fn get(&self, path: &str) -> Option<&ResumePosition> {
+        self.positions.get(path)
+    }
+
+    /// Saved positions, most recently played first.
+    pub fn list(&self) -> Vec<ResumePosition> {
+        let mut positions: Vec<_> = self.positions.values().cloned().collect();
+        positions.sort_by_key(|position| Reverse(position.updated_at));
+        positions
+    }
+
+    /// Remembers the playhead of a file if `settings` apply to it, or forgets it once the file is
+    /// close to the end.
+    pub fn update(
+        &mut self,
+        settings: &ResumeSettings,
+        path: &str,
+        pos: usize,
+        pos_seconds: f64,
+        duration_seconds: f64,
+    ) {
+        if !settings.applies_to(path, duration_seconds) {
+            return;
+        }
+        if duration_seconds - pos_seconds < FINISHED_THRESHOLD_SECONDS {
+            self.remove(path);
+            return;
+        }
+        let updated_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_millis() as u64);
+        self.positions.insert(
+            path.to_owned(),
+            ResumePosition {
+                path: path.to_owned(),
+                pos,
+                pos_seconds,
+                duration_seconds,
+                updated_at,
+            },
+        );
+        self.is_changed = true;
+    }
+
+    pub fn remove(&mut self, path: &str) {
+        if self.positions.remove(path).is_some() {
+            self.is_changed = true;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        if !self.positions.is_empty() {
+            self.positions.clear();
+            self.is_changed = true;
+        }
+    }
+
+    pub fn save_if_changed(&mut self) {
+        if self.is_changed {
+            self.store.save(&self.positions);
+            self.is_changed = false;
+        }
+    }
+}
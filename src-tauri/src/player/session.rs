@@ -0,0 +1,27 @@
+use serde::{Deserialize, Serialize};
+
+use super::manager::{RepeatMode, ShuffleMode};
+use super::queue::Queue;
+
+pub const SESSION_FILE_NAME: &str = "session.json";
+
+/// The playback state that is restored when the app is launched again.
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Session {
+    /// The queue, if something was playing or paused.
+    pub queue: Option<Queue<String>>,
+    /// Position in the current item of the queue.
+    pub pos: usize,
+    pub shuffle_mode: ShuffleMode,
+    pub repeat_mode: RepeatMode,
+    pub volume: Option<f64>,
+}
+
+impl Session {
+    /// Whether the session can be restored. It's read from a file that may have been edited or
+    /// written by another version, so nothing about the queue can be assumed.
+    pub fn is_valid(&self) -> bool {
+        self.queue.as_ref().map_or(true, Queue::is_valid)
+    }
+}
@@ -0,0 +1,28 @@
+use serde::{Deserialize, Serialize};
+
+use super::errors::StreamErrorPolicy;
+use super::output::{OutputDeviceSelection, OutputRateMode};
+use super::resampler::ResamplerKind;
+use super::resume::ResumeSettings;
+use super::time_stretch::PlaybackRate;
+
+pub const SETTINGS_FILE_NAME: &str = "player-settings.json";
+
+/// Player settings that are persisted across restarts. Missing fields are filled in with their
+/// defaults, so that settings written by older versions can still be loaded.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PlayerSettings {
+    pub output_device: OutputDeviceSelection,
+    pub output_rate_mode: OutputRateMode,
+    /// Opens the output with all of the device's channels, so that multichannel files are passed
+    /// through rather than downmixed to stereo.
+    pub multichannel_output: bool,
+    /// Mixes the LFE channel into the front speakers when downmixing, instead of dropping it.
+    pub downmix_lfe: bool,
+    /// Algorithm used for streams that have to be resampled to the output rate.
+    pub resampler: ResamplerKind,
+    pub playback_rate: PlaybackRate,
+    pub resume: ResumeSettings,
+    pub stream_error_policy: StreamErrorPolicy,
+}
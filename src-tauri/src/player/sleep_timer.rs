@@ -0,0 +1,114 @@
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// Playback fades out over this long before the sleep timer stops it.
+const FADE_DURATION: Duration = Duration::from_secs(60);
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+/// The gain is updated more often while fading, so that the steps can't be heard.
+const FADE_TICK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// When the sleep timer stops playback.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum SleepTimerMode {
+    Minutes(u32),
+    EndOfTrack,
+    /// After the given number of tracks, counting the current one.
+    Tracks(u32),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SleepTimerStatus {
+    pub mode: SleepTimerMode,
+    /// Seconds until playback stops, once that is known. With a track based timer, this is only
+    /// known during the last track.
+    pub remaining_seconds: Option<u64>,
+    /// Tracks left to play including the current one, for a track based timer.
+    pub remaining_tracks: Option<u32>,
+}
+
+pub struct SleepTimer {
+    mode: SleepTimerMode,
+    deadline: Option<Instant>,
+    remaining_tracks: u32,
+    /// When the manager should update the fade and status next.
+    pub next_tick: Instant,
+}
+
+impl SleepTimer {
+    pub fn new(mode: SleepTimerMode) -> SleepTimer {
+        let now = Instant::now();
+        let (deadline, remaining_tracks) = match mode {
+            SleepTimerMode::Minutes(minutes) => {
+                (Some(now + Duration::from_secs(minutes as u64 * 60)), 0)
+            }
+            SleepTimerMode::EndOfTrack => (None, 1),
+            SleepTimerMode::Tracks(tracks) => (None, tracks.max(1)),
+        };
+        SleepTimer {
+            mode,
+            deadline,
+            remaining_tracks,
+            next_tick: now,
+        }
+    }
+
+    pub fn is_due(&self) -> bool {
+        Instant::now() >= self.next_tick
+    }
+
+    /// Whether the timer runs out when the current track ends.
+    pub fn ends_with_current_track(&self) -> bool {
+        self.deadline.is_none() && self.remaining_tracks <= 1
+    }
+
+    /// Counts a track that played to the end, returning whether the timer ran out with it.
+    pub fn track_finished(&mut self) -> bool {
+        if self.deadline.is_some() {
+            return false;
+        }
+        self.remaining_tracks = self.remaining_tracks.saturating_sub(1);
+        self.remaining_tracks == 0
+    }
+
+    /// Whether the timer ran out, which only happens by time, as tracks are counted as they
+    /// finish.
+    pub fn is_expired(&self) -> bool {
+        self.deadline
+            .map_or(false, |deadline| Instant::now() >= deadline)
+    }
+
+    /// Time until playback stops, given the time left in the current track.
+    pub fn remaining(&self, track_remaining: Option<Duration>) -> Option<Duration> {
+        match self.deadline {
+            Some(deadline) => Some(deadline.saturating_duration_since(Instant::now())),
+            None if self.remaining_tracks <= 1 => track_remaining,
+            None => None,
+        }
+    }
+
+    pub fn schedule_next_tick(&mut self, remaining: Option<Duration>) {
+        let is_fading = remaining.map_or(false, |remaining| remaining < FADE_DURATION);
+        let interval = if is_fading {
+            FADE_TICK_INTERVAL
+        } else {
+            TICK_INTERVAL
+        };
+        self.next_tick = Instant::now() + interval;
+    }
+
+    pub fn status(&self, remaining: Option<Duration>) -> SleepTimerStatus {
+        SleepTimerStatus {
+            mode: self.mode,
+            remaining_seconds: remaining.map(|remaining| remaining.as_secs_f64().ceil() as u64),
+            remaining_tracks: self.deadline.is_none().then_some(self.remaining_tracks),
+        }
+    }
+}
+
+/// Gain factor of the fade out at the end of the sleep timer.
+pub fn fade_gain(remaining: Option<Duration>) -> f32 {
+    remaining.map_or(1.0, |remaining| {
+        (remaining.as_secs_f32() / FADE_DURATION.as_secs_f32()).min(1.0)
+    })
+}
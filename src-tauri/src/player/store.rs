@@ -0,0 +1,51 @@
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    path::PathBuf,
+};
+
+use log::{error, warn};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// A value persisted as a JSON file in the app data directory.
+pub struct JsonStore {
+    path: PathBuf,
+}
+
+impl JsonStore {
+    pub fn new<P: Into<PathBuf>>(path: P) -> JsonStore {
+        JsonStore { path: path.into() }
+    }
+
+    /// Loads the stored value, if there is one and it can be read.
+    pub fn load<T: DeserializeOwned>(&self) -> Option<T> {
+        let contents = match fs::read(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Failed to read {:?}: {e:?}", self.path);
+                return None;
+            }
+        };
+        serde_json::from_slice(&contents)
+            .map_err(|e| warn!("Failed to parse {:?}: {e:?}", self.path))
+            .ok()
+    }
+
+    pub fn save<T: Serialize>(&self, value: &T) {
+        if let Err(e) = self.try_save(value) {
+            error!("Failed to write {:?}: {e:?}", self.path);
+        }
+    }
+
+    fn try_save<T: Serialize>(&self, value: &T) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let contents = serde_json::to_vec(value)?;
+        // Write to a temporary file first so that a crash can't leave a truncated file behind
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, contents)?;
+        fs::rename(tmp_path, &self.path)
+    }
+}
@@ -0,0 +1,398 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+pub const MIN_PLAYBACK_RATE: f32 = 0.5;
+pub const MAX_PLAYBACK_RATE: f32 = 3.0;
+/// Rates are rounded to this step, which keeps the ratios of the varispeed resampler simple.
+const PLAYBACK_RATE_STEP: f32 = 0.05;
+
+/// Length of the segments that are overlapped when time-stretching.
+const SEGMENT_MS: usize = 40;
+/// How far a segment may be moved from its nominal position to line up with the previous one.
+const TOLERANCE_MS: usize = 10;
+/// Only every nth sample is compared when searching for the best segment position.
+const CORRELATION_STRIDE: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PlaybackRate {
+    pub rate: f32,
+    /// Keeps the pitch by time-stretching, instead of playing faster or slower like a tape.
+    pub preserve_pitch: bool,
+}
+
+impl Default for PlaybackRate {
+    fn default() -> Self {
+        PlaybackRate {
+            rate: 1.0,
+            preserve_pitch: true,
+        }
+    }
+}
+
+impl PlaybackRate {
+    pub fn clamped(&self) -> PlaybackRate {
+        let rate = if self.rate.is_finite() {
+            self.rate.clamp(MIN_PLAYBACK_RATE, MAX_PLAYBACK_RATE)
+        } else {
+            1.0
+        };
+        PlaybackRate {
+            rate: (rate / PLAYBACK_RATE_STEP).round() * PLAYBACK_RATE_STEP,
+            preserve_pitch: self.preserve_pitch,
+        }
+    }
+
+    /// Whether the rate is one, allowing for the rounding to steps in `clamped`.
+    pub fn is_normal(&self) -> bool {
+        (self.rate - 1.0).abs() < PLAYBACK_RATE_STEP / 2.0
+    }
+}
+
+/// The playback rate, shared between the manager and the decode workers. Workers read it when
+/// they start and after every seek.
+#[derive(Debug)]
+pub struct SharedPlaybackRate {
+    rate: AtomicU32,
+    preserve_pitch: AtomicBool,
+}
+
+impl SharedPlaybackRate {
+    pub fn new(playback_rate: PlaybackRate) -> SharedPlaybackRate {
+        SharedPlaybackRate {
+            rate: AtomicU32::new(playback_rate.rate.to_bits()),
+            preserve_pitch: AtomicBool::new(playback_rate.preserve_pitch),
+        }
+    }
+
+    pub fn load(&self) -> PlaybackRate {
+        PlaybackRate {
+            rate: f32::from_bits(self.rate.load(Ordering::Relaxed)),
+            preserve_pitch: self.preserve_pitch.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn store(&self, playback_rate: PlaybackRate) {
+        self.rate
+            .store(playback_rate.rate.to_bits(), Ordering::Relaxed);
+        self.preserve_pitch
+            .store(playback_rate.preserve_pitch, Ordering::Relaxed);
+    }
+}
+
+/// Changes the tempo of audio without changing its pitch, using WSOLA (waveform similarity
+/// overlap-add). Segments are taken from the input at the playback rate and overlapped at a fixed
+/// hop in the output, with each segment shifted slightly so that it lines up with the previous
+/// one.
+pub struct TimeStretcher {
+    rate: f64,
+    /// Hop between segments in the output, which is half a segment.
+    hop: usize,
+    tolerance: usize,
+    window: Vec<f32>,
+    input: Vec<Vec<f32>>,
+    /// Nominal position of the next segment in `input`.
+    input_pos: f64,
+    /// Windowed second half of the previous segment, to be added to the next one.
+    overlap: Vec<Vec<f32>>,
+    /// Mono mix of the input that followed the previous segment, which the next segment should
+    /// resemble.
+    continuation: Vec<f32>,
+    is_first_segment: bool,
+}
+
+impl TimeStretcher {
+    pub fn new(rate: f32, sample_rate: u32, num_channels: usize) -> TimeStretcher {
+        let hop = (sample_rate as usize * SEGMENT_MS / 1000 / 2).max(1);
+        let segment_len = hop * 2;
+        // A periodic Hann window sums to one when overlapped by half
+        let window = (0..segment_len)
+            .map(|i| {
+                let phase = i as f32 / segment_len as f32;
+                0.5 - 0.5 * (2.0 * std::f32::consts::PI * phase).cos()
+            })
+            .collect();
+        TimeStretcher {
+            rate: rate as f64,
+            hop,
+            tolerance: sample_rate as usize * TOLERANCE_MS / 1000,
+            window,
+            input: vec![Vec::new(); num_channels],
+            input_pos: 0.0,
+            overlap: vec![vec![0.0; hop]; num_channels],
+            continuation: vec![0.0; hop],
+            is_first_segment: true,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for channel in self.input.iter_mut() {
+            channel.clear();
+        }
+        for channel in self.overlap.iter_mut() {
+            channel.fill(0.0);
+        }
+        self.input_pos = 0.0;
+        self.is_first_segment = true;
+    }
+
+    /// Stretches the first `num_frames` frames of `input`, returning as much output as can be
+    /// produced so far.
+    pub fn process(&mut self, input: &[Vec<f32>], num_frames: usize) -> Vec<Vec<f32>> {
+        for (buffer, channel) in self.input.iter_mut().zip(input) {
+            buffer.extend_from_slice(&channel[0..num_frames]);
+        }
+        self.stretch(usize::MAX)
+    }
+
+    /// Pushes the remaining input through at the end of the stream, leaving the stretcher empty.
+    pub fn flush(&mut self) -> Vec<Vec<f32>> {
+        let end = self.input[0].len();
+        let padding = self.hop * 2 + self.tolerance * 2;
+        for buffer in self.input.iter_mut() {
+            buffer.resize(buffer.len() + padding, 0.0);
+        }
+        let mut output = self.stretch(end);
+        // The second half of the last segment would only be added to the next one
+        for (out, overlap) in output.iter_mut().zip(self.overlap.iter()) {
+            out.extend_from_slice(overlap);
+        }
+        self.reset();
+        output
+    }
+
+    /// Overlaps the segments that the input is long enough for, up to the ones that start at
+    /// `end`.
+    fn stretch(&mut self, end: usize) -> Vec<Vec<f32>> {
+        let segment_len = self.hop * 2;
+        let input_len = self.input[0].len();
+        let mut output: Vec<Vec<f32>> = vec![Vec::new(); self.input.len()];
+
+        loop {
+            let nominal = self.input_pos.round() as usize;
+            if nominal + self.tolerance + segment_len > input_len || nominal >= end {
+                break;
+            }
+            let pos = if self.is_first_segment {
+                nominal
+            } else {
+                self.best_position(
+                    nominal.saturating_sub(self.tolerance),
+                    nominal + self.tolerance,
+                )
+            };
+            self.is_first_segment = false;
+
+            for ((input, overlap), out) in self
+                .input
+                .iter()
+                .zip(self.overlap.iter_mut())
+                .zip(output.iter_mut())
+            {
+                let segment = &input[pos..pos + segment_len];
+                let (head, tail) = self.window.split_at(self.hop);
+                out.extend(
+                    segment[0..self.hop]
+                        .iter()
+                        .zip(head)
+                        .zip(overlap.iter())
+                        .map(|((sample, w), overlap)| sample * w + overlap),
+                );
+                for ((overlap, sample), w) in overlap.iter_mut().zip(&segment[self.hop..]).zip(tail)
+                {
+                    *overlap = sample * w;
+                }
+            }
+            let num_channels = self.input.len() as f32;
+            for (i, sample) in self.continuation.iter_mut().enumerate() {
+                *sample = self
+                    .input
+                    .iter()
+                    .map(|channel| channel[pos + self.hop + i])
+                    .sum::<f32>()
+                    / num_channels;
+            }
+
+            self.input_pos += self.hop as f64 * self.rate;
+        }
+
+        // Drop the input that no future segment can start in
+        let consumed = (self.input_pos as usize)
+            .saturating_sub(self.tolerance)
+            .min(input_len);
+        for buffer in self.input.iter_mut() {
+            buffer.drain(0..consumed);
+        }
+        self.input_pos -= consumed as f64;
+
+        output
+    }
+
+    /// Finds the segment position in `start..=end` whose beginning is most similar to the
+    /// continuation of the previous segment.
+    fn best_position(&self, start: usize, end: usize) -> usize {
+        let num_channels = self.input.len() as f32;
+        let mut best_pos = start;
+        let mut best_correlation = f32::NEG_INFINITY;
+        for pos in start..=end {
+            let correlation: f32 = (0..self.hop)
+                .step_by(CORRELATION_STRIDE)
+                .map(|i| {
+                    let sample = self
+                        .input
+                        .iter()
+                        .map(|channel| channel[pos + i])
+                        .sum::<f32>()
+                        / num_channels;
+                    sample * self.continuation[i]
+                })
+                .sum();
+            if correlation > best_correlation {
+                best_correlation = correlation;
+                best_pos = pos;
+            }
+        }
+        best_pos
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 44100;
+    const NUM_FRAMES: usize = SAMPLE_RATE as usize;
+    /// Frames that are passed in at a time, which isn't a multiple of the hop.
+    const CHUNK_FRAMES: usize = 1000;
+
+    /// A stereo sine, with the channels out of phase so that they aren't mixed away.
+    fn sine() -> Vec<Vec<f32>> {
+        (0..2)
+            .map(|channel| {
+                (0..NUM_FRAMES)
+                    .map(|frame| {
+                        let phase =
+                            2.0 * std::f32::consts::PI * 440.0 * frame as f32 / SAMPLE_RATE as f32;
+                        0.5 * (phase + channel as f32).sin()
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn stretch(rate: f32) -> (TimeStretcher, Vec<Vec<f32>>) {
+        let input = sine();
+        let mut stretcher = TimeStretcher::new(rate, SAMPLE_RATE, input.len());
+        let mut output = vec![Vec::new(); input.len()];
+        for start in (0..NUM_FRAMES).step_by(CHUNK_FRAMES) {
+            let chunk: Vec<Vec<f32>> = input
+                .iter()
+                .map(|channel| channel[start..(start + CHUNK_FRAMES).min(NUM_FRAMES)].to_vec())
+                .collect();
+            let num_frames = chunk[0].len();
+            for (out, stretched) in output.iter_mut().zip(stretcher.process(&chunk, num_frames)) {
+                out.extend(stretched);
+            }
+        }
+        for (out, stretched) in output.iter_mut().zip(stretcher.flush()) {
+            out.extend(stretched);
+        }
+        (stretcher, output)
+    }
+
+    fn assert_stretched(rate: f32) {
+        let (stretcher, output) = stretch(rate);
+        let expected = NUM_FRAMES as f32 / rate;
+        let allowed = (stretcher.hop * 2 + stretcher.tolerance) as f32;
+        for channel in output.iter() {
+            assert!(
+                (channel.len() as f32 - expected).abs() <= allowed,
+                "{} frames at {rate}x instead of about {expected}",
+                channel.len()
+            );
+            assert!(channel.iter().all(|sample| sample.is_finite()));
+            // A 440 Hz sine at half scale changes by at most 0.032 per frame. The input ends
+            // wherever the sine is, so the end of the output is left out.
+            let end = (expected as usize).saturating_sub(stretcher.hop * 2);
+            for pair in channel[0..end].windows(2) {
+                assert!(
+                    (pair[1] - pair[0]).abs() < 0.1,
+                    "jumped from {} to {} at {rate}x",
+                    pair[0],
+                    pair[1]
+                );
+            }
+            // The overlapped segments line up instead of cancelling out
+            let middle = &channel[channel.len() / 4..channel.len() * 3 / 4];
+            let peak = middle
+                .iter()
+                .fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+            assert!(peak > 0.4, "peak of {peak} at {rate}x");
+        }
+    }
+
+    #[test]
+    fn stretches_to_half_speed() {
+        assert_stretched(0.5);
+    }
+
+    #[test]
+    fn stretches_to_one_and_a_half_speed() {
+        assert_stretched(1.5);
+    }
+
+    #[test]
+    fn stretches_to_triple_speed() {
+        assert_stretched(3.0);
+    }
+
+    #[test]
+    fn flush_consumes_all_input() {
+        for rate in [0.5, 1.5, 3.0] {
+            let (mut stretcher, _) = stretch(rate);
+            assert!(stretcher.input.iter().all(|channel| channel.is_empty()));
+            assert!(stretcher
+                .flush()
+                .iter()
+                .flatten()
+                .all(|&sample| sample == 0.0));
+        }
+    }
+
+    #[test]
+    fn rounded_rates_of_one_are_normal() {
+        for rate in [1.0, 0.98, 1.02, 1.0 + f32::EPSILON] {
+            let playback_rate = PlaybackRate {
+                rate,
+                preserve_pitch: true,
+            };
+            assert!(playback_rate.clamped().is_normal(), "{rate}");
+        }
+        for rate in [0.95, 1.05, MIN_PLAYBACK_RATE, MAX_PLAYBACK_RATE] {
+            let playback_rate = PlaybackRate {
+                rate,
+                preserve_pitch: true,
+            };
+            assert!(!playback_rate.clamped().is_normal(), "{rate}");
+        }
+    }
+
+    #[test]
+    fn rates_are_clamped_to_steps() {
+        let playback_rate = PlaybackRate {
+            rate: 1.23,
+            preserve_pitch: false,
+        };
+        assert!((playback_rate.clamped().rate - 1.25).abs() < 1e-6);
+        let playback_rate = PlaybackRate {
+            rate: f32::NAN,
+            preserve_pitch: false,
+        };
+        assert!(playback_rate.clamped().is_normal());
+        let playback_rate = PlaybackRate {
+            rate: 10.0,
+            preserve_pitch: false,
+        };
+        assert_eq!(playback_rate.clamped().rate, MAX_PLAYBACK_RATE);
+    }
+}
@@ -0,0 +1,229 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use symphonia::core::audio::SampleBuffer;
+
+use super::{errors::FileStreamOpenError, file_stream::TrackDecoder, store::JsonStore};
+
+const CACHE_DIR_NAME: &str = "waveforms";
+/// Least number of peaks that are computed and cached for each file, so that the usual lower
+/// resolutions can be served by merging them. Higher resolutions are computed when they are asked
+/// for.
+const MIN_CACHED_RESOLUTION: usize = 2048;
+/// Frames per peak while decoding, before the length of the file is known for sure. This is also
+/// the finest resolution that a waveform can have.
+const DECODE_BUCKET_FRAMES: usize = 64;
+/// The oldest waveforms are removed once the cache grows past this size.
+const MAX_CACHE_BYTES: u64 = 64 * 1024 * 1024;
+
+/// The lowest and highest sample in a section of a file, across all of its channels.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct WaveformPeak {
+    pub min: f32,
+    pub max: f32,
+}
+
+impl WaveformPeak {
+    fn merge(self, other: WaveformPeak) -> WaveformPeak {
+        WaveformPeak {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+}
+
+/// Identifies a version of a file, which a cached waveform is only valid for.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+struct WaveformSource {
+    path: String,
+    modified: u64,
+    size: u64,
+}
+
+impl WaveformSource {
+    fn read(path: &str) -> Option<WaveformSource> {
+        let metadata = fs::metadata(path).ok()?;
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(WaveformSource {
+            path: path.to_owned(),
+            modified: modified.as_millis() as u64,
+            size: metadata.len(),
+        })
+    }
+
+    /// Name of the cache file, from a 64-bit FNV-1a hash that stays the same across builds.
+    fn cache_file_name(&self) -> String {
+        const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+        const FNV_PRIME: u64 = 0x100000001b3;
+        let bytes = self
+            .path
+            .as_bytes()
+            .iter()
+            .copied()
+            .chain(self.modified.to_le_bytes())
+            .chain(self.size.to_le_bytes());
+        let hash = bytes.fold(FNV_OFFSET_BASIS, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+        });
+        format!("{hash:016x}.json")
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedWaveform {
+    source: WaveformSource,
+    /// The resolution that the peaks were computed at. There are fewer peaks for short files.
+    resolution: usize,
+    peaks: Vec<WaveformPeak>,
+}
+
+/// Generates waveform overviews of files, caching them on disk with one file per track.
+#[derive(Clone)]
+pub struct WaveformCache {
+    dir: PathBuf,
+}
+
+impl WaveformCache {
+    pub fn new(data_dir: &Path) -> WaveformCache {
+        WaveformCache {
+            dir: data_dir.join(CACHE_DIR_NAME),
+        }
+    }
+
+    /// Peaks of the file at `resolution` evenly spaced points, or fewer for very short files.
+    /// This decodes the whole file unless it has been cached at a high enough resolution, so it
+    /// shouldn't be called from a thread that has to stay responsive.
+    pub fn peaks(
+        &self,
+        path: &str,
+        resolution: usize,
+    ) -> Result<Vec<WaveformPeak>, FileStreamOpenError> {
+        let Some(source) = WaveformSource::read(path) else {
+            // The file can't be identified, so the waveform can't be cached either
+            let peaks = decode_peaks(path, resolution)?;
+            return Ok(downsample(&peaks, resolution));
+        };
+        let store = JsonStore::new(self.dir.join(source.cache_file_name()));
+        let cached = store
+            .load::<CachedWaveform>()
+            .filter(|cached| cached.source == source && cached.resolution >= resolution);
+
+        let peaks = match cached {
+            Some(cached) => cached.peaks,
+            None => {
+                let cached_resolution = resolution.max(MIN_CACHED_RESOLUTION);
+                let peaks = decode_peaks(path, cached_resolution)?;
+                info!("Generated waveform of {path:?} with {} peaks", peaks.len());
+                store.save(&CachedWaveform {
+                    source,
+                    resolution: cached_resolution,
+                    peaks: peaks.clone(),
+                });
+                self.evict();
+                peaks
+            }
+        };
+        Ok(downsample(&peaks, resolution))
+    }
+
+    /// Removes the least recently generated waveforms until the cache fits in `MAX_CACHE_BYTES`.
+    /// This also cleans up the waveforms of files that have changed or been deleted since.
+    fn evict(&self) {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return;
+        };
+        let mut files: Vec<_> = entries
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let metadata = entry.metadata().ok()?;
+                metadata
+                    .is_file()
+                    .then(|| Some((metadata.modified().ok()?, metadata.len(), entry.path())))?
+            })
+            .collect();
+        let mut total_bytes: u64 = files.iter().map(|(_, len, _)| len).sum();
+        if total_bytes <= MAX_CACHE_BYTES {
+            return;
+        }
+
+        files.sort_by_key(|(modified, _, _)| *modified);
+        for (_, len, path) in files {
+            if total_bytes <= MAX_CACHE_BYTES {
+                break;
+            }
+            match fs::remove_file(&path) {
+                Ok(()) => total_bytes -= len,
+                Err(e) => warn!("Failed to remove cached waveform {path:?}: {e:?}"),
+            }
+        }
+    }
+}
+
+/// Decodes the whole file into `resolution` peaks, or fewer if it has less than
+/// `DECODE_BUCKET_FRAMES` frames per peak.
+fn decode_peaks(path: &str, resolution: usize) -> Result<Vec<WaveformPeak>, FileStreamOpenError> {
+    let mut track = TrackDecoder::open(Path::new(path))?;
+    let mut peaks = Vec::new();
+    let mut peak = WaveformPeak::default();
+    let mut peak_frames = 0;
+    let mut sample_buffer: Option<SampleBuffer<f32>> = None;
+
+    loop {
+        let decoded = match track.next_buffer() {
+            Ok(decoded) => decoded,
+            Err(symphonia::core::errors::Error::IoError(e))
+                if e.kind() == ErrorKind::UnexpectedEof =>
+            {
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let spec = *decoded.spec();
+        let num_channels = spec.channels.count().max(1);
+        let buffer = match sample_buffer.as_mut() {
+            Some(buffer) if buffer.capacity() >= decoded.capacity() * num_channels => buffer,
+            _ => sample_buffer.insert(SampleBuffer::new(decoded.capacity() as u64, spec)),
+        };
+        buffer.copy_interleaved_ref(decoded);
+
+        for frame in buffer.samples().chunks_exact(num_channels) {
+            for &sample in frame {
+                peak.min = peak.min.min(sample);
+                peak.max = peak.max.max(sample);
+            }
+            peak_frames += 1;
+            if peak_frames == DECODE_BUCKET_FRAMES {
+                peaks.push(peak);
+                peak = WaveformPeak::default();
+                peak_frames = 0;
+            }
+        }
+    }
+    if peak_frames > 0 {
+        peaks.push(peak);
+    }
+
+    Ok(downsample(&peaks, resolution))
+}
+
+/// Merges `peaks` down to `resolution` peaks, if there are more than that.
+fn downsample(peaks: &[WaveformPeak], resolution: usize) -> Vec<WaveformPeak> {
+    if peaks.len() <= resolution {
+        return peaks.to_vec();
+    }
+    (0..resolution)
+        .map(|i| {
+            let start = i * peaks.len() / resolution;
+            let end = (i + 1) * peaks.len() / resolution;
+            peaks[start..end]
+                .iter()
+                .fold(WaveformPeak::default(), |acc, &peak| acc.merge(peak))
+        })
+        .collect()
+}
@@ -0,0 +1,551 @@
+#!/usr/bin/env python3
+"""Writes the audio files that the player's tests decode.
+
+The files are built by hand rather than with encoders, so that they can be regenerated anywhere
+and stay small. Lossless formats hold a tone whose samples the tests check exactly, which is
+`tone_sample` here and in the tests.
+
+Run from this directory with `python3 generate.py`.
+"""
+
+import math
+import struct
+
+TONE_SAMPLE_RATE = 8000
+TONE_FRAMES = 2000
+TONE_FREQUENCIES = [440, 660]
+
+
+def tone_sample(channel, frame):
+    phase = 2 * math.pi * TONE_FREQUENCIES[channel] * frame / TONE_SAMPLE_RATE
+    return round(0.5 * 32767 * math.sin(phase))
+
+
+def tone():
+    """Interleaved 16-bit frames of the stereo tone."""
+    return [
+        [tone_sample(channel, frame) for channel in range(len(TONE_FREQUENCIES))]
+        for frame in range(TONE_FRAMES)
+    ]
+
+
+class BitWriter:
+    """Packs bits most significant first, as FLAC and MPEG do."""
+
+    def __init__(self):
+        self.data = bytearray()
+        self.acc = 0
+        self.bits = 0
+
+    def write(self, value, bits):
+        for i in reversed(range(bits)):
+            self.acc = (self.acc << 1) | ((value >> i) & 1)
+            self.bits += 1
+            if self.bits == 8:
+                self.data.append(self.acc)
+                self.acc = 0
+                self.bits = 0
+
+    def align(self):
+        if self.bits:
+            self.write(0, 8 - self.bits)
+        return bytes(self.data)
+
+
+class LsbBitWriter:
+    """Packs bits least significant first, as Vorbis does."""
+
+    def __init__(self):
+        self.data = bytearray()
+        self.acc = 0
+        self.bits = 0
+
+    def write(self, value, bits):
+        for i in range(bits):
+            self.acc |= ((value >> i) & 1) << self.bits
+            self.bits += 1
+            if self.bits == 8:
+                self.data.append(self.acc)
+                self.acc = 0
+                self.bits = 0
+
+    def align(self):
+        if self.bits:
+            self.write(0, 8 - self.bits)
+        return bytes(self.data)
+
+
+def crc(data, poly, bits):
+    top = 1 << (bits - 1)
+    mask = (1 << bits) - 1
+    value = 0
+    for byte in data:
+        value ^= byte << (bits - 8)
+        for _ in range(8):
+            value = ((value << 1) ^ poly) if value & top else value << 1
+            value &= mask
+    return value
+
+
+def flac(frames, sample_rate, block_size=576):
+    """FLAC with verbatim subframes, which store the samples as they are."""
+    header, flac_frames = flac_stream(frames, sample_rate, block_size)
+    return header + b"".join(flac_frames)
+
+
+def flac_stream(frames, sample_rate, block_size):
+    """The stream marker and info of a FLAC file, and its frames."""
+    num_channels = len(frames[0])
+    info = BitWriter()
+    info.write(block_size, 16)
+    info.write(block_size, 16)
+    info.write(0, 24)
+    info.write(0, 24)
+    info.write(sample_rate, 20)
+    info.write(num_channels - 1, 3)
+    info.write(15, 5)
+    info.write(len(frames), 36)
+    info.write(0, 128)
+    streaminfo = info.align()
+    stream_header = b"fLaC" + bytes([0x80]) + len(streaminfo).to_bytes(3, "big") + streaminfo
+    flac_frames = []
+
+    for number, start in enumerate(range(0, len(frames), block_size)):
+        block = frames[start : start + block_size]
+        header = BitWriter()
+        header.write(0b11111111111110, 14)
+        header.write(0, 2)
+        # Block size from the end of the header, sample rate from the stream info
+        header.write(0b0111, 4)
+        header.write(0b0000, 4)
+        header.write(num_channels - 1, 4)
+        header.write(0b100, 3)
+        header.write(0, 1)
+        assert number < 0x80
+        header.write(number, 8)
+        header.write(len(block) - 1, 16)
+        header_bytes = header.align()
+        frame = BitWriter()
+        for byte in header_bytes + bytes([crc(header_bytes, 0x07, 8)]):
+            frame.write(byte, 8)
+        for channel in range(num_channels):
+            frame.write(0, 1)
+            frame.write(0b000001, 6)
+            frame.write(0, 1)
+            for samples in block:
+                frame.write(samples[channel] & 0xFFFF, 16)
+        frame_bytes = frame.align()
+        flac_frames.append(frame_bytes + crc(frame_bytes, 0x8005, 16).to_bytes(2, "big"))
+    return stream_header, flac_frames
+
+
+def mp3(num_frames):
+    """Silent MPEG-1 Layer III at 32 kbit/s and 44.1 kHz in mono, where every frame has empty
+    side info and so no main data."""
+    header = bytes([0xFF, 0xFB, 0x10, 0xC0])
+    frame_size = 144 * 32000 // 44100
+    frame = header + bytes(frame_size - len(header))
+    return frame * num_frames
+
+
+def pcm16le(frames):
+    return b"".join(struct.pack("<h", sample) for samples in frames for sample in samples)
+
+
+def wav(frames, sample_rate):
+    num_channels = len(frames[0])
+    data = pcm16le(frames)
+    fmt = struct.pack(
+        "<HHIIHH", 1, num_channels, sample_rate, sample_rate * num_channels * 2, num_channels * 2, 16
+    )
+    body = b"WAVE" + chunk(b"fmt ", fmt, "<") + chunk(b"data", data, "<")
+    return chunk(b"RIFF", body, "<")
+
+
+def chunk(tag, data, endian):
+    padding = b"\0" if len(data) % 2 else b""
+    return tag + struct.pack(endian + "I", len(data)) + data + padding
+
+
+def extended(value):
+    """80-bit IEEE 754 extended precision, which AIFF stores the sample rate in."""
+    exponent = value.bit_length() - 1
+    mantissa = value << (63 - exponent)
+    return struct.pack(">HQ", 16383 + exponent, mantissa)
+
+
+def aiff(frames, sample_rate):
+    num_channels = len(frames[0])
+    data = b"".join(struct.pack(">h", sample) for samples in frames for sample in samples)
+    comm = struct.pack(">hIh", num_channels, len(frames), 16) + extended(sample_rate)
+    ssnd = struct.pack(">II", 0, 0) + data
+    body = b"AIFF" + chunk(b"COMM", comm, ">") + chunk(b"SSND", ssnd, ">")
+    return chunk(b"FORM", body, ">")
+
+
+IMA_INDEX_TABLE = [-1, -1, -1, -1, 2, 4, 6, 8]
+IMA_STEP_TABLE = [
+    7, 8, 9, 10, 11, 12, 13, 14, 16, 17, 19, 21, 23, 25, 28, 31, 34, 37, 41, 45, 50, 55, 60, 66,
+    73, 80, 88, 97, 107, 118, 130, 143, 157, 173, 190, 209, 230, 253, 279, 307, 337, 371, 408,
+    449, 494, 544, 598, 658, 724, 796, 876, 963, 1060, 1166, 1282, 1411, 1552, 1707, 1878, 2066,
+    2272, 2499, 2749, 3024, 3327, 3660, 4026, 4428, 4871, 5358, 5894, 6484, 7132, 7845, 8630,
+    9493, 10442, 11487, 12635, 13899, 15289, 16818, 18500, 20350, 22385, 24623, 27086, 29794,
+    32767,
+]
+
+
+def ima_adpcm_wav(frames, sample_rate, block_align=256):
+    """IMA ADPCM in WAV, dropping the frames that don't fill the last block."""
+    num_channels = len(frames[0])
+    frames_per_block = (block_align - 4 * num_channels) * 2 // num_channels + 1
+    num_blocks = len(frames) // frames_per_block
+    # Starts at the step size that the tone settles on, so that it doesn't take a few samples to
+    # get there
+    indexes = [68] * num_channels
+    data = bytearray()
+    for block_number in range(num_blocks):
+        block = frames[block_number * frames_per_block : (block_number + 1) * frames_per_block]
+        nibbles = []
+        for channel in range(num_channels):
+            predicted = block[0][channel]
+            data += struct.pack("<hBB", predicted, indexes[channel], 0)
+            channel_nibbles = []
+            for samples in block[1:]:
+                step = IMA_STEP_TABLE[indexes[channel]]
+                diff = samples[channel] - predicted
+                nibble = 8 if diff < 0 else 0
+                diff = abs(diff)
+                delta = step >> 3
+                for bit in (4, 2, 1):
+                    if diff >= step:
+                        nibble |= bit
+                        diff -= step
+                        delta += step
+                    step >>= 1
+                predicted += -delta if nibble & 8 else delta
+                predicted = max(-32768, min(32767, predicted))
+                indexes[channel] = max(0, min(88, indexes[channel] + IMA_INDEX_TABLE[nibble & 7]))
+                channel_nibbles.append(nibble)
+            nibbles.append(channel_nibbles)
+        # Each channel takes turns with 4 bytes of 8 samples, low nibble first
+        for start in range(0, frames_per_block - 1, 8):
+            for channel in range(num_channels):
+                group = nibbles[channel][start : start + 8]
+                data += bytes(group[i] | (group[i + 1] << 4) for i in range(0, 8, 2))
+    fmt = struct.pack(
+        "<HHIIHHHH",
+        0x11,
+        num_channels,
+        sample_rate,
+        sample_rate * block_align // frames_per_block,
+        block_align,
+        4,
+        2,
+        frames_per_block,
+    )
+    fact = struct.pack("<I", num_blocks * frames_per_block)
+    body = (
+        b"WAVE" + chunk(b"fmt ", fmt, "<") + chunk(b"fact", fact, "<") + chunk(b"data", data, "<")
+    )
+    return chunk(b"RIFF", body, "<")
+
+
+def ebml_size(size):
+    length = 1
+    while size >= (1 << (7 * length)) - 1:
+        length += 1
+    return ((1 << (7 * length)) | size).to_bytes(length, "big")
+
+
+def ebml(element_id, *children):
+    data = b"".join(children)
+    return element_id.to_bytes((element_id.bit_length() + 7) // 8, "big") + ebml_size(len(data)) + data
+
+
+def ebml_uint(element_id, value):
+    return ebml(element_id, value.to_bytes(max(1, (value.bit_length() + 7) // 8), "big"))
+
+
+def mkv(frames, sample_rate, block_size=576):
+    """Matroska with FLAC in simple blocks of a single cluster. Symphonia can't decode PCM from
+    Matroska, since it doesn't know how many frames its packets hold."""
+    num_channels = len(frames[0])
+    flac_header, flac_frames = flac_stream(frames, sample_rate, block_size)
+    header = ebml(
+        0x1A45DFA3,
+        ebml_uint(0x4286, 1),
+        ebml_uint(0x42F7, 1),
+        ebml_uint(0x42F2, 4),
+        ebml_uint(0x42F3, 8),
+        ebml(0x4282, b"matroska"),
+        ebml_uint(0x4287, 4),
+        ebml_uint(0x4285, 2),
+    )
+    info = ebml(0x1549A966, ebml_uint(0x2AD7B1, 1000000), ebml(0x4D80, b"generate.py"))
+    audio = ebml(
+        0xE1,
+        ebml(0xB5, struct.pack(">d", sample_rate)),
+        ebml_uint(0x9F, num_channels),
+        ebml_uint(0x6264, 16),
+    )
+    track = ebml(
+        0xAE,
+        ebml_uint(0xD7, 1),
+        ebml_uint(0x73C5, 1),
+        ebml_uint(0x83, 2),
+        ebml(0x86, b"A_FLAC"),
+        ebml(0x63A2, flac_header),
+        audio,
+    )
+    blocks = []
+    for number, data in enumerate(flac_frames):
+        timestamp = number * block_size * 1000 // sample_rate
+        blocks.append(ebml(0xA3, b"\x81" + struct.pack(">hB", timestamp, 0x80) + data))
+    cluster = ebml(0x1F43B675, ebml_uint(0xE7, 0), *blocks)
+    return header + ebml(0x18538067, info, ebml(0x1654AE6B, track), cluster)
+
+
+def atom(atom_type, *children):
+    data = b"".join(children)
+    return struct.pack(">I", 8 + len(data)) + atom_type + data
+
+
+def full_atom(atom_type, flags, *children):
+    return atom(atom_type, struct.pack(">I", flags), *children)
+
+
+MATRIX = struct.pack(">9I", 0x10000, 0, 0, 0, 0x10000, 0, 0, 0, 0x40000000)
+
+
+def mp4_alac(frames, sample_rate, frames_per_packet=500):
+    """ALAC in MP4, with every packet stored uncompressed as an escape frame."""
+    num_channels = len(frames[0])
+    assert num_channels == 2 and len(frames) % frames_per_packet == 0
+    packets = []
+    for start in range(0, len(frames), frames_per_packet):
+        packet = BitWriter()
+        # A channel pair element that isn't partial or shifted and is uncompressed
+        packet.write(1, 3)
+        packet.write(0, 4)
+        packet.write(0, 12)
+        packet.write(0, 1)
+        packet.write(0, 2)
+        packet.write(1, 1)
+        for samples in frames[start : start + frames_per_packet]:
+            for sample in samples:
+                packet.write(sample & 0xFFFF, 16)
+        packet.write(7, 3)
+        packets.append(packet.align())
+
+    cookie = struct.pack(
+        ">IBBBBBBHIII", frames_per_packet, 0, 16, 40, 10, 14, num_channels, 255, 0, 0, sample_rate
+    )
+    sample_entry = atom(
+        b"alac",
+        bytes(6),
+        struct.pack(">HHHIHHHHI", 1, 0, 0, 0, num_channels, 16, 0, 0, sample_rate << 16),
+        full_atom(b"alac", 0, cookie),
+    )
+    duration = len(frames)
+
+    def moov(data_offset):
+        stbl = atom(
+            b"stbl",
+            full_atom(b"stsd", 0, struct.pack(">I", 1), sample_entry),
+            full_atom(b"stts", 0, struct.pack(">III", 1, len(packets), frames_per_packet)),
+            full_atom(b"stsc", 0, struct.pack(">IIII", 1, 1, len(packets), 1)),
+            full_atom(
+                b"stsz",
+                0,
+                struct.pack(">II", 0, len(packets)),
+                *(struct.pack(">I", len(packet)) for packet in packets),
+            ),
+            full_atom(b"stco", 0, struct.pack(">II", 1, data_offset)),
+        )
+        minf = atom(
+            b"minf",
+            full_atom(b"smhd", 0, bytes(4)),
+            atom(b"dinf", full_atom(b"dref", 0, struct.pack(">I", 1), full_atom(b"url ", 1))),
+            stbl,
+        )
+        mdia = atom(
+            b"mdia",
+            full_atom(b"mdhd", 0, struct.pack(">IIIIHH", 0, 0, sample_rate, duration, 0x55C4, 0)),
+            full_atom(b"hdlr", 0, bytes(4), b"soun", bytes(12), b"\0"),
+            minf,
+        )
+        tkhd = full_atom(
+            b"tkhd",
+            7,
+            struct.pack(">IIIII", 0, 0, 1, 0, duration),
+            bytes(8),
+            struct.pack(">hhhH", 0, 0, 0x100, 0),
+            MATRIX,
+            struct.pack(">II", 0, 0),
+        )
+        mvhd = full_atom(
+            b"mvhd",
+            0,
+            struct.pack(">IIIIIH", 0, 0, sample_rate, duration, 0x10000, 0x100),
+            bytes(10),
+            MATRIX,
+            bytes(24),
+            struct.pack(">I", 2),
+        )
+        return atom(b"moov", mvhd, atom(b"trak", tkhd, mdia))
+
+    ftyp = atom(b"ftyp", b"M4A ", struct.pack(">I", 0), b"M4A mp42isom")
+    # The offset of the samples depends on the size of the moov atom, which doesn't
+    data_offset = len(ftyp) + len(moov(0)) + 8
+    return ftyp + moov(data_offset) + atom(b"mdat", *packets)
+
+
+def adts_aac(num_frames):
+    """Silent AAC-LC in ADTS at 44.1 kHz in mono, where every frame has a single channel element
+    without any scale factor bands."""
+    raw = BitWriter()
+    # Single channel element with a global gain and long window, then the end element
+    raw.write(0, 3)
+    raw.write(0, 4)
+    raw.write(100, 8)
+    raw.write(0, 1)
+    raw.write(0, 2)
+    raw.write(0, 1)
+    raw.write(0, 6)
+    raw.write(0, 1)
+    raw.write(0, 3)
+    raw.write(7, 3)
+    raw_bytes = raw.align()
+    header = BitWriter()
+    header.write(0xFFF, 12)
+    header.write(0, 1)
+    header.write(0, 2)
+    header.write(1, 1)
+    header.write(1, 2)
+    header.write(4, 4)
+    header.write(0, 1)
+    header.write(1, 3)
+    header.write(0, 4)
+    header.write(7 + len(raw_bytes), 13)
+    header.write(0x7FF, 11)
+    header.write(0, 2)
+    return (header.align() + raw_bytes) * num_frames
+
+
+def ogg_page(serial, sequence, granule, packets, header_type=0):
+    lacing = bytearray()
+    for packet in packets:
+        lacing += bytes([255] * (len(packet) // 255) + [len(packet) % 255])
+    header = struct.pack("<4sBBqIII", b"OggS", 0, header_type, granule, serial, sequence, 0)
+    page = bytearray(header + bytes([len(lacing)]) + lacing + b"".join(packets))
+    page[22:26] = struct.pack("<I", crc(page, 0x04C11DB7, 32))
+    return bytes(page)
+
+
+def ogg(headers, packets, granule, serial=1):
+    pages = [ogg_page(serial, 0, 0, [headers[0]], 2)]
+    for header in headers[1:]:
+        pages.append(ogg_page(serial, len(pages), 0, [header]))
+    pages.append(ogg_page(serial, len(pages), granule, packets, 4))
+    return b"".join(pages)
+
+
+def ogg_vorbis(num_packets, sample_rate=44100):
+    """Silent Vorbis in mono with short blocks only, where the single floor marks the channel as
+    unused in every packet."""
+    identification = b"\x01vorbis" + struct.pack("<IBIiiiBB", 0, 1, sample_rate, 0, 0, 0, 0xB8, 1)
+    comment = b"\x03vorbis" + struct.pack("<I", 11) + b"generate.py" + struct.pack("<I", 0) + b"\x01"
+    setup = LsbBitWriter()
+    # A codebook of two entries that are one bit long
+    setup.write(0, 8)
+    setup.write(0x564342, 24)
+    setup.write(1, 16)
+    setup.write(2, 24)
+    setup.write(0, 1)
+    setup.write(0, 1)
+    setup.write(0, 5)
+    setup.write(0, 5)
+    setup.write(0, 4)
+    # Time domain transforms, which are placeholders
+    setup.write(0, 6)
+    setup.write(0, 16)
+    # A floor of type 1 without partitions
+    setup.write(0, 6)
+    setup.write(1, 16)
+    setup.write(0, 5)
+    setup.write(1, 2)
+    setup.write(8, 4)
+    # A residue of type 0 that covers nothing
+    setup.write(0, 6)
+    setup.write(0, 16)
+    setup.write(0, 24)
+    setup.write(0, 24)
+    setup.write(0, 24)
+    setup.write(0, 6)
+    setup.write(0, 8)
+    setup.write(0, 3)
+    setup.write(0, 1)
+    # A mapping with one submap
+    setup.write(0, 6)
+    setup.write(0, 16)
+    setup.write(0, 1)
+    setup.write(0, 1)
+    setup.write(0, 2)
+    setup.write(0, 8)
+    setup.write(0, 8)
+    setup.write(0, 8)
+    # A mode with short blocks
+    setup.write(0, 6)
+    setup.write(0, 1)
+    setup.write(0, 16)
+    setup.write(0, 16)
+    setup.write(0, 8)
+    setup.write(1, 1)
+    setup_bytes = b"\x05vorbis" + setup.align()
+    # Short blocks are 256 samples and overlap by half, and the first packet only primes the
+    # decoder
+    granule = (num_packets - 1) * 128
+    return ogg([identification, comment, setup_bytes], [b"\x00"] * num_packets, granule)
+
+
+def ogg_opus(num_packets, pre_skip=312):
+    """Opus in mono, where every packet is a 20 ms CELT frame without any data, which decodes as
+    concealed silence."""
+    head = b"OpusHead" + struct.pack("<BBHIhB", 1, 1, pre_skip, 48000, 0, 0)
+    tags = b"OpusTags" + struct.pack("<I", 11) + b"generate.py" + struct.pack("<I", 0)
+    granule = num_packets * 960
+    return ogg([head, tags], [bytes([31 << 3])] * num_packets, granule)
+
+
+def write(name, data):
+    with open(name, "wb") as f:
+        f.write(data)
+
+
+def main():
+    tone_flac = flac(tone(), TONE_SAMPLE_RATE)
+    write("tone.flac", tone_flac)
+    # Cut in the middle of the last frame but one
+    write("truncated.flac", tone_flac[: len(tone_flac) * 3 // 4])
+    # Zeroes part of the samples of the second frame, which then fails its checksum
+    damaged_flac = bytearray(tone_flac)
+    second_frame = tone_flac.index(b"\xff\xf8", 42 + 16)
+    damaged_flac[second_frame + 100 : second_frame + 164] = bytes(64)
+    write("damaged.flac", damaged_flac)
+
+    write("tone.wav", wav(tone(), TONE_SAMPLE_RATE))
+    write("tone.aiff", aiff(tone(), TONE_SAMPLE_RATE))
+    write("tone-adpcm.wav", ima_adpcm_wav(tone(), TONE_SAMPLE_RATE))
+    write("tone.mkv", mkv(tone(), TONE_SAMPLE_RATE))
+    write("tone-alac.m4a", mp4_alac(tone(), TONE_SAMPLE_RATE))
+    write("silence.aac", adts_aac(20))
+    write("silence.ogg", ogg_vorbis(40))
+    write("silence.opus", ogg_opus(20))
+
+    silence_mp3 = mp3(20)
+    write("silence.mp3", silence_mp3)
+    write("truncated.mp3", silence_mp3[: len(silence_mp3) - 150])
+
+
+if __name__ == "__main__":
+    main()
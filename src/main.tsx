@@ -5,27 +5,41 @@ import "./main.css";
 import React from "react";
 import ReactDOM from "react-dom/client";
 import { invoke } from "@tauri-apps/api";
-import { attachConsole } from "tauri-plugin-log-api";
+import { attachConsole, warn } from "tauri-plugin-log-api";
 
-import { PlaybackFileProvider, PlaybackStateProvider } from "./player";
+import {
+  PlaybackFileProvider,
+  PlaybackStateProvider,
+  PlayerSnapshotContext,
+  fetchPlayerSnapshot,
+} from "./player";
 import { initFileListing } from "./treeview/core/file-listing";
+import { initSupportedMusicFileExtensions } from "./treeview/core/file-type";
 import { FileListingContext } from "./treeview/context/file-listing-context";
 
 import App from "./app";
 
 async function main() {
   await attachConsole();
+  try {
+    await initSupportedMusicFileExtensions();
+  } catch (e) {
+    warn(`Failed to get supported formats with "${e}", using built-in list`);
+  }
   const fileListing = await initFileListing();
+  const playerSnapshot = await fetchPlayerSnapshot();
 
   ReactDOM.createRoot(document.getElementById("root") as HTMLElement).render(
     <React.StrictMode>
-      <PlaybackFileProvider>
-        <PlaybackStateProvider>
-          <FileListingContext.Provider value={fileListing}>
-            <App />
-          </FileListingContext.Provider>
-        </PlaybackStateProvider>
-      </PlaybackFileProvider>
+      <PlayerSnapshotContext.Provider value={playerSnapshot}>
+        <PlaybackFileProvider>
+          <PlaybackStateProvider>
+            <FileListingContext.Provider value={fileListing}>
+              <App />
+            </FileListingContext.Provider>
+          </PlaybackStateProvider>
+        </PlaybackFileProvider>
+      </PlayerSnapshotContext.Provider>
     </React.StrictMode>,
   );
   invoke("show_main_window");
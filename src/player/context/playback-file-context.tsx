@@ -3,6 +3,7 @@ import { createContext, ReactNode } from "react";
 import { useLatestEventPayload } from "../../tauri";
 import { PlaybackFileChangePayloadSchema } from "../schemas";
 import { PlaybackFile } from "../types";
+import usePlayerSnapshot from "../hooks/use-player-snapshot";
 
 export const PlaybackFileContext = createContext<PlaybackFile | null>(null);
 
@@ -11,10 +12,11 @@ export interface PlaybackFileProviderProps {
 }
 
 export function PlaybackFileProvider({ children }: PlaybackFileProviderProps) {
+  const snapshot = usePlayerSnapshot();
   const value = useLatestEventPayload(
     "player://playback-file-change",
     PlaybackFileChangePayloadSchema,
-    null,
+    snapshot?.playback_file ?? null,
   );
   return (
     <PlaybackFileContext.Provider value={value}>
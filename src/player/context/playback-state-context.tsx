@@ -3,6 +3,7 @@ import { ReactNode, createContext } from "react";
 import { PlaybackState } from "../types";
 import { useLatestEventPayload } from "../../tauri";
 import { PlaybackStateSchema } from "../schemas";
+import usePlayerSnapshot from "../hooks/use-player-snapshot";
 
 export const PlaybackStateContext = createContext<PlaybackState>("Stopped");
 
@@ -13,10 +14,11 @@ export interface PlaybackStateProviderProps {
 export function PlaybackStateProvider({
   children,
 }: PlaybackStateProviderProps) {
+  const snapshot = usePlayerSnapshot();
   const value = useLatestEventPayload(
     "player://playback-state-change",
     PlaybackStateSchema,
-    "Stopped",
+    snapshot?.playback_state ?? "Stopped",
   );
   return (
     <PlaybackStateContext.Provider value={value}>
@@ -0,0 +1,23 @@
+import { createContext } from "react";
+import { invoke } from "@tauri-apps/api";
+
+import { PlayerSnapshotSchema } from "../schemas";
+import { PlayerSnapshot } from "../types";
+
+/**
+ * The player state at the time the app was loaded, which the UI starts
+ * from before any events arrive. `null` if it couldn't be fetched.
+ */
+export const PlayerSnapshotContext = createContext<PlayerSnapshot | null>(
+  null,
+);
+
+export async function fetchPlayerSnapshot(): Promise<PlayerSnapshot | null> {
+  try {
+    const snapshot = await invoke("player_get_state");
+    return PlayerSnapshotSchema.nullable().parse(snapshot);
+  } catch (error) {
+    console.error("Failed to get the player state", error);
+    return null;
+  }
+}
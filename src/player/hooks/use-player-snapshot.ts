@@ -0,0 +1,8 @@
+import { useContext } from "react";
+
+import { PlayerSnapshotContext } from "../context/player-snapshot-context";
+import { PlayerSnapshot } from "../types";
+
+export default function usePlayerSnapshot(): PlayerSnapshot | null {
+  return useContext(PlayerSnapshotContext);
+}
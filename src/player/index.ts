@@ -1,4 +1,8 @@
 export { PlaybackFileProvider } from "./context/playback-file-context";
 export { PlaybackStateProvider } from "./context/playback-state-context";
+export {
+  PlayerSnapshotContext,
+  fetchPlayerSnapshot,
+} from "./context/player-snapshot-context";
 export { default as usePlaybackFile } from "./hooks/use-playback-file";
 export { default as PlayerPane } from "./ui/player-pane";
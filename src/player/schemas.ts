@@ -20,12 +20,52 @@ export const StreamTimingChangePayloadSchema = z.nullable(StreamTimingSchema);
 export const StreamMetadataVisualSchema = z.object({
   media_type: z.string(),
   data_base64: z.string(),
+  usage: z.string().nullable(),
+});
+
+export const StreamCodecInfoSchema = z.object({
+  codec: z.string().nullable(),
+  bitrate: z.number().nullable(),
+  bits_per_sample: z.number().nullable(),
+  sample_rate: z.number().nullable(),
+  channels: z.number().nullable(),
 });
 
 export const StreamMetadataSchema = z.object({
   track_title: z.string().nullable(),
   artist: z.string().nullable(),
-  album_cover: StreamMetadataVisualSchema.nullable(),
+  album: z.string().nullable(),
+  album_artist: z.string().nullable(),
+  track_number: z.number().nullable(),
+  track_total: z.number().nullable(),
+  disc_number: z.number().nullable(),
+  disc_total: z.number().nullable(),
+  date: z.string().nullable(),
+  genre: z.string().nullable(),
+  composer: z.string().nullable(),
+  comment: z.string().nullable(),
+  visuals: z.array(StreamMetadataVisualSchema),
+  codec: StreamCodecInfoSchema,
 });
 
 export const StreamMetadataPayloadSchema = z.nullable(StreamMetadataSchema);
+
+export const ShuffleModeSchema = z.enum(["NotEnabled", "Enabled"]);
+
+export const RepeatModeSchema = z.enum(["None", "RepeatAll", "RepeatOne"]);
+
+export const QueueStateSchema = z.object({
+  history: z.array(z.string()),
+  current: z.string().nullable(),
+  upcoming: z.array(z.string()),
+});
+
+export const PlayerSnapshotSchema = z.object({
+  playback_file: PlaybackFileSchema.nullable(),
+  playback_state: PlaybackStateSchema,
+  stream_timing: StreamTimingSchema.nullable(),
+  queue: QueueStateSchema,
+  shuffle_mode: ShuffleModeSchema,
+  repeat_mode: RepeatModeSchema,
+  volume: z.number().nullable(),
+});
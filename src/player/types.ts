@@ -2,6 +2,8 @@ import { z } from "zod";
 import {
   PlaybackFileSchema,
   PlaybackStateSchema,
+  PlayerSnapshotSchema,
+  RepeatModeSchema,
   StreamMetadataSchema,
   StreamTimingSchema,
 } from "./schemas";
@@ -13,3 +15,7 @@ export type PlaybackState = z.infer<typeof PlaybackStateSchema>;
 export type StreamTiming = z.infer<typeof StreamTimingSchema>;
 
 export type StreamMetadata = z.infer<typeof StreamMetadataSchema>;
+
+export type RepeatMode = z.infer<typeof RepeatModeSchema>;
+
+export type PlayerSnapshot = z.infer<typeof PlayerSnapshotSchema>;
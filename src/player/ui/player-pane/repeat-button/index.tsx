@@ -1,10 +1,10 @@
 import { invoke } from "@tauri-apps/api";
 
 import PlaybackButton from "../../../shared-ui/playback-button";
+import usePlayerSnapshot from "../../../hooks/use-player-snapshot";
+import { RepeatMode } from "../../../types";
 import { useState } from "react";
 
-type RepeatMode = "None" | "RepeatAll" | "RepeatOne";
-
 function nextRepeatMode(repeatMode: RepeatMode): RepeatMode {
   switch (repeatMode) {
     case "None":
@@ -18,7 +18,10 @@ function nextRepeatMode(repeatMode: RepeatMode): RepeatMode {
 }
 
 export default function RepeatButton() {
-  const [repeatMode, setRepeatMode] = useState<RepeatMode>("None");
+  const snapshot = usePlayerSnapshot();
+  const [repeatMode, setRepeatMode] = useState<RepeatMode>(
+    snapshot?.repeat_mode ?? "None",
+  );
   const desiredRepeatMode = nextRepeatMode(repeatMode);
   const handleClick = async () => {
     await invoke("player_set_repeat_mode", { repeatMode: desiredRepeatMode });
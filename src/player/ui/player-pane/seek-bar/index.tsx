@@ -14,6 +14,7 @@ import { invoke } from "@tauri-apps/api";
 import "./seek-bar.styles.css";
 import { StreamTimingChangePayloadSchema } from "../../../schemas";
 import useEventListener from "../../../../tauri/hooks/use-event-listener";
+import usePlayerSnapshot from "../../../hooks/use-player-snapshot";
 
 /**
  * Grace period to get a new seek bar position from the server after a
@@ -73,7 +74,10 @@ function wrapQuasiMonoChars(s: string | undefined): ReactNode {
 }
 
 export default function SeekBar() {
-  const [streamTiming, setStreamTiming] = useState<StreamTiming | null>(null);
+  const snapshot = usePlayerSnapshot();
+  const [streamTiming, setStreamTiming] = useState<StreamTiming | null>(
+    snapshot?.stream_timing ?? null,
+  );
   const [optimisticPosition, setOptimisticPosition] = useState<
     number | undefined
   >();
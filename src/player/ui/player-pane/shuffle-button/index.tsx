@@ -1,10 +1,14 @@
 import { invoke } from "@tauri-apps/api";
 
 import PlaybackButton from "../../../shared-ui/playback-button";
+import usePlayerSnapshot from "../../../hooks/use-player-snapshot";
 import { useState } from "react";
 
 export default function ShuffleButton() {
-  const [isShuffleEnabled, setIsShuffleEnabled] = useState(false);
+  const snapshot = usePlayerSnapshot();
+  const [isShuffleEnabled, setIsShuffleEnabled] = useState(
+    snapshot?.shuffle_mode === "Enabled",
+  );
   const handleClick = async () => {
     await invoke("player_set_shuffle_mode", {
       shuffleMode: isShuffleEnabled ? "NotEnabled" : "Enabled",
@@ -41,8 +41,11 @@ export default function StreamMetadata() {
     [],
   );
   useEventListener("player://stream-metadata-change", debouncedEventListener);
-  const imageSrc = latestMetadata?.album_cover
-    ? `data:${latestMetadata.album_cover.media_type};base64,${latestMetadata.album_cover.data_base64}`
+  const albumCover = latestMetadata?.visuals.find(
+    (visual) => visual.usage === "FrontCover",
+  );
+  const imageSrc = albumCover
+    ? `data:${albumCover.media_type};base64,${albumCover.data_base64}`
     : undefined;
   return (
     <div
@@ -3,11 +3,16 @@ import "./volume-slider.styles.css";
 import { invoke } from "@tauri-apps/api";
 import syncStorage from "../../../../sync-storage";
 import { z } from "zod";
+import usePlayerSnapshot from "../../../hooks/use-player-snapshot";
 
 const LOCAL_STORAGE_VOLUME_KEY = "volume";
 const DEFAULT_VOLUME = 85;
 
-function getPersistedVolumeOrDefault(): number {
+/**
+ * The volume used to be persisted by the UI, so it's still read from there
+ * until the player has saved one of its own.
+ */
+function getLegacyVolumeOrDefault(): number {
   const value = syncStorage.getWithSchema(LOCAL_STORAGE_VOLUME_KEY, z.number());
   if (value === null) {
     return DEFAULT_VOLUME;
@@ -16,7 +21,10 @@ function getPersistedVolumeOrDefault(): number {
 }
 
 export default function VolumeSlider() {
-  const [volume, setVolume] = useState(getPersistedVolumeOrDefault());
+  const snapshot = usePlayerSnapshot();
+  const [volume, setVolume] = useState(
+    () => snapshot?.volume ?? getLegacyVolumeOrDefault(),
+  );
   useEffect(() => {
     invoke<void>("player_set_volume", { volume });
   }, [volume]);
@@ -26,9 +34,7 @@ export default function VolumeSlider() {
       type="range"
       value={volume}
       onChange={(event) => {
-        const volume = Number(event.target.value);
-        syncStorage.set(LOCAL_STORAGE_VOLUME_KEY, volume);
-        setVolume(volume);
+        setVolume(Number(event.target.value));
       }}
       title="Volume"
     />
@@ -1,8 +1,27 @@
+import { invoke } from "@tauri-apps/api";
+
 import { File } from "./file-listing";
 
 export type FileType = "file" | "music-file" | "directory";
 
-const SUPPORTED_MUSIC_FILE_EXTENSIONS = [".mp3", ".flac", ".wav", ".ogg"];
+interface SupportedFormat {
+  name: string;
+  extensions: string[];
+}
+
+/**
+ * Used until the player reports what it supports, or if it fails to.
+ */
+const FALLBACK_MUSIC_FILE_EXTENSIONS = [".mp3", ".flac", ".wav", ".ogg"];
+
+let supportedMusicFileExtensions: string[] = FALLBACK_MUSIC_FILE_EXTENSIONS;
+
+export async function initSupportedMusicFileExtensions(): Promise<void> {
+  const formats = await invoke<SupportedFormat[]>("player_supported_formats");
+  supportedMusicFileExtensions = formats.flatMap((format) =>
+    format.extensions.map((extension) => `.${extension}`),
+  );
+}
 
 export function getFileType(file: File): FileType {
   if (file.children !== undefined) {
@@ -10,7 +29,7 @@ export function getFileType(file: File): FileType {
   }
   if (
     file.name &&
-    SUPPORTED_MUSIC_FILE_EXTENSIONS.some(
+    supportedMusicFileExtensions.some(
       (extension) => file.name?.toLowerCase().endsWith(extension),
     )
   ) {